@@ -1,14 +1,30 @@
-use agentproxy::{mcp::AgentProxyMcp, policy::load_policy, service, service::AppState};
+use agentproxy::{
+    audit::{self, AuditLog},
+    db::{SQLDB, SqliteDb},
+    mcp::AgentProxyMcp,
+    policy::load_policy,
+    policy_test,
+    replay::{self, CaptureLog},
+    service,
+    service::AppState,
+    telemetry,
+};
 use axum::Router;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{Resource, trace::SdkTracerProvider};
 use rmcp::ServiceExt;
 use rmcp::transport::stdio;
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::Path, sync::Arc, time::Duration};
 use tokio::net::TcpListener;
+use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
 
 #[derive(Debug, Parser)]
 #[command(name = "agentproxy", version, about = "AgentProxy CLI")]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg(long, default_value = "examples/policy.yaml")]
     policy_file: String,
     #[arg(long, default_value = "127.0.0.1:3000")]
@@ -17,31 +33,124 @@ struct Cli {
     sqlite_path: String,
     #[arg(long)]
     mcp_stdio: bool,
+    /// Seconds a preview stays committable.
+    #[arg(long, default_value_t = 900)]
+    preview_ttl_secs: u64,
+    /// Seconds a commit's idempotency key keeps replaying its response.
+    #[arg(long, default_value_t = 86400)]
+    idempotency_ttl_secs: u64,
+    /// Seconds committed and failed query records are kept.
+    #[arg(long, default_value_t = 604800)]
+    record_ttl_secs: u64,
+    /// Append-only, hash-chained JSONL log of previews, rejections and commits.
+    #[arg(long)]
+    audit_log: Option<String>,
+    /// Capture every SQL request and its decision to this JSONL file.
+    #[arg(long)]
+    capture: Option<String>,
+    /// Log format on stderr; levels follow `RUST_LOG` (default `info`).
+    #[arg(long, value_enum, default_value_t = LogFormat::Json)]
+    log_format: LogFormat,
+    /// Also export spans over OTLP/HTTP, e.g. `http://localhost:4318/v1/traces`.
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+    /// Log SQL with its literals instead of replacing them with `?`.
+    #[arg(long)]
+    log_sql_literals: bool,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum LogFormat {
+    Json,
+    Text,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Inspect the audit log.
+    Audit {
+        #[command(subcommand)]
+        command: AuditCommand,
+    },
+    /// Re-run captured requests against a policy and report changed decisions.
+    Replay {
+        capture: String,
+        #[arg(long, default_value = "examples/policy.yaml")]
+        policy_file: String,
+        /// Preview against this database instead of in dry-run mode.
+        #[arg(long)]
+        sqlite_path: Option<String>,
+    },
+    /// Work with policy files.
+    Policy {
+        #[command(subcommand)]
+        command: PolicyCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum PolicyCommand {
+    /// Run a policy test suite and report failing cases.
+    Test {
+        /// Test suite to run; defaults to `policy.tests.yaml` next to the policy file.
+        tests: Option<String>,
+        #[arg(long, default_value = "examples/policy.yaml")]
+        policy_file: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum AuditCommand {
+    /// Check the hash chain of an audit log for edited or deleted entries.
+    Verify { path: String },
 }
 
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
+    if let Some(command) = cli.command {
+        run_command(command);
+        return;
+    }
+    let tracer_provider = init_tracing(&cli);
+
     let policy = load_policy(&cli.policy_file).unwrap_or_else(|error| {
         eprintln!("{error}");
         std::process::exit(1)
     });
 
-    let state = AppState::new(policy);
+    let mut state = AppState::new(policy)
+        .with_preview_ttl(Duration::from_secs(cli.preview_ttl_secs))
+        .with_idempotency_ttl(Duration::from_secs(cli.idempotency_ttl_secs))
+        .with_record_ttl(Duration::from_secs(cli.record_ttl_secs));
+    if let Some(path) = &cli.audit_log {
+        let log = AuditLog::open(path).unwrap_or_else(|error| {
+            eprintln!("{error}");
+            std::process::exit(1)
+        });
+        state = state.with_audit_log(log);
+    }
+    if let Some(path) = &cli.capture {
+        state = state.with_capture_log(CaptureLog::new(path));
+    }
+    state.spawn_sweeper(Duration::from_secs(60));
 
     if cli.mcp_stdio {
         let server = AgentProxyMcp::new(state);
         if let Ok(service) = server.serve(stdio()).await {
             let _ = service.waiting().await;
         }
+        shutdown_tracing(tracer_provider);
         return;
     }
 
     let router: Router = service::router(state);
 
-    println!(
-        "AgentProxy listening on http://{} (sqlite: {})",
-        cli.listen, cli.sqlite_path
+    tracing::info!(
+        listen = %cli.listen,
+        sqlite = %cli.sqlite_path,
+        "AgentProxy listening on http://{}",
+        cli.listen
     );
 
     let addr: SocketAddr = cli.listen.parse().unwrap_or_else(|error| {
@@ -50,7 +159,158 @@ async fn main() {
     });
 
     let listener = TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, router.into_make_service())
-        .await
-        .unwrap();
+    axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
+    shutdown_tracing(tracer_provider);
+}
+
+/// Installs the stderr log output (stdout carries MCP messages with
+/// `--mcp-stdio`) and, with `--otlp-endpoint`, the span exporter.
+fn init_tracing(cli: &Cli) -> Option<SdkTracerProvider> {
+    telemetry::set_log_sql_literals(cli.log_sql_literals);
+    let mut filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    if !cli.log_sql_literals {
+        // The parser's debug output quotes the SQL it parses.
+        filter = filter.add_directive("sqlparser=warn".parse().expect("directive is valid"));
+    }
+    let (json, text) = match cli.log_format {
+        LogFormat::Json => (
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_writer(std::io::stderr),
+            ),
+            None,
+        ),
+        LogFormat::Text => (
+            None,
+            Some(tracing_subscriber::fmt::layer().with_writer(std::io::stderr)),
+        ),
+    };
+
+    let provider = cli.otlp_endpoint.as_ref().map(|endpoint| {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .unwrap_or_else(|error| exit_with(format!("Invalid OTLP exporter: {error}")));
+        SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name("agentproxy").build())
+            .build()
+    });
+    let otlp = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("agentproxy")));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(json)
+        .with(text)
+        .with(otlp)
+        .init();
+    provider
+}
+
+/// Flushes spans still queued for the OTLP exporter.
+fn shutdown_tracing(provider: Option<SdkTracerProvider>) {
+    if let Some(provider) = provider
+        && let Err(error) = provider.shutdown()
+    {
+        eprintln!("Failed to flush OTLP spans: {error}");
+    }
+}
+
+fn run_command(command: Command) {
+    match command {
+        Command::Audit {
+            command: AuditCommand::Verify { path },
+        } => match audit::verify(&path) {
+            Ok(Some(last)) => println!(
+                "Audit log OK: {} entries, last hash {}",
+                last.seq + 1,
+                last.hash
+            ),
+            Ok(None) => println!("Audit log OK: empty"),
+            Err(error) => {
+                eprintln!("Audit log verification failed: {error}");
+                std::process::exit(1)
+            }
+        },
+        Command::Replay {
+            capture,
+            policy_file,
+            sqlite_path,
+        } => {
+            let entries = replay::read_capture(&capture).unwrap_or_else(|error| exit_with(error));
+            let policy = load_policy(&policy_file).unwrap_or_else(|error| exit_with(error));
+            let db: Option<Arc<dyn SQLDB>> = sqlite_path.map(|path| {
+                let db = SqliteDb::new(path).unwrap_or_else(|error| exit_with(error));
+                Arc::new(db) as Arc<dyn SQLDB>
+            });
+
+            let changes = replay::replay(&entries, &policy, db.as_ref());
+            for change in &changes {
+                println!("line {}: {}", change.line, change.sql);
+                println!("  before: {}", describe(&change.before));
+                println!("  after:  {}", describe(&change.after));
+            }
+            let previews = entries
+                .iter()
+                .filter(|entry| entry.kind == replay::CaptureKind::Preview)
+                .count();
+            println!(
+                "{} of {previews} captured previews changed decision",
+                changes.len()
+            );
+            if !changes.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Command::Policy {
+            command: PolicyCommand::Test { tests, policy_file },
+        } => {
+            let tests = tests.unwrap_or_else(|| {
+                Path::new(&policy_file)
+                    .with_file_name("policy.tests.yaml")
+                    .to_string_lossy()
+                    .into_owned()
+            });
+            let policy = load_policy(&policy_file).unwrap_or_else(|error| exit_with(error));
+            let suite = policy_test::load_suite(&tests).unwrap_or_else(|error| exit_with(error));
+
+            let results = policy_test::run_suite(&suite, &policy);
+            for result in &results {
+                if result.passed {
+                    println!("PASS {}", result.name);
+                } else {
+                    println!("FAIL {}: {}", result.name, result.message);
+                }
+            }
+            let failed = results.iter().filter(|result| !result.passed).count();
+            println!("{} passed, {failed} failed", results.len() - failed);
+            if failed > 0 {
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn describe(decision: &replay::Decision) -> String {
+    match (decision.allowed, &decision.rewritten_sql, &decision.error) {
+        (true, Some(sql), _) => format!("allowed as {sql}"),
+        (true, None, _) => "allowed".to_string(),
+        (false, _, Some(error)) => format!("denied: {error}"),
+        (false, _, None) => "denied".to_string(),
+    }
+}
+
+fn exit_with(error: impl std::fmt::Display) -> ! {
+    eprintln!("{error}");
+    std::process::exit(1)
 }
@@ -0,0 +1,207 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::query_engine::QueryContext;
+
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    Preview,
+    Rejection,
+    Commit,
+    Failure,
+    /// A commit answered from its idempotency key without executing again.
+    Replay,
+}
+
+impl AuditEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditEvent::Preview => "preview",
+            AuditEvent::Rejection => "rejection",
+            AuditEvent::Commit => "commit",
+            AuditEvent::Failure => "failure",
+            AuditEvent::Replay => "replay",
+        }
+    }
+}
+
+/// One line of the audit log. `hash` covers every other field, including the
+/// hash of the previous entry, so editing or removing an entry breaks the chain.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+    pub event: AuditEvent,
+    pub actor: String,
+    pub tenant_id: String,
+    pub role: String,
+    pub preview_id: Option<String>,
+    pub sql: Vec<String>,
+    pub rows_affected: Option<u64>,
+    pub error: Option<String>,
+    pub prev_hash: String,
+    #[serde(default)]
+    pub hash: String,
+}
+
+/// What happened, as reported by the service; the log adds the chain fields.
+#[derive(Clone, Debug)]
+pub struct AuditRecord<'a> {
+    pub event: AuditEvent,
+    pub context: &'a QueryContext,
+    pub preview_id: Option<String>,
+    pub sql: Vec<String>,
+    pub rows_affected: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Append-only JSONL audit log with a SHA-256 hash chain.
+pub struct AuditLog {
+    path: PathBuf,
+    tail: Mutex<(u64, String)>,
+}
+
+impl AuditLog {
+    /// Opens the log at `path`, continuing the chain of any existing entries.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        let last = if path.exists() { verify(&path)? } else { None };
+        let tail = match last {
+            Some(last) => (last.seq + 1, last.hash),
+            None => (0, GENESIS_HASH.to_string()),
+        };
+        Ok(Self {
+            path,
+            tail: Mutex::new(tail),
+        })
+    }
+
+    pub fn append(&self, record: AuditRecord<'_>) -> Result<AuditEntry, String> {
+        let mut tail = self
+            .tail
+            .lock()
+            .map_err(|_| "Audit log lock poisoned".to_string())?;
+        let mut entry = AuditEntry {
+            seq: tail.0,
+            timestamp: Utc::now(),
+            event: record.event,
+            actor: record.context.actor.clone(),
+            tenant_id: record.context.tenant_id.clone(),
+            role: record.context.role.clone(),
+            preview_id: record.preview_id,
+            sql: record.sql,
+            rows_affected: record.rows_affected,
+            error: record.error,
+            prev_hash: tail.1.clone(),
+            hash: String::new(),
+        };
+        entry.hash = entry_hash(&entry)?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|err| format!("Failed to open audit log: {err}"))?;
+        let line = serde_json::to_string(&entry).map_err(|err| err.to_string())?;
+        writeln!(file, "{line}")
+            .and_then(|_| file.sync_data())
+            .map_err(|err| format!("Failed to write audit log: {err}"))?;
+
+        *tail = (entry.seq + 1, entry.hash.clone());
+        Ok(entry)
+    }
+}
+
+/// Checks every entry of the log at `path` against the hash chain and returns
+/// the last entry. Fails at the first edited, reordered or missing entry.
+pub fn verify(path: impl AsRef<Path>) -> Result<Option<AuditEntry>, String> {
+    let file =
+        File::open(path.as_ref()).map_err(|err| format!("Failed to open audit log: {err}"))?;
+    let mut previous: Option<AuditEntry> = None;
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|err| format!("Failed to read audit log: {err}"))?;
+        let number = index + 1;
+        let entry: AuditEntry = serde_json::from_str(&line)
+            .map_err(|err| format!("Line {number}: invalid audit entry: {err}"))?;
+
+        let (expected_seq, expected_prev) = match &previous {
+            Some(previous) => (previous.seq + 1, previous.hash.as_str()),
+            None => (0, GENESIS_HASH),
+        };
+        if entry.seq != expected_seq || entry.prev_hash != expected_prev {
+            return Err(format!(
+                "Line {number}: chain broken; expected entry {expected_seq} after hash {expected_prev}"
+            ));
+        }
+        if entry_hash(&entry)? != entry.hash {
+            return Err(format!("Line {number}: entry {} was modified", entry.seq));
+        }
+        previous = Some(entry);
+    }
+
+    Ok(previous)
+}
+
+fn entry_hash(entry: &AuditEntry) -> Result<String, String> {
+    let mut unsigned = entry.clone();
+    unsigned.hash = String::new();
+    let json = serde_json::to_string(&unsigned).map_err(|err| err.to_string())?;
+    Ok(Sha256::digest(json.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn detects_edited_and_deleted_entries() {
+        let path = std::env::temp_dir().join(format!("audit-{}.jsonl", uuid::Uuid::new_v4()));
+        let context = QueryContext {
+            actor: "agent:test".to_string(),
+            tenant_id: "acme".to_string(),
+            role: String::new(),
+            client_ip: None,
+            attributes: HashMap::new(),
+        };
+        let log = AuditLog::open(&path).unwrap();
+        for event in [
+            AuditEvent::Preview,
+            AuditEvent::Rejection,
+            AuditEvent::Commit,
+        ] {
+            log.append(AuditRecord {
+                event,
+                context: &context,
+                preview_id: None,
+                sql: vec!["DELETE FROM orders WHERE id = 1".to_string()],
+                rows_affected: None,
+                error: None,
+            })
+            .unwrap();
+        }
+        assert_eq!(verify(&path).unwrap().unwrap().seq, 2);
+
+        let original = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = original.lines().collect();
+        std::fs::write(&path, original.replace("id = 1", "id = 2")).unwrap();
+        assert!(verify(&path).unwrap_err().contains("was modified"));
+
+        std::fs::write(&path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        assert!(verify(&path).unwrap_err().contains("chain broken"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,11 +1,185 @@
-use rusqlite::{Connection, Result as SqlResult};
-use std::{path::Path, sync::Mutex};
+use rusqlite::{Connection, Result as SqlResult, functions::FunctionFlags, types::ValueRef};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, path::Path, sync::Mutex};
+
+use crate::error::{ProxyError, ProxyResult};
+use crate::telemetry::loggable_sql;
 
 pub trait SQLDB: Send + Sync {
     fn execute(&self, sql: &str) -> Result<u64, String>;
+    /// Runs `work` inside a single transaction that is committed or rolled back
+    /// according to `mode`. The transaction is always rolled back on error.
+    fn transaction(
+        &self,
+        mode: TransactionMode,
+        work: &mut dyn FnMut(&dyn SqlTransaction) -> Result<(), String>,
+    ) -> Result<(), String>;
     fn describe_schema(&self) -> Result<SchemaSnapshot, String>;
 }
 
+/// Rows returned by [`SqlTransaction::query_capped`].
+#[derive(Clone, Debug, Default)]
+pub struct CappedRows {
+    pub rows: Vec<serde_json::Value>,
+    /// Rows read from the result. Only a lower bound on the result size when
+    /// `truncated` is set, since reading may stop early.
+    pub read: u64,
+    /// Whether rows were dropped to stay within the byte cap.
+    pub truncated: bool,
+}
+
+pub trait SqlTransaction {
+    /// Executes a statement and returns the rows it affected, or the rows it
+    /// returned for statements producing a result set.
+    fn execute(&self, sql: &str, params: &SqlParams) -> Result<u64, String>;
+    /// Runs a query and returns each row as a JSON object keyed by column name.
+    fn query(&self, sql: &str, params: &SqlParams) -> Result<Vec<serde_json::Value>, String>;
+    /// Like [`SqlTransaction::query`], but keeps rows only while their
+    /// serialized size stays within `max_bytes`. Once the cap is hit, later
+    /// rows are only counted, and reading stops after `count_to` rows. The
+    /// default reads every row; backends that can stream override it.
+    fn query_capped(
+        &self,
+        sql: &str,
+        params: &SqlParams,
+        max_bytes: usize,
+        _count_to: u64,
+    ) -> Result<CappedRows, String> {
+        let mut rows = self.query(sql, params)?;
+        let read = rows.len() as u64;
+        let mut bytes = 0;
+        let keep = rows
+            .iter()
+            .take_while(|row| {
+                bytes += row.to_string().len();
+                bytes <= max_bytes
+            })
+            .count();
+        let truncated = keep < rows.len();
+        rows.truncate(keep);
+        Ok(CappedRows {
+            rows,
+            read,
+            truncated,
+        })
+    }
+
+    /// Returns the query plan the database would use for `sql`. The default runs
+    /// SQLite's `EXPLAIN QUERY PLAN`; other backends map their `EXPLAIN` output
+    /// to the same steps.
+    fn explain(&self, sql: &str, params: &SqlParams) -> Result<Vec<PlanStep>, String> {
+        self.query(&format!("EXPLAIN QUERY PLAN {sql}"), params)?
+            .iter()
+            .map(|row| {
+                Ok(PlanStep {
+                    id: row["id"].as_i64().unwrap_or_default(),
+                    parent: row["parent"].as_i64().unwrap_or_default(),
+                    detail: row["detail"]
+                        .as_str()
+                        .ok_or_else(|| "Query plan row has no detail".to_string())?
+                        .to_string(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// One node of a query plan, such as `SCAN users` or
+/// `SEARCH orders USING INDEX orders_tenant (tenant_id=?)`.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+pub struct PlanStep {
+    pub id: i64,
+    pub parent: i64,
+    pub detail: String,
+}
+
+/// Bind parameters for a statement: a JSON array for `?`/`$N` placeholders or
+/// a JSON object for `:name`/`$name` placeholders.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+#[serde(untagged)]
+pub enum SqlParams {
+    Positional(Vec<serde_json::Value>),
+    Named(HashMap<String, serde_json::Value>),
+}
+
+impl Default for SqlParams {
+    fn default() -> Self {
+        SqlParams::Positional(Vec::new())
+    }
+}
+
+impl SqlParams {
+    pub fn is_empty(&self) -> bool {
+        match self {
+            SqlParams::Positional(values) => values.is_empty(),
+            SqlParams::Named(values) => values.is_empty(),
+        }
+    }
+
+    /// Looks up the value for a normalized placeholder (`?N`, `:name`, `$name`
+    /// or `@name`).
+    pub fn value_for(&self, placeholder: &str) -> Option<&serde_json::Value> {
+        match self {
+            SqlParams::Positional(values) => {
+                let index = placeholder.strip_prefix('?')?.parse::<usize>().ok()?;
+                values.get(index.checked_sub(1)?)
+            }
+            SqlParams::Named(values) => {
+                let name = placeholder_name(placeholder);
+                values
+                    .iter()
+                    .find(|(key, _)| placeholder_name(key) == name)
+                    .map(|(_, value)| value)
+            }
+        }
+    }
+}
+
+/// The name of a named placeholder or param key, without its `:`, `$` or `@`
+/// prefix, so `:id`, `$id`, `@id` and `id` all refer to the same parameter.
+pub fn placeholder_name(placeholder: &str) -> &str {
+    placeholder.trim_start_matches([':', '$', '@'])
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransactionMode {
+    Commit,
+    Rollback,
+}
+
+/// Runs `work` in a transaction on `db` and hands back its result. Failures
+/// to begin or finish the transaction are reported as `DbError`.
+pub fn with_transaction<T>(
+    db: &dyn SQLDB,
+    mode: TransactionMode,
+    work: impl FnOnce(&dyn SqlTransaction) -> ProxyResult<T>,
+) -> ProxyResult<T> {
+    let mut work = Some(work);
+    let mut output = None;
+    let outcome = db.transaction(mode, &mut |tx| {
+        let work = work
+            .take()
+            .ok_or_else(|| "Transaction work already ran".to_string())?;
+        let result = work(tx);
+        let failed = result.is_err();
+        output = Some(result);
+        if failed {
+            Err("Transaction work failed".to_string())
+        } else {
+            Ok(())
+        }
+    });
+    match output {
+        Some(Err(error)) => Err(error),
+        Some(Ok(value)) => outcome.map(|_| value).map_err(ProxyError::db),
+        // `work` is still pending only when the backend failed to begin.
+        None => Err(match outcome {
+            Err(error) if work.is_some() => ProxyError::db(error),
+            _ => ProxyError::internal("Transaction produced no result"),
+        }),
+    }
+}
+
 pub struct SqliteDb {
     connection: Mutex<Connection>,
 }
@@ -26,17 +200,22 @@ pub struct ColumnSchema {
     pub name: String,
     pub data_type: String,
     pub nullable: bool,
+    #[serde(default)]
+    pub primary_key: bool,
 }
 
 impl SqliteDb {
     pub fn new(path: impl AsRef<Path>) -> SqlResult<Self> {
+        let connection = Connection::open(path)?;
+        register_functions(&connection)?;
         Ok(Self {
-            connection: Mutex::new(Connection::open(path)?),
+            connection: Mutex::new(connection),
         })
     }
 }
 
 impl SQLDB for SqliteDb {
+    #[tracing::instrument(name = "db.execute", level = "debug", skip_all, fields(sql = %loggable_sql(sql)), err)]
     fn execute(&self, sql: &str) -> Result<u64, String> {
         let connection = self
             .connection
@@ -48,6 +227,26 @@ impl SQLDB for SqliteDb {
             .map_err(|err| err.to_string())
     }
 
+    #[tracing::instrument(name = "db.transaction", level = "debug", skip_all, fields(mode = ?mode), err)]
+    fn transaction(
+        &self,
+        mode: TransactionMode,
+        work: &mut dyn FnMut(&dyn SqlTransaction) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let mut connection = self
+            .connection
+            .lock()
+            .map_err(|_| "DB connection lock poisoned".to_string())?;
+        let tx = connection.transaction().map_err(|err| err.to_string())?;
+        work(&SqliteTransaction { tx: &tx })?;
+        match mode {
+            TransactionMode::Commit => tx.commit(),
+            TransactionMode::Rollback => tx.rollback(),
+        }
+        .map_err(|err| err.to_string())
+    }
+
+    #[tracing::instrument(name = "db.describe_schema", level = "debug", skip_all, err)]
     fn describe_schema(&self) -> Result<SchemaSnapshot, String> {
         let connection = self
             .connection
@@ -74,6 +273,7 @@ impl SQLDB for SqliteDb {
                         name: row.get::<_, String>(1)?,
                         data_type: row.get::<_, String>(2)?,
                         nullable: row.get::<_, i32>(3)? == 0,
+                        primary_key: row.get::<_, i32>(5)? > 0,
                     })
                 })
                 .map_err(|err| err.to_string())?;
@@ -93,6 +293,175 @@ impl SQLDB for SqliteDb {
     }
 }
 
+/// Registers the SQL functions used by policy rewrites, such as `mask_hash`.
+fn register_functions(connection: &Connection) -> SqlResult<()> {
+    connection.create_scalar_function(
+        "mask_hash",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let text = match ctx.get_raw(0) {
+                ValueRef::Null => return Ok(None),
+                ValueRef::Integer(value) => value.to_string(),
+                ValueRef::Real(value) => value.to_string(),
+                ValueRef::Text(bytes) | ValueRef::Blob(bytes) => {
+                    String::from_utf8_lossy(bytes).into_owned()
+                }
+            };
+            let digest = Sha256::digest(text.as_bytes());
+            Ok(Some(
+                digest[..8]
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect::<String>(),
+            ))
+        },
+    )
+}
+
+struct SqliteTransaction<'a> {
+    tx: &'a rusqlite::Transaction<'a>,
+}
+
+impl SqlTransaction for SqliteTransaction<'_> {
+    #[tracing::instrument(name = "db.statement", level = "debug", skip_all, fields(sql = %loggable_sql(sql)), err)]
+    fn execute(&self, sql: &str, params: &SqlParams) -> Result<u64, String> {
+        let mut statement = self.tx.prepare(sql).map_err(|err| err.to_string())?;
+        bind_params(&mut statement, params)?;
+        if statement.column_count() == 0 {
+            return statement
+                .raw_execute()
+                .map(|rows| rows as u64)
+                .map_err(|err| err.to_string());
+        }
+
+        let mut rows = statement.raw_query();
+        let mut count = 0;
+        while rows.next().map_err(|err| err.to_string())?.is_some() {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn query(&self, sql: &str, params: &SqlParams) -> Result<Vec<serde_json::Value>, String> {
+        self.query_capped(sql, params, usize::MAX, 0)
+            .map(|capped| capped.rows)
+    }
+
+    #[tracing::instrument(name = "db.query", level = "debug", skip_all, fields(sql = %loggable_sql(sql)), err)]
+    fn query_capped(
+        &self,
+        sql: &str,
+        params: &SqlParams,
+        max_bytes: usize,
+        count_to: u64,
+    ) -> Result<CappedRows, String> {
+        let mut statement = self.tx.prepare(sql).map_err(|err| err.to_string())?;
+        bind_params(&mut statement, params)?;
+        let columns: Vec<String> = statement
+            .column_names()
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        let mut rows = statement.raw_query();
+
+        let mut output = CappedRows::default();
+        let mut bytes = 0usize;
+        while let Some(row) = rows.next().map_err(|err| err.to_string())? {
+            output.read += 1;
+            if output.truncated {
+                if output.read >= count_to {
+                    break;
+                }
+                continue;
+            }
+            let mut object = serde_json::Map::new();
+            for (index, column) in columns.iter().enumerate() {
+                let value = row.get_ref(index).map_err(|err| err.to_string())?;
+                object.insert(column.clone(), json_value(value));
+            }
+            let object = serde_json::Value::Object(object);
+            bytes = bytes.saturating_add(object.to_string().len());
+            if bytes > max_bytes {
+                output.truncated = true;
+                if output.read >= count_to {
+                    break;
+                }
+                continue;
+            }
+            output.rows.push(object);
+        }
+        Ok(output)
+    }
+}
+
+/// Binds `params` to the statement. A named parameter the statement does not
+/// reference is rejected; extra positional values past the last placeholder are
+/// ignored.
+fn bind_params(statement: &mut rusqlite::Statement<'_>, params: &SqlParams) -> Result<(), String> {
+    match params {
+        SqlParams::Positional(values) => {
+            let count = statement.parameter_count();
+            for (index, value) in values.iter().enumerate().take(count) {
+                statement
+                    .raw_bind_parameter(index + 1, sql_value(value))
+                    .map_err(|err| err.to_string())?;
+            }
+        }
+        SqlParams::Named(values) => {
+            for (key, value) in values {
+                let name = placeholder_name(key);
+                let mut index = None;
+                for prefix in [":", "$", "@"] {
+                    index = statement
+                        .parameter_index(&format!("{prefix}{name}"))
+                        .map_err(|err| err.to_string())?;
+                    if index.is_some() {
+                        break;
+                    }
+                }
+                let Some(index) = index else {
+                    return Err(format!(
+                        "Bind parameter '{name}' is not used by the statement"
+                    ));
+                };
+                statement
+                    .raw_bind_parameter(index, sql_value(value))
+                    .map_err(|err| err.to_string())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn sql_value(value: &serde_json::Value) -> rusqlite::types::Value {
+    use rusqlite::types::Value;
+    match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(value) => Value::Integer(i64::from(*value)),
+        serde_json::Value::Number(number) => match number.as_i64() {
+            Some(value) => Value::Integer(value),
+            None => Value::Real(number.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(text) => Value::Text(text.clone()),
+        other => Value::Text(other.to_string()),
+    }
+}
+
+fn json_value(value: ValueRef<'_>) -> serde_json::Value {
+    match value {
+        ValueRef::Null => serde_json::Value::Null,
+        ValueRef::Integer(value) => value.into(),
+        ValueRef::Real(value) => value.into(),
+        ValueRef::Text(text) => String::from_utf8_lossy(text).into_owned().into(),
+        ValueRef::Blob(bytes) => bytes
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>()
+            .into(),
+    }
+}
+
 pub fn connect(path: impl AsRef<Path>) -> SqlResult<Connection> {
     Connection::open(path)
 }
@@ -0,0 +1,240 @@
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::trace::TraceStep;
+
+/// Machine-readable category of a failed request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    ParseError,
+    UnsupportedStatement,
+    /// Malformed request, such as missing bind parameters or an empty changeset.
+    InvalidRequest,
+    PolicyDenied,
+    TenantViolation,
+    LimitExceeded,
+    StalePreview,
+    /// The request conflicts with the state of a stored query.
+    Conflict,
+    /// The idempotency key was already used for a different request.
+    IdempotencyMismatch,
+    Forbidden,
+    NotFound,
+    Expired,
+    /// A rate limit of the actor, role or tenant is used up.
+    RateLimited,
+    /// The database rejected or failed to run the statement.
+    DbError,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::ParseError => "parse_error",
+            ErrorCode::UnsupportedStatement => "unsupported_statement",
+            ErrorCode::InvalidRequest => "invalid_request",
+            ErrorCode::PolicyDenied => "policy_denied",
+            ErrorCode::TenantViolation => "tenant_violation",
+            ErrorCode::LimitExceeded => "limit_exceeded",
+            ErrorCode::StalePreview => "stale_preview",
+            ErrorCode::Conflict => "conflict",
+            ErrorCode::IdempotencyMismatch => "idempotency_mismatch",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::Expired => "expired",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::DbError => "db_error",
+            ErrorCode::Internal => "internal",
+        }
+    }
+}
+
+/// The policy rule behind a denial.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, schemars::JsonSchema)]
+pub struct RuleRef {
+    pub rule: String,
+    /// Path of the rule in the policy file, or `builtin`.
+    pub source: String,
+    #[serde(default)]
+    pub table: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, schemars::JsonSchema)]
+pub struct ProxyError {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default)]
+    pub rule: Option<Box<RuleRef>>,
+    /// What to change so the request can succeed.
+    #[serde(default)]
+    pub suggestion: Option<String>,
+    /// 1-based index of the changeset statement that failed.
+    #[serde(default)]
+    pub statement: Option<usize>,
+    #[serde(default)]
+    pub remediation: Option<Box<Remediation>>,
+    /// Seconds to wait before retrying a rate-limited request.
+    #[serde(default)]
+    pub retry_after: Option<u64>,
+}
+
+/// Machine-readable fix for a denied query, derived from the policy and the
+/// database schema.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize, schemars::JsonSchema)]
+pub struct Remediation {
+    /// Predicate to AND into the WHERE clause, such as `tenant_id = 'acme'`.
+    #[serde(default)]
+    pub add_predicate: Option<String>,
+    /// Columns that must be filtered on, with a value the caller has to supply.
+    #[serde(default)]
+    pub filter_columns: Vec<String>,
+    /// Columns to drop from the query.
+    #[serde(default)]
+    pub remove_columns: Vec<String>,
+    /// Columns the query may use instead.
+    #[serde(default)]
+    pub allowed_columns: Vec<String>,
+    #[serde(default)]
+    pub allowed_operations: Vec<String>,
+    /// The request rewritten so that it passes the policy.
+    #[serde(default)]
+    pub suggested_sql: Option<String>,
+}
+
+pub type ProxyResult<T> = Result<T, ProxyError>;
+
+impl ProxyError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            rule: None,
+            suggestion: None,
+            statement: None,
+            remediation: None,
+            retry_after: None,
+        }
+    }
+
+    pub fn invalid(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InvalidRequest, message)
+    }
+
+    pub fn db(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::DbError, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Internal, message)
+    }
+
+    /// Builds the denial for a failed trace step, with the rule that failed.
+    pub fn denied(step: &TraceStep) -> Self {
+        let code = match step.rule.as_str() {
+            "parse" => ErrorCode::ParseError,
+            "tenant_filter" | "tenant_id_immutable" => ErrorCode::TenantViolation,
+            _ => ErrorCode::PolicyDenied,
+        };
+        let message = step.detail.clone().unwrap_or_else(|| {
+            format!("Rule '{}' at {} rejected the query", step.rule, step.source)
+        });
+        Self {
+            code,
+            message,
+            rule: Some(Box::new(RuleRef {
+                rule: step.rule.clone(),
+                source: step.source.clone(),
+                table: step.table.clone(),
+            })),
+            suggestion: suggestion_for(&step.rule, step.table.as_deref()),
+            statement: None,
+            remediation: None,
+            retry_after: None,
+        }
+    }
+
+    pub fn with_suggestion(mut self, suggestion: impl Into<String>) -> Self {
+        self.suggestion = Some(suggestion.into());
+        self
+    }
+
+    /// HTTP status the service answers with.
+    pub fn status(&self) -> StatusCode {
+        match self.code {
+            ErrorCode::ParseError | ErrorCode::UnsupportedStatement | ErrorCode::InvalidRequest => {
+                StatusCode::BAD_REQUEST
+            }
+            ErrorCode::PolicyDenied | ErrorCode::TenantViolation | ErrorCode::Forbidden => {
+                StatusCode::FORBIDDEN
+            }
+            ErrorCode::LimitExceeded | ErrorCode::IdempotencyMismatch | ErrorCode::DbError => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            ErrorCode::StalePreview | ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Expired => StatusCode::GONE,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Marks the error as coming from statement `index` (0-based) of a changeset.
+    pub fn in_statement(mut self, index: usize) -> Self {
+        self.statement = Some(index + 1);
+        self.message = format!("Statement {}: {}", index + 1, self.message);
+        self
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+fn suggestion_for(rule: &str, table: Option<&str>) -> Option<String> {
+    let table = table.unwrap_or("the table");
+    let suggestion = match rule {
+        "where_required" => "Add a WHERE clause that selects only the rows to change".to_string(),
+        "tenant_id_immutable" => "Remove tenant_id from the SET list".to_string(),
+        "tenant_filter" => "Filter on tenant_id in the WHERE clause".to_string(),
+        "allow_ops" => format!("Use an operation the policy allows on {table}"),
+        "writable_columns" => format!("Only write the writable columns of {table}"),
+        "value_constraints" => "Write a literal value that satisfies the constraint".to_string(),
+        "required_filters" => format!("Add the required filter on {table} to the WHERE clause"),
+        "deny_columns" => "Remove the denied columns from the query".to_string(),
+        "required_expressions" => "Add the required expression to the WHERE clause".to_string(),
+        _ => return None,
+    };
+    Some(suggestion)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_codes_to_http_statuses() {
+        let cases = [
+            (ErrorCode::ParseError, StatusCode::BAD_REQUEST),
+            (ErrorCode::InvalidRequest, StatusCode::BAD_REQUEST),
+            (ErrorCode::PolicyDenied, StatusCode::FORBIDDEN),
+            (ErrorCode::TenantViolation, StatusCode::FORBIDDEN),
+            (ErrorCode::LimitExceeded, StatusCode::UNPROCESSABLE_ENTITY),
+            (ErrorCode::DbError, StatusCode::UNPROCESSABLE_ENTITY),
+            (ErrorCode::StalePreview, StatusCode::CONFLICT),
+            (ErrorCode::NotFound, StatusCode::NOT_FOUND),
+            (ErrorCode::Expired, StatusCode::GONE),
+            (ErrorCode::RateLimited, StatusCode::TOO_MANY_REQUESTS),
+            (ErrorCode::Internal, StatusCode::INTERNAL_SERVER_ERROR),
+        ];
+        for (code, status) in cases {
+            assert_eq!(ProxyError::new(code, "failed").status(), status, "{code:?}");
+        }
+    }
+}
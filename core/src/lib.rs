@@ -1,6 +1,18 @@
+pub mod audit;
 pub mod db;
+pub mod error;
 pub mod mcp;
+pub mod metrics;
+pub mod plan;
 pub mod policy;
+pub mod policy_test;
 pub mod query_engine;
 pub mod query_executor;
+pub mod rate_limit;
+pub mod remediation;
+pub mod replay;
+pub mod revert;
+pub mod rewrite;
 pub mod service;
+pub mod telemetry;
+pub mod trace;
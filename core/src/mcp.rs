@@ -1,16 +1,20 @@
-use crate::query_engine::SqlRequest;
-use crate::query_executor::QueryExecutor;
+use crate::error::{ErrorCode, ProxyError};
+use crate::query_engine::{
+    ChangesetRequest, ExplainRequest, QueryContext, QueryGetRequest, QueryListRequest,
+    RevertRequest, SqlRequest,
+};
 use crate::service::AppState;
 use rmcp::{
     ErrorData as McpError, ServerHandler,
     handler::server::{router::tool::ToolRouter, wrapper::Parameters},
-    model::{CallToolResult, Content, ErrorCode, ServerCapabilities, ServerInfo},
+    model::{CallToolResult, Content, ErrorCode as McpErrorCode, ServerCapabilities, ServerInfo},
     tool, tool_handler, tool_router,
 };
 use schemars::JsonSchema;
 use serde::Deserialize;
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use tracing::Instrument;
 
 #[derive(Clone)]
 pub struct AgentProxyMcp {
@@ -18,9 +22,26 @@ pub struct AgentProxyMcp {
     tool_router: ToolRouter<Self>,
 }
 
+/// A stored query id, looked up in the caller's tenant.
 #[derive(Debug, Deserialize, JsonSchema)]
 struct QueryIdRequest {
     id: String,
+    context: QueryContext,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct QueryRevertRequest {
+    id: String,
+    #[serde(flatten)]
+    revert: RevertRequest,
+}
+
+/// Query list filters, scoped to the caller's tenant and actor.
+#[derive(Debug, Deserialize, JsonSchema)]
+struct QueryListToolRequest {
+    context: QueryContext,
+    #[serde(flatten)]
+    filters: QueryListRequest,
 }
 
 #[derive(Debug, Clone, Deserialize, JsonSchema)]
@@ -34,42 +55,117 @@ impl AgentProxyMcp {
         }
     }
 
+    /// Runs a tool call in a span, then logs and counts it by outcome.
+    async fn observe(
+        &self,
+        tool: &'static str,
+        call: impl Future<Output = Result<CallToolResult, McpError>>,
+    ) -> Result<CallToolResult, McpError> {
+        let span = tracing::info_span!("mcp_tool", tool);
+        let result = call.instrument(span.clone()).await;
+        let outcome = if result.is_ok() { "ok" } else { "error" };
+        span.in_scope(|| tracing::info!(outcome, "tool call finished"));
+        self.state.read().await.metrics.inc(
+            "agentproxy_mcp_tool_calls_total",
+            &[("tool", tool), ("outcome", outcome)],
+        );
+        result
+    }
+
     async fn preview_internal(
         &self,
-        payload: SqlRequest,
+        mut payload: SqlRequest,
     ) -> Result<crate::query_engine::PreviewResponse, McpError> {
+        untrusted_client_ip(&mut payload.context);
         let state = self.state.read().await;
-        let executor = QueryExecutor::new(state.engine.clone());
-        let executed = executor
-            .preview(&payload, &state.policy, state.db.as_ref())
-            .map_err(|message| McpError::new(ErrorCode::INVALID_REQUEST, message, None))?;
+        state.preview_sql(&payload).await.map_err(mcp_error)
+    }
+
+    async fn commit_internal(&self, mut payload: SqlRequest) -> Result<CallToolResult, McpError> {
+        untrusted_client_ip(&mut payload.context);
+        let state = self.state.read().await;
+        let response = state.commit_sql(&payload).await.map_err(mcp_error)?;
+        Ok(CallToolResult::success(vec![Content::json(response)?]))
+    }
 
-        Ok(executed.preview)
+    async fn changeset_internal(
+        &self,
+        mut payload: ChangesetRequest,
+        commit: bool,
+    ) -> Result<CallToolResult, McpError> {
+        untrusted_client_ip(&mut payload.context);
+        let state = self.state.read().await;
+        let content = if commit {
+            Content::json(state.commit_changeset(&payload).await.map_err(mcp_error)?)?
+        } else {
+            Content::json(state.preview_changeset(&payload).await.map_err(mcp_error)?)?
+        };
+        Ok(CallToolResult::success(vec![content]))
     }
 
-    async fn commit_internal(&self, payload: SqlRequest) -> Result<CallToolResult, McpError> {
+    async fn revert_internal(
+        &self,
+        mut payload: QueryRevertRequest,
+    ) -> Result<CallToolResult, McpError> {
+        untrusted_client_ip(&mut payload.revert.context);
         let state = self.state.read().await;
-        let executor = QueryExecutor::new(state.engine.clone());
-        let executed = executor
-            .commit(&payload, &state.policy, state.db.as_ref())
-            .map_err(|message| McpError::new(ErrorCode::INTERNAL_ERROR, message, None))?;
+        let content = if payload.revert.preview_id.is_some() {
+            Content::json(
+                state
+                    .commit_revert(&payload.id, &payload.revert)
+                    .await
+                    .map_err(mcp_error)?,
+            )?
+        } else {
+            Content::json(
+                state
+                    .preview_revert(&payload.id, &payload.revert)
+                    .await
+                    .map_err(mcp_error)?,
+            )?
+        };
+        Ok(CallToolResult::success(vec![content]))
+    }
 
-        Ok(CallToolResult::success(vec![Content::json(
-            executed.preview,
-        )?]))
+    async fn list_queries_internal(
+        &self,
+        payload: QueryListToolRequest,
+    ) -> Result<CallToolResult, McpError> {
+        let mut filters = payload.filters;
+        filters.tenant_id = Some(payload.context.tenant_id);
+        filters.actor = Some(payload.context.actor);
+        let state = self.state.read().await;
+        let response = state.list_queries(&filters).await.map_err(mcp_error)?;
+        Ok(CallToolResult::success(vec![Content::json(response)?]))
     }
 
-    async fn get_query_internal(&self, id: String) -> Result<CallToolResult, McpError> {
+    async fn get_query_internal(
+        &self,
+        payload: QueryIdRequest,
+    ) -> Result<CallToolResult, McpError> {
+        let request = QueryGetRequest {
+            tenant_id: Some(payload.context.tenant_id),
+        };
         let state = self.state.read().await;
-        let store = state.store.read().await;
-        let record = store
-            .entries
-            .get(&id)
-            .map(|entry| &entry.record)
-            .ok_or_else(|| McpError::new(ErrorCode::RESOURCE_NOT_FOUND, "Query not found", None))?;
+        let record = state
+            .get_query(&payload.id, &request)
+            .await
+            .map_err(mcp_error)?;
         Ok(CallToolResult::success(vec![Content::json(record)?]))
     }
 
+    async fn explain_internal(
+        &self,
+        mut payload: ExplainRequest,
+    ) -> Result<CallToolResult, McpError> {
+        if let Some(request) = &mut payload.request {
+            untrusted_client_ip(&mut request.context);
+        }
+        let state = self.state.read().await;
+        let response = state.explain_decision(&payload).await.map_err(mcp_error)?;
+        Ok(CallToolResult::success(vec![Content::json(response)?]))
+    }
+
     async fn policy_internal(&self) -> Result<CallToolResult, McpError> {
         let state = self.state.read().await;
         Ok(CallToolResult::success(vec![Content::json(&state.policy)?]))
@@ -79,18 +175,39 @@ impl AgentProxyMcp {
         let state = self.state.read().await;
         let db = state.db.as_ref().ok_or_else(|| {
             McpError::new(
-                ErrorCode::RESOURCE_NOT_FOUND,
+                McpErrorCode::RESOURCE_NOT_FOUND,
                 "No database configured",
                 None,
             )
         })?;
         let schema = db
             .describe_schema()
-            .map_err(|message| McpError::new(ErrorCode::INTERNAL_ERROR, message, None))?;
+            .map_err(|message| McpError::new(McpErrorCode::INTERNAL_ERROR, message, None))?;
         Ok(CallToolResult::success(vec![Content::json(schema)?]))
     }
 }
 
+/// MCP calls have no peer address, so a `client_ip` sent in the arguments is
+/// dropped rather than trusted by `client_ip` conditions.
+fn untrusted_client_ip(context: &mut QueryContext) {
+    context.client_ip = None;
+}
+
+/// Maps a service error to an MCP error, with the structured error as data so
+/// agents can read the code, rule and suggestion.
+fn mcp_error(error: ProxyError) -> McpError {
+    let code = match error.code {
+        ErrorCode::ParseError | ErrorCode::UnsupportedStatement | ErrorCode::InvalidRequest => {
+            McpErrorCode::INVALID_PARAMS
+        }
+        ErrorCode::NotFound => McpErrorCode::RESOURCE_NOT_FOUND,
+        ErrorCode::DbError | ErrorCode::Internal => McpErrorCode::INTERNAL_ERROR,
+        _ => McpErrorCode::INVALID_REQUEST,
+    };
+    let data = serde_json::to_value(&error).ok();
+    McpError::new(code, error.message, data)
+}
+
 #[tool_router]
 impl AgentProxyMcp {
     #[tool(description = "Preview SQL with policy enforcement")]
@@ -98,24 +215,80 @@ impl AgentProxyMcp {
         &self,
         Parameters(payload): Parameters<SqlRequest>,
     ) -> Result<CallToolResult, McpError> {
-        let preview = self.preview_internal(payload).await?;
-        Ok(CallToolResult::success(vec![Content::json(preview)?]))
+        self.observe("sql_preview", async {
+            let preview = self.preview_internal(payload).await?;
+            Ok(CallToolResult::success(vec![Content::json(preview)?]))
+        })
+        .await
     }
 
-    #[tool(description = "Commit SQL after preview (stub)")]
+    #[tool(description = "Commit SQL against an earlier preview_id; writes require one")]
     async fn sql_commit(
         &self,
         Parameters(payload): Parameters<SqlRequest>,
     ) -> Result<CallToolResult, McpError> {
-        self.commit_internal(payload).await
+        self.observe("sql_commit", self.commit_internal(payload))
+            .await
+    }
+
+    #[tool(description = "Preview an ordered list of SQL statements as one changeset")]
+    async fn changeset_preview(
+        &self,
+        Parameters(payload): Parameters<ChangesetRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        self.observe("changeset_preview", self.changeset_internal(payload, false))
+            .await
+    }
+
+    #[tool(description = "Commit a previewed changeset atomically; writes require its preview_id")]
+    async fn changeset_commit(
+        &self,
+        Parameters(payload): Parameters<ChangesetRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        self.observe("changeset_commit", self.changeset_internal(payload, true))
+            .await
     }
 
-    #[tool(description = "Get stored query metadata")]
+    #[tool(
+        description = "List the caller's stored queries (actor and tenant from context) filtered by role, status, operation, table or time range"
+    )]
+    async fn queries_list(
+        &self,
+        Parameters(payload): Parameters<QueryListToolRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        self.observe("queries_list", self.list_queries_internal(payload))
+            .await
+    }
+
+    #[tool(description = "Get a stored query of the caller's tenant (from context)")]
     async fn queries_get(
         &self,
         Parameters(payload): Parameters<QueryIdRequest>,
     ) -> Result<CallToolResult, McpError> {
-        self.get_query_internal(payload.id).await
+        self.observe("queries_get", self.get_query_internal(payload))
+            .await
+    }
+
+    #[tool(
+        description = "Revert a committed query: without preview_id previews the inverse changes, with the returned preview_id commits them"
+    )]
+    async fn queries_revert(
+        &self,
+        Parameters(payload): Parameters<QueryRevertRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        self.observe("queries_revert", self.revert_internal(payload))
+            .await
+    }
+
+    #[tool(
+        description = "Explain which policy rules allowed or denied a stored query (by id) or a SQL request"
+    )]
+    async fn explain_decision(
+        &self,
+        Parameters(payload): Parameters<ExplainRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        self.observe("explain_decision", self.explain_internal(payload))
+            .await
     }
 
     #[tool(description = "Describe active policy config")]
@@ -123,7 +296,8 @@ impl AgentProxyMcp {
         &self,
         Parameters(_): Parameters<EmptyRequest>,
     ) -> Result<CallToolResult, McpError> {
-        self.policy_internal().await
+        self.observe("policy_describe", self.policy_internal())
+            .await
     }
 
     #[tool(description = "Describe schema tables")]
@@ -131,7 +305,8 @@ impl AgentProxyMcp {
         &self,
         Parameters(_): Parameters<EmptyRequest>,
     ) -> Result<CallToolResult, McpError> {
-        self.schema_internal().await
+        self.observe("schema_describe", self.schema_internal())
+            .await
     }
 }
 
@@ -148,3 +323,25 @@ impl ServerHandler for AgentProxyMcp {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_proxy_errors_to_mcp_codes() {
+        let cases = [
+            (ErrorCode::ParseError, McpErrorCode::INVALID_PARAMS),
+            (ErrorCode::InvalidRequest, McpErrorCode::INVALID_PARAMS),
+            (ErrorCode::NotFound, McpErrorCode::RESOURCE_NOT_FOUND),
+            (ErrorCode::DbError, McpErrorCode::INTERNAL_ERROR),
+            (ErrorCode::Internal, McpErrorCode::INTERNAL_ERROR),
+            (ErrorCode::PolicyDenied, McpErrorCode::INVALID_REQUEST),
+        ];
+        for (code, expected) in cases {
+            let error = mcp_error(ProxyError::new(code, "failed"));
+            assert_eq!(error.code, expected, "{code:?}");
+            assert_eq!(error.data.unwrap()["code"], code.as_str());
+        }
+    }
+}
@@ -0,0 +1,327 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    sync::Mutex,
+};
+
+use crate::{error::ProxyResult, query_engine::QueryContext};
+
+/// Upper bounds, in seconds, of the latency histogram buckets.
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Distinct actors given their own label; later ones are counted as `other`,
+/// since the actor is chosen by the client.
+const MAX_ACTOR_LABELS: usize = 100;
+
+/// Every metric the proxy exports: name, type and help text.
+const FAMILIES: &[(&str, &str, &str)] = &[
+    (
+        "agentproxy_previews_total",
+        "counter",
+        "Previews of statements and changesets by outcome (allowed, denied or error).",
+    ),
+    (
+        "agentproxy_commits_total",
+        "counter",
+        "Commits of statements and changesets by outcome (committed, denied or error).",
+    ),
+    (
+        "agentproxy_denials_total",
+        "counter",
+        "Requests rejected by a policy rule or limit, by error code, rule and table.",
+    ),
+    (
+        "agentproxy_actor_requests_total",
+        "counter",
+        "Previews and commits per actor and outcome; actors past the first 100 are counted as other.",
+    ),
+    (
+        "agentproxy_actor_rows_written_total",
+        "counter",
+        "Rows written by committed statements per actor; actors past the first 100 are counted as other.",
+    ),
+    (
+        "agentproxy_db_duration_seconds",
+        "histogram",
+        "Time spent in database transactions, by phase (preview or commit).",
+    ),
+    (
+        "agentproxy_http_requests_total",
+        "counter",
+        "HTTP requests by route and status.",
+    ),
+    (
+        "agentproxy_http_request_duration_seconds",
+        "histogram",
+        "HTTP request latency by route.",
+    ),
+    (
+        "agentproxy_mcp_tool_calls_total",
+        "counter",
+        "MCP tool calls by tool and outcome.",
+    ),
+    (
+        "agentproxy_audit_failures_total",
+        "counter",
+        "Audit log entries that could not be written, by event.",
+    ),
+    (
+        "agentproxy_stored_queries",
+        "gauge",
+        "Queries in the query store by status.",
+    ),
+    (
+        "agentproxy_pending_previews",
+        "gauge",
+        "Previews awaiting a commit.",
+    ),
+];
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative.
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// In-process metrics rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    counters: Mutex<BTreeMap<(&'static str, Labels), f64>>,
+    histograms: Mutex<BTreeMap<(&'static str, Labels), Histogram>>,
+    actors: Mutex<BTreeSet<String>>,
+}
+
+impl Metrics {
+    pub fn inc(&self, name: &'static str, labels: &[(&'static str, &str)]) {
+        self.add(name, labels, 1.0);
+    }
+
+    pub fn add(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        if let Ok(mut counters) = self.counters.lock() {
+            *counters.entry((name, owned(labels))).or_default() += value;
+        }
+    }
+
+    /// Sets a gauge; gauges share storage with counters.
+    pub fn set(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        if let Ok(mut counters) = self.counters.lock() {
+            counters.insert((name, owned(labels)), value);
+        }
+    }
+
+    pub fn observe(&self, name: &'static str, labels: &[(&'static str, &str)], seconds: f64) {
+        let Ok(mut histograms) = self.histograms.lock() else {
+            return;
+        };
+        let histogram = histograms.entry((name, owned(labels))).or_default();
+        if let Some(index) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            histogram.counts[index] += 1;
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    /// The label value for `actor`: the actor itself while fewer than
+    /// `MAX_ACTOR_LABELS` actors have been seen, `other` after that.
+    pub fn actor_label<'a>(&self, actor: &'a str) -> &'a str {
+        let Ok(mut actors) = self.actors.lock() else {
+            return "other";
+        };
+        if actors.contains(actor) || actors.len() < MAX_ACTOR_LABELS {
+            actors.insert(actor.to_string());
+            actor
+        } else {
+            "other"
+        }
+    }
+
+    /// Counts a preview or commit (`kind`) under its outcome, and a denial by
+    /// rule and table when a policy rule or limit rejected it.
+    pub fn record_request<T>(&self, kind: &str, context: &QueryContext, result: &ProxyResult<T>) {
+        let (name, success) = match kind {
+            "preview" => ("agentproxy_previews_total", "allowed"),
+            _ => ("agentproxy_commits_total", "committed"),
+        };
+        let outcome = match result {
+            Ok(_) => success,
+            Err(error) if error.rule.is_some() => "denied",
+            Err(_) => "error",
+        };
+        self.inc(name, &[("outcome", outcome)]);
+        self.inc(
+            "agentproxy_actor_requests_total",
+            &[
+                ("actor", self.actor_label(&context.actor)),
+                ("kind", kind),
+                ("outcome", outcome),
+            ],
+        );
+        if let Err(error) = result
+            && let Some(rule) = &error.rule
+        {
+            self.inc(
+                "agentproxy_denials_total",
+                &[
+                    ("code", error.code.as_str()),
+                    ("rule", &rule.rule),
+                    ("table", rule.table.as_deref().unwrap_or_default()),
+                ],
+            );
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let counters = match self.counters.lock() {
+            Ok(counters) => counters
+                .iter()
+                .map(|((name, labels), value)| (*name, labels.clone(), *value))
+                .collect::<Vec<_>>(),
+            Err(_) => Vec::new(),
+        };
+        let histograms = match self.histograms.lock() {
+            Ok(histograms) => histograms
+                .iter()
+                .map(|((name, labels), histogram)| {
+                    (
+                        *name,
+                        labels.clone(),
+                        histogram.counts,
+                        histogram.count,
+                        histogram.sum,
+                    )
+                })
+                .collect::<Vec<_>>(),
+            Err(_) => Vec::new(),
+        };
+
+        let mut output = String::new();
+        for (family, kind, help) in FAMILIES {
+            let _ = writeln!(output, "# HELP {family} {help}");
+            let _ = writeln!(output, "# TYPE {family} {kind}");
+            for (_, labels, value) in counters.iter().filter(|(name, ..)| name == family) {
+                let _ = writeln!(output, "{family}{} {value}", format_labels(labels, None));
+            }
+            for (_, labels, counts, count, sum) in
+                histograms.iter().filter(|(name, ..)| name == family)
+            {
+                let mut cumulative = 0;
+                for (bound, bucket) in BUCKETS.iter().zip(counts) {
+                    cumulative += bucket;
+                    let _ = writeln!(
+                        output,
+                        "{family}_bucket{} {cumulative}",
+                        format_labels(labels, Some(&bound.to_string()))
+                    );
+                }
+                let _ = writeln!(
+                    output,
+                    "{family}_bucket{} {count}",
+                    format_labels(labels, Some("+Inf"))
+                );
+                let _ = writeln!(output, "{family}_sum{} {sum}", format_labels(labels, None));
+                let _ = writeln!(
+                    output,
+                    "{family}_count{} {count}",
+                    format_labels(labels, None)
+                );
+            }
+        }
+        output
+    }
+}
+
+fn owned(labels: &[(&'static str, &str)]) -> Labels {
+    labels
+        .iter()
+        .map(|(name, value)| (*name, value.to_string()))
+        .collect()
+}
+
+fn format_labels(labels: &Labels, le: Option<&str>) -> String {
+    let mut parts: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{name}=\"{}\"", escape(value)))
+        .collect();
+    if let Some(le) = le {
+        parts.push(format!("le=\"{le}\""));
+    }
+    if parts.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", parts.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_counters_and_cumulative_histograms() {
+        let metrics = Metrics::default();
+        metrics.inc("agentproxy_previews_total", &[("outcome", "allowed")]);
+        metrics.inc("agentproxy_previews_total", &[("outcome", "allowed")]);
+        metrics.inc(
+            "agentproxy_denials_total",
+            &[("rule", "deny_columns"), ("table", "users \"x\"")],
+        );
+        metrics.observe(
+            "agentproxy_db_duration_seconds",
+            &[("phase", "preview")],
+            0.003,
+        );
+        metrics.observe(
+            "agentproxy_db_duration_seconds",
+            &[("phase", "preview")],
+            20.0,
+        );
+
+        let output = metrics.render();
+        assert!(output.contains("# TYPE agentproxy_previews_total counter"));
+        assert!(output.contains("agentproxy_previews_total{outcome=\"allowed\"} 2"));
+        assert!(output.contains(
+            "agentproxy_denials_total{rule=\"deny_columns\",table=\"users \\\"x\\\"\"} 1"
+        ));
+        assert!(
+            output.contains(
+                "agentproxy_db_duration_seconds_bucket{phase=\"preview\",le=\"0.001\"} 0"
+            )
+        );
+        assert!(
+            output.contains(
+                "agentproxy_db_duration_seconds_bucket{phase=\"preview\",le=\"0.005\"} 1"
+            )
+        );
+        assert!(
+            output
+                .contains("agentproxy_db_duration_seconds_bucket{phase=\"preview\",le=\"+Inf\"} 2")
+        );
+        assert!(output.contains("agentproxy_db_duration_seconds_count{phase=\"preview\"} 2"));
+    }
+
+    #[test]
+    fn caps_actor_labels() {
+        let metrics = Metrics::default();
+        for index in 0..MAX_ACTOR_LABELS {
+            assert_eq!(
+                metrics.actor_label(&format!("agent:{index}")),
+                format!("agent:{index}")
+            );
+        }
+        assert_eq!(metrics.actor_label("agent:late"), "other");
+        assert_eq!(metrics.actor_label("agent:0"), "agent:0");
+    }
+}
@@ -0,0 +1,573 @@
+use serde::{Deserialize, Serialize};
+use sqlparser::ast::{
+    BinaryOperator, Expr, JoinConstraint, JoinOperator, Query, SetExpr, Statement, TableFactor,
+    TableWithJoins, Visit, Visitor, visit_expressions, visit_relations,
+};
+use std::ops::ControlFlow;
+
+use crate::{
+    db::{PlanStep, SqlParams, SqlTransaction},
+    error::{ErrorCode, ProxyError, ProxyResult, RuleRef},
+    policy::PlanLimits,
+    query_engine::table_name_to_string,
+};
+
+/// The database's plan for a previewed statement and what is risky about it.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, schemars::JsonSchema)]
+pub struct QueryPlan {
+    pub steps: Vec<PlanStep>,
+    pub findings: Vec<PlanFinding>,
+    /// Rows read by scans: their sum, or their product when the
+    /// tables are joined without a condition. Row counts stop just above the
+    /// largest plan threshold, and are only taken when one is set; without
+    /// them the cost and the full-scan findings are left out.
+    pub estimated_cost: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, schemars::JsonSchema)]
+pub struct PlanFinding {
+    pub kind: PlanFindingKind,
+    #[serde(default)]
+    pub table: Option<String>,
+    #[serde(default)]
+    pub rows: Option<u64>,
+    pub detail: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanFindingKind {
+    /// A large table is read without an index.
+    FullScan,
+    /// Tables are joined without a join condition.
+    CartesianJoin,
+    /// A filter or join is not served by an index, or the database had to
+    /// build a temporary one.
+    MissingIndex,
+}
+
+impl QueryPlan {
+    /// Explains `sql` in `tx` and inspects the plan. `statement` is the parsed
+    /// statement, used for its tables, WHERE clause and joins.
+    pub fn analyze(
+        tx: &dyn SqlTransaction,
+        sql: &str,
+        params: &SqlParams,
+        statement: &Statement,
+        limits: &PlanLimits,
+    ) -> Result<Self, String> {
+        let steps = tx.explain(sql, params)?;
+        let relations = relations(statement);
+        let filtered = has_filter(statement);
+        let cartesian = cartesian_join(statement);
+        let cap = limits
+            .large_table_rows
+            .max(limits.max_estimated_cost.unwrap_or_default())
+            .saturating_add(1);
+
+        let mut findings = Vec::new();
+        let mut scanned = Vec::new();
+        for step in &steps {
+            let Some(access) = TableAccess::parse(&step.detail, &relations) else {
+                continue;
+            };
+            if access.automatic_index {
+                findings.push(PlanFinding {
+                    kind: PlanFindingKind::MissingIndex,
+                    table: Some(access.table.clone()),
+                    rows: None,
+                    detail: format!(
+                        "The database builds a temporary index on table '{}'",
+                        access.table
+                    ),
+                });
+            }
+            if !access.scan {
+                continue;
+            }
+
+            // Counting rows costs a scan of its own, so it is only done when a
+            // threshold needs the counts.
+            if !limits.is_set() {
+                continue;
+            }
+            // A scan through an index still reads every row, but only a scan
+            // of the table itself is a finding.
+            let rows = count_rows(tx, &access.table, cap)?;
+            scanned.push(rows);
+            if access.uses_index || rows < limits.large_table_rows {
+                continue;
+            }
+            findings.push(PlanFinding {
+                kind: PlanFindingKind::FullScan,
+                table: Some(access.table.clone()),
+                rows: Some(rows),
+                detail: format!("Full scan of table '{}' ({rows} rows)", access.table),
+            });
+            if filtered {
+                findings.push(PlanFinding {
+                    kind: PlanFindingKind::MissingIndex,
+                    table: Some(access.table.clone()),
+                    rows: Some(rows),
+                    detail: format!("No index serves the filter on table '{}'", access.table),
+                });
+            }
+        }
+
+        if cartesian {
+            findings.push(PlanFinding {
+                kind: PlanFindingKind::CartesianJoin,
+                table: None,
+                rows: None,
+                detail: "Tables are joined without a join condition".to_string(),
+            });
+        }
+
+        let estimated_cost = if cartesian && scanned.len() > 1 {
+            scanned
+                .iter()
+                .fold(1u64, |cost, rows| cost.saturating_mul(*rows))
+        } else {
+            scanned
+                .iter()
+                .fold(0u64, |cost, rows| cost.saturating_add(*rows))
+        };
+
+        Ok(Self {
+            steps,
+            findings,
+            estimated_cost,
+        })
+    }
+
+    /// Rejects the plan when it breaks one of the `limits.plan` thresholds.
+    pub fn check(&self, limits: &PlanLimits) -> ProxyResult<()> {
+        let found = |kind| {
+            self.findings
+                .iter()
+                .filter(move |finding| finding.kind == kind)
+        };
+
+        let full_scans = found(PlanFindingKind::FullScan).count();
+        if let Some(max) = limits.max_full_scans
+            && full_scans > max
+        {
+            let table = found(PlanFindingKind::FullScan).find_map(|finding| finding.table.clone());
+            return Err(plan_error(
+                "max_full_scans",
+                table,
+                format!(
+                    "Query plan does {full_scans} full scans of large tables, exceeding the limit of {max}"
+                ),
+            ));
+        }
+        if let Some(max) = limits.max_estimated_cost
+            && self.estimated_cost > max
+        {
+            return Err(plan_error(
+                "max_estimated_cost",
+                None,
+                format!(
+                    "Query plan reads an estimated {} rows, exceeding the limit of {max}",
+                    self.estimated_cost
+                ),
+            ));
+        }
+        if limits.deny_cartesian_joins
+            && let Some(finding) = found(PlanFindingKind::CartesianJoin).next()
+        {
+            return Err(plan_error(
+                "deny_cartesian_joins",
+                None,
+                finding.detail.clone(),
+            ));
+        }
+        if limits.deny_missing_index
+            && let Some(finding) = found(PlanFindingKind::MissingIndex).next()
+        {
+            return Err(plan_error(
+                "deny_missing_index",
+                finding.table.clone(),
+                finding.detail.clone(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn plan_error(rule: &str, table: Option<String>, message: String) -> ProxyError {
+    let mut error = ProxyError::new(ErrorCode::LimitExceeded, message).with_suggestion(
+        "Filter on indexed columns, add join conditions or ask for an index on the table",
+    );
+    error.rule = Some(Box::new(RuleRef {
+        rule: rule.to_string(),
+        source: format!("limits.plan.{rule}"),
+        table,
+    }));
+    error
+}
+
+/// How a plan step reads one of the statement's tables.
+struct TableAccess {
+    table: String,
+    scan: bool,
+    uses_index: bool,
+    automatic_index: bool,
+}
+
+impl TableAccess {
+    /// Parses SQLite plan details such as `SCAN users`, `SCAN TABLE users AS u`
+    /// or `SEARCH orders USING INDEX orders_tenant (tenant_id=?)`. Steps that
+    /// do not name one of `relations`, like `SCAN CONSTANT ROW`, are skipped.
+    fn parse(detail: &str, relations: &[(String, Option<String>)]) -> Option<Self> {
+        let mut words = detail.split_whitespace();
+        let scan = match words.next()? {
+            "SCAN" => true,
+            "SEARCH" => false,
+            _ => return None,
+        };
+        let mut name = words.next()?;
+        if name == "TABLE" {
+            name = words.next()?;
+        }
+        let alias = match words.next() {
+            Some("AS") => words.next(),
+            _ => None,
+        };
+        let table = relations
+            .iter()
+            .find(|(table, table_alias)| {
+                let matches = |candidate: &str| {
+                    table.eq_ignore_ascii_case(candidate)
+                        || table_alias
+                            .as_deref()
+                            .is_some_and(|table_alias| table_alias.eq_ignore_ascii_case(candidate))
+                };
+                matches(name) || alias.is_some_and(matches)
+            })
+            .map(|(table, _)| table.clone())?;
+
+        let uses_index = detail.contains(" USING ")
+            && (detail.contains("INDEX") || detail.contains("PRIMARY KEY"));
+        Some(Self {
+            table,
+            scan,
+            uses_index,
+            automatic_index: detail.contains("AUTOMATIC"),
+        })
+    }
+}
+
+/// Counts the rows of `table`, stopping at `cap` so large tables stay cheap.
+fn count_rows(tx: &dyn SqlTransaction, table: &str, cap: u64) -> Result<u64, String> {
+    let quoted = table
+        .split('.')
+        .map(|part| format!("\"{}\"", part.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(".");
+    let rows = tx.query(
+        &format!("SELECT COUNT(*) AS count FROM (SELECT 1 FROM {quoted} LIMIT {cap})"),
+        &SqlParams::default(),
+    )?;
+    Ok(rows
+        .first()
+        .and_then(|row| row["count"].as_u64())
+        .unwrap_or_default())
+}
+
+/// Every table the statement reads or writes, with the alias it goes by.
+fn relations(statement: &Statement) -> Vec<(String, Option<String>)> {
+    let mut names = Vec::new();
+    let _ = visit_relations(statement, |name| {
+        names.push(table_name_to_string(name));
+        ControlFlow::<()>::Continue(())
+    });
+
+    let mut aliases = AliasCollector::default();
+    let _ = statement.visit(&mut aliases);
+    names.sort();
+    names.dedup();
+    let mut relations: Vec<_> = names.into_iter().map(|name| (name, None)).collect();
+    for (table, alias) in aliases.aliases {
+        relations.push((table, Some(alias)));
+    }
+    relations
+}
+
+fn has_filter(statement: &Statement) -> bool {
+    match statement {
+        Statement::Update { selection, .. } | Statement::Delete { selection, .. } => {
+            selection.is_some()
+        }
+        Statement::Query(query) => match query.body.as_ref() {
+            SetExpr::Select(select) => {
+                select.selection.is_some()
+                    || select.from.iter().any(|table| !table.joins.is_empty())
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Whether any query in the statement joins tables without a condition: an
+/// explicit CROSS JOIN, a join without ON or USING, or a comma-separated FROM
+/// list whose tables the WHERE clause does not connect.
+fn cartesian_join(statement: &Statement) -> bool {
+    let mut finder = CartesianFinder::default();
+    let _ = statement.visit(&mut finder);
+    finder.found
+}
+
+#[derive(Default)]
+struct CartesianFinder {
+    found: bool,
+}
+
+impl Visitor for CartesianFinder {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, query: &Query) -> ControlFlow<Self::Break> {
+        let SetExpr::Select(select) = query.body.as_ref() else {
+            return ControlFlow::Continue(());
+        };
+        let unconditioned = select
+            .from
+            .iter()
+            .flat_map(|table| &table.joins)
+            .any(|join| {
+                matches!(
+                    join.join_operator,
+                    JoinOperator::CrossJoin
+                        | JoinOperator::Inner(JoinConstraint::None)
+                        | JoinOperator::LeftOuter(JoinConstraint::None)
+                        | JoinOperator::RightOuter(JoinConstraint::None)
+                        | JoinOperator::FullOuter(JoinConstraint::None)
+                )
+            });
+        if unconditioned || !from_items_connected(&select.from, &select.selection) {
+            self.found = true;
+            return ControlFlow::Break(());
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// Whether the comparisons in `selection` link every item of a comma-separated
+/// FROM list. A comparison connects the items its two sides name through
+/// qualified columns; one with an unqualified column on both sides cannot be
+/// attributed without the schema and is taken as a join condition.
+fn from_items_connected(from: &[TableWithJoins], selection: &Option<Expr>) -> bool {
+    if from.len() < 2 {
+        return true;
+    }
+    let names: Vec<Vec<String>> = from
+        .iter()
+        .map(|table| {
+            std::iter::once(&table.relation)
+                .chain(table.joins.iter().map(|join| &join.relation))
+                .flat_map(relation_names)
+                .collect()
+        })
+        .collect();
+    let item_of = |qualifier: &str| {
+        names.iter().position(|names| {
+            names
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(qualifier))
+        })
+    };
+
+    // Each item's group; connected items share one.
+    let mut group: Vec<usize> = (0..from.len()).collect();
+    let mut unattributed = false;
+    let _ = visit_expressions(selection, |expr| {
+        let Expr::BinaryOp { left, op, right } = expr else {
+            return ControlFlow::<()>::Continue(());
+        };
+        if !matches!(
+            op,
+            BinaryOperator::Eq
+                | BinaryOperator::NotEq
+                | BinaryOperator::Lt
+                | BinaryOperator::LtEq
+                | BinaryOperator::Gt
+                | BinaryOperator::GtEq
+        ) {
+            return ControlFlow::Continue(());
+        }
+        let (left, left_unqualified) = column_qualifiers(left);
+        let (right, right_unqualified) = column_qualifiers(right);
+        if left_unqualified && right_unqualified {
+            unattributed = true;
+            return ControlFlow::Break(());
+        }
+        if left.is_empty() || right.is_empty() {
+            return ControlFlow::Continue(());
+        }
+        let items: Vec<usize> = left
+            .iter()
+            .chain(&right)
+            .filter_map(|qualifier| item_of(qualifier))
+            .collect();
+        if let Some(&first) = items.first() {
+            let target = group[first];
+            for item in items {
+                let merged = group[item];
+                for entry in group.iter_mut().filter(|entry| **entry == merged) {
+                    *entry = target;
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    });
+    unattributed || group.iter().all(|entry| *entry == group[0])
+}
+
+/// Table qualifiers of the columns `expr` references, and whether it also
+/// references an unqualified column.
+fn column_qualifiers(expr: &Expr) -> (Vec<String>, bool) {
+    let mut qualifiers = Vec::new();
+    let mut unqualified = false;
+    let _ = visit_expressions(expr, |expr| {
+        match expr {
+            Expr::Identifier(_) => unqualified = true,
+            Expr::CompoundIdentifier(parts) if parts.len() >= 2 => {
+                qualifiers.push(parts[parts.len() - 2].value.clone());
+            }
+            _ => {}
+        }
+        ControlFlow::<()>::Continue(())
+    });
+    (qualifiers, unqualified)
+}
+
+/// Names a FROM relation can be referred to by: its alias, else its table name.
+fn relation_names(relation: &TableFactor) -> Vec<String> {
+    match relation {
+        TableFactor::Table {
+            alias: Some(alias), ..
+        } => vec![alias.name.value.clone()],
+        TableFactor::Table { name, .. } => name
+            .0
+            .last()
+            .map(|part| vec![part.value.clone()])
+            .unwrap_or_default(),
+        TableFactor::Derived {
+            alias: Some(alias), ..
+        } => vec![alias.name.value.clone()],
+        _ => Vec::new(),
+    }
+}
+
+#[derive(Default)]
+struct AliasCollector {
+    aliases: Vec<(String, String)>,
+}
+
+impl Visitor for AliasCollector {
+    type Break = ();
+
+    fn pre_visit_table_factor(
+        &mut self,
+        factor: &sqlparser::ast::TableFactor,
+    ) -> ControlFlow<Self::Break> {
+        if let sqlparser::ast::TableFactor::Table {
+            name,
+            alias: Some(alias),
+            ..
+        } = factor
+        {
+            self.aliases
+                .push((table_name_to_string(name), alias.name.value.clone()));
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{SQLDB, SqliteDb, TransactionMode, with_transaction};
+    use sqlparser::{dialect::PostgreSqlDialect, parser::Parser};
+
+    fn analyze(db: &SqliteDb, sql: &str, limits: &PlanLimits) -> QueryPlan {
+        let statement = Parser::parse_sql(&PostgreSqlDialect {}, sql)
+            .unwrap()
+            .remove(0);
+        with_transaction(db as &dyn SQLDB, TransactionMode::Rollback, |tx| {
+            QueryPlan::analyze(tx, sql, &SqlParams::default(), &statement, limits)
+                .map_err(crate::error::ProxyError::db)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn finds_full_scans_cartesian_joins_and_missing_indexes() {
+        let db = SqliteDb::new(":memory:").unwrap();
+        for sql in [
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, tenant_id TEXT, email TEXT)",
+            "CREATE TABLE orders (id INTEGER PRIMARY KEY, user_id INTEGER, tenant_id TEXT)",
+            "CREATE INDEX orders_tenant ON orders (tenant_id)",
+        ] {
+            db.execute(sql).unwrap();
+        }
+        db.execute(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 50) \
+             INSERT INTO users SELECT i, 'acme', 'u' || i FROM n",
+        )
+        .unwrap();
+        db.execute("INSERT INTO orders VALUES (1, 1, 'acme'), (2, 2, 'acme')")
+            .unwrap();
+        let limits = PlanLimits {
+            large_table_rows: 10,
+            max_full_scans: Some(0),
+            ..PlanLimits::default()
+        };
+
+        let scan = analyze(&db, "SELECT id FROM users WHERE email = 'u1'", &limits);
+        let kinds: Vec<_> = scan.findings.iter().map(|finding| finding.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![PlanFindingKind::FullScan, PlanFindingKind::MissingIndex]
+        );
+        assert_eq!(scan.estimated_cost, 11);
+        let error = scan.check(&limits).unwrap_err();
+        assert_eq!(error.code, ErrorCode::LimitExceeded);
+        assert_eq!(error.rule.unwrap().source, "limits.plan.max_full_scans");
+
+        let indexed = analyze(
+            &db,
+            "SELECT id FROM orders WHERE tenant_id = 'acme'",
+            &limits,
+        );
+        assert!(indexed.findings.is_empty());
+        assert!(indexed.check(&limits).is_ok());
+
+        let cartesian = analyze(&db, "SELECT u.id FROM users u, orders o", &limits);
+        assert!(
+            cartesian
+                .findings
+                .iter()
+                .any(|finding| finding.kind == PlanFindingKind::CartesianJoin)
+        );
+        assert!(cartesian.estimated_cost > 11);
+
+        let is_cartesian = |sql: &str| {
+            cartesian_join(
+                &Parser::parse_sql(&PostgreSqlDialect {}, sql)
+                    .unwrap()
+                    .remove(0),
+            )
+        };
+        assert!(is_cartesian(
+            "SELECT u.id FROM users u, orders o WHERE u.tenant_id = 'acme'"
+        ));
+        assert!(!is_cartesian(
+            "SELECT u.id FROM users u, orders o WHERE u.id = o.user_id AND u.tenant_id = 'acme'"
+        ));
+        assert!(!is_cartesian(
+            "SELECT users.id FROM users, orders WHERE orders.user_id = users.id"
+        ));
+    }
+}
@@ -7,6 +7,138 @@ pub struct PolicyConfig {
     pub roles: HashMap<String, HashMap<String, TablePolicy>>,
     #[serde(default)]
     pub tables: HashMap<String, TablePolicy>,
+    #[serde(default)]
+    pub limits: QueryLimits,
+    #[serde(default)]
+    pub rate_limits: Vec<RateLimitRule>,
+    /// Roles that may revert commits made by another actor of the same tenant.
+    /// Without one, only the actor that committed a query can revert it.
+    #[serde(default)]
+    pub revert_roles: Vec<String>,
+}
+
+/// Global ceilings applied to SELECT results and previewed changes.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct QueryLimits {
+    /// LIMIT injected into SELECTs on tables without their own `select_limit`.
+    #[serde(default)]
+    pub select_limit: Option<u64>,
+    /// Maximum size of the serialized result rows returned to the caller.
+    #[serde(default = "default_max_result_bytes")]
+    pub max_result_bytes: usize,
+    /// Rows affected by a previewed UPDATE or DELETE that may change before
+    /// commit without failing it as `stale_preview`.
+    #[serde(default)]
+    pub stale_row_tolerance: u64,
+    /// Lets commits that write run without a `preview_id`. Such commits skip
+    /// drift detection and the preview's expiry and single-use checks.
+    #[serde(default)]
+    pub allow_unpreviewed_writes: bool,
+    #[serde(default)]
+    pub plan: PlanLimits,
+}
+
+impl Default for QueryLimits {
+    fn default() -> Self {
+        Self {
+            select_limit: None,
+            max_result_bytes: default_max_result_bytes(),
+            stale_row_tolerance: 0,
+            allow_unpreviewed_writes: false,
+            plan: PlanLimits::default(),
+        }
+    }
+}
+
+fn default_max_result_bytes() -> usize {
+    256 * 1024
+}
+
+/// Thresholds checked against the query plan of every previewed statement.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PlanLimits {
+    /// Tables with at least this many rows count as large; only scans of large
+    /// tables are reported.
+    #[serde(default = "default_large_table_rows")]
+    pub large_table_rows: u64,
+    /// Full scans of large tables a statement may do.
+    #[serde(default)]
+    pub max_full_scans: Option<usize>,
+    /// Ceiling on the estimated rows read, see `QueryPlan::estimated_cost`.
+    #[serde(default)]
+    pub max_estimated_cost: Option<u64>,
+    #[serde(default)]
+    pub deny_cartesian_joins: bool,
+    #[serde(default)]
+    pub deny_missing_index: bool,
+}
+
+impl Default for PlanLimits {
+    fn default() -> Self {
+        Self {
+            large_table_rows: default_large_table_rows(),
+            max_full_scans: None,
+            max_estimated_cost: None,
+            deny_cartesian_joins: false,
+            deny_missing_index: false,
+        }
+    }
+}
+
+impl PlanLimits {
+    /// Whether any threshold is configured; tables are only counted and plans
+    /// only checked then.
+    pub fn is_set(&self) -> bool {
+        self.max_full_scans.is_some()
+            || self.max_estimated_cost.is_some()
+            || self.deny_cartesian_joins
+            || self.deny_missing_index
+    }
+}
+
+fn default_large_table_rows() -> u64 {
+    10_000
+}
+
+/// Token-bucket limits for the actors, roles or tenants matching `pattern`.
+/// Each limit is the bucket size, refilled evenly over `window_secs`; every
+/// matching key gets its own buckets.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RateLimitRule {
+    pub scope: RateScope,
+    /// Glob over the actor, role or tenant id, such as `agent:*`; all when unset.
+    #[serde(default)]
+    pub pattern: Option<String>,
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    #[serde(default)]
+    pub previews: Option<u64>,
+    #[serde(default)]
+    pub commits: Option<u64>,
+    #[serde(default)]
+    pub rows_written: Option<u64>,
+}
+
+fn default_window_secs() -> u64 {
+    60
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RateScope {
+    Actor,
+    Role,
+    Tenant,
+}
+
+impl RateScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RateScope::Actor => "actor",
+            RateScope::Role => "role",
+            RateScope::Tenant => "tenant",
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -19,18 +151,100 @@ pub struct TablePolicy {
     pub deny_columns: Vec<String>,
     #[serde(default)]
     pub required_expressions: Vec<String>,
+    #[serde(default)]
+    pub writable_columns: Vec<String>,
+    #[serde(default)]
+    pub immutable_columns: Vec<String>,
+    #[serde(default)]
+    pub value_constraints: Vec<ValueConstraint>,
+    #[serde(default)]
+    pub max_rows_affected: Option<u64>,
+    #[serde(default)]
+    pub max_rows_returned: Option<u64>,
+    #[serde(default)]
+    pub select_limit: Option<u64>,
+    #[serde(default)]
+    pub mask_columns: Vec<ColumnMask>,
+    /// SQL predicate injected into every read, UPDATE and DELETE of the table.
+    /// `{{context.actor}}`, `{{context.tenant_id}}` and `{{context.role}}`
+    /// placeholders are replaced with quoted values.
+    #[serde(default)]
+    pub row_filter: Option<String>,
+    #[serde(default)]
+    pub conditions: Vec<PolicyCondition>,
 }
 
 impl PolicyConfig {
     pub fn table_policy_for(&self, role: &str, table: &str) -> Option<&TablePolicy> {
-        if !role.is_empty() {
-            if let Some(role_tables) = self.roles.get(role) {
-                if let Some(table_policy) = role_tables.get(table) {
-                    return Some(table_policy);
-                }
-            }
+        self.resolve_table_policy(role, table)
+            .map(|(_, table_policy)| table_policy)
+    }
+
+    /// The rules for `table` and whether they came from the role section or
+    /// the global `tables` fallback.
+    pub fn resolve_table_policy(
+        &self,
+        role: &str,
+        table: &str,
+    ) -> Option<(RuleScope, &TablePolicy)> {
+        if !role.is_empty()
+            && let Some(role_tables) = self.roles.get(role)
+            && let Some(table_policy) = role_tables.get(table)
+        {
+            return Some((RuleScope::Role, table_policy));
+        }
+        self.tables
+            .get(table)
+            .map(|table_policy| (RuleScope::Global, table_policy))
+    }
+
+    /// Column masks that apply to `role`, keyed by table name.
+    pub fn masks_for_role(&self, role: &str) -> HashMap<String, Vec<ColumnMask>> {
+        self.table_policies_for_role(role)
+            .filter(|(_, table_policy)| !table_policy.mask_columns.is_empty())
+            .map(|(table, table_policy)| (table.to_string(), table_policy.mask_columns.clone()))
+            .collect()
+    }
+
+    /// Row filter templates that apply to `role`, keyed by table name.
+    pub fn row_filters_for_role(&self, role: &str) -> HashMap<String, String> {
+        self.table_policies_for_role(role)
+            .filter_map(|(table, table_policy)| {
+                Some((table.to_string(), table_policy.row_filter.clone()?))
+            })
+            .collect()
+    }
+
+    fn table_policies_for_role<'a>(
+        &'a self,
+        role: &'a str,
+    ) -> impl Iterator<Item = (&'a str, &'a TablePolicy)> + 'a {
+        let role_tables = self.roles.get(role).into_iter().flat_map(HashMap::keys);
+        self.tables
+            .keys()
+            .chain(role_tables)
+            .filter_map(move |table| Some((table.as_str(), self.table_policy_for(role, table)?)))
+    }
+}
+
+/// Where a rule comes from: built into the engine, a `roles` section or the
+/// global `tables` section of the policy file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleScope {
+    Builtin,
+    Role,
+    Global,
+}
+
+impl RuleScope {
+    /// Path of a table's section in the policy file.
+    pub fn section(&self, role: &str, table: &str) -> String {
+        match self {
+            RuleScope::Builtin => "builtin".to_string(),
+            RuleScope::Role => format!("roles.{role}.{table}"),
+            RuleScope::Global => format!("tables.{table}"),
         }
-        self.tables.get(table)
     }
 }
 
@@ -45,6 +259,140 @@ fn default_operator() -> String {
     "=".to_string()
 }
 
+/// Replaces a column in query results instead of rejecting queries that read it.
+/// `value` is the replacement for `mask: constant`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ColumnMask {
+    pub column: String,
+    #[serde(default)]
+    pub mask: MaskKind,
+    #[serde(default)]
+    pub value: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MaskKind {
+    #[default]
+    Null,
+    Constant,
+    Hash,
+    Last4,
+    PartialEmail,
+}
+
+impl MaskKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MaskKind::Null => "null",
+            MaskKind::Constant => "constant",
+            MaskKind::Hash => "hash",
+            MaskKind::Last4 => "last4",
+            MaskKind::PartialEmail => "partial_email",
+        }
+    }
+}
+
+/// Constraint on literal values written to a column by INSERT or UPDATE.
+/// `equals_context` names a trusted context field: `actor`, `tenant_id` or
+/// `role`. INSERTs must write the column unless `allow_default` is set.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ValueConstraint {
+    pub column: String,
+    #[serde(default)]
+    pub min: Option<f64>,
+    #[serde(default)]
+    pub max: Option<f64>,
+    #[serde(default, rename = "in")]
+    pub one_of: Vec<serde_json::Value>,
+    #[serde(default)]
+    pub equals_context: Option<String>,
+    #[serde(default)]
+    pub pattern: Option<PolicyPattern>,
+    /// Lets INSERTs leave the column out so it takes its database default.
+    #[serde(default)]
+    pub allow_default: bool,
+}
+
+/// A regex compiled when the policy is parsed, so a bad pattern fails the
+/// load instead of the first write it applies to.
+#[derive(Clone, Debug)]
+pub struct PolicyPattern(regex::Regex);
+
+impl PolicyPattern {
+    pub fn is_match(&self, text: &str) -> bool {
+        self.0.is_match(text)
+    }
+}
+
+impl Serialize for PolicyPattern {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for PolicyPattern {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        regex::Regex::new(&pattern)
+            .map(Self)
+            .map_err(|err| serde::de::Error::custom(format!("invalid pattern '{pattern}': {err}")))
+    }
+}
+
+/// A rule over request context attributes, scoped to a set of operations.
+///
+/// With `effect: require` every matcher must hold for the operation to be
+/// allowed; with `effect: deny` the operation is rejected when they all hold.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PolicyCondition {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub ops: Vec<String>,
+    #[serde(default)]
+    pub effect: ConditionEffect,
+    #[serde(default)]
+    pub when: Vec<AttributeMatch>,
+    #[serde(default)]
+    pub during: Option<TimeWindow>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConditionEffect {
+    #[default]
+    Require,
+    Deny,
+}
+
+/// Matches a single context attribute. `attribute` is one of `actor`,
+/// `tenant_id`, `role`, `client_ip` or a key of `context.attributes`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AttributeMatch {
+    pub attribute: String,
+    #[serde(default)]
+    pub equals: Option<serde_json::Value>,
+    #[serde(default, rename = "in")]
+    pub one_of: Vec<serde_json::Value>,
+    #[serde(default)]
+    pub pattern: Option<String>,
+}
+
+/// Wall-clock window such as business hours. Times are `HH:MM`, days are
+/// short or long weekday names; an empty list means every day.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TimeWindow {
+    #[serde(default)]
+    pub days: Vec<String>,
+    #[serde(default)]
+    pub start: Option<String>,
+    #[serde(default)]
+    pub end: Option<String>,
+    #[serde(default)]
+    pub utc_offset_minutes: i32,
+}
+
 #[derive(Debug)]
 pub enum PolicyError {
     ReadFailed(String),
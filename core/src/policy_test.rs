@@ -0,0 +1,155 @@
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+use crate::{
+    db::SqlParams,
+    error::{ErrorCode, ProxyResult},
+    policy::PolicyConfig,
+    query_engine::{QueryContext, QueryEngine, SqlRequest},
+};
+
+/// Declarative test cases for a policy file, usually kept next to it as
+/// `policy.tests.yaml`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PolicyTestSuite {
+    pub cases: Vec<PolicyTestCase>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PolicyTestCase {
+    pub name: String,
+    pub sql: String,
+    pub context: QueryContext,
+    #[serde(default)]
+    pub params: SqlParams,
+    pub expect: Expectation,
+    /// Substring the denial message must contain.
+    #[serde(default)]
+    pub error: Option<String>,
+    /// Error code the denial must carry, such as `tenant_violation`.
+    #[serde(default)]
+    pub code: Option<ErrorCode>,
+    /// Exact SQL an allowed query must be rewritten to.
+    #[serde(default)]
+    pub rewritten_sql: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Expectation {
+    Allow,
+    Deny,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PolicyTestResult {
+    pub name: String,
+    pub passed: bool,
+    /// Why the case failed; empty when it passed.
+    pub message: String,
+}
+
+pub fn load_suite(path: impl AsRef<Path>) -> Result<PolicyTestSuite, String> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read {}: {err}", path.display()))?;
+    serde_yaml::from_str(&contents).map_err(|err| format!("Invalid test suite: {err}"))
+}
+
+/// Runs every case through parsing, the global rules and the policy checks,
+/// and for expected rewrites through the policy rewrites without a schema.
+pub fn run_suite(suite: &PolicyTestSuite, policy: &PolicyConfig) -> Vec<PolicyTestResult> {
+    suite
+        .cases
+        .iter()
+        .map(|case| {
+            let outcome = evaluate(case, policy);
+            let failure = match (case.expect, &outcome) {
+                (Expectation::Allow, Err(error)) => {
+                    Some(format!("expected allow, denied: {error}"))
+                }
+                (Expectation::Allow, Ok(rewritten)) => case
+                    .rewritten_sql
+                    .as_ref()
+                    .filter(|expected| *expected != rewritten)
+                    .map(|expected| format!("expected rewrite `{expected}`, got `{rewritten}`")),
+                (Expectation::Deny, Ok(_)) => Some("expected deny, query was allowed".to_string()),
+                (Expectation::Deny, Err(error)) => case
+                    .error
+                    .as_ref()
+                    .filter(|expected| !error.message.contains(expected.as_str()))
+                    .map(|expected| format!("expected error containing '{expected}', got: {error}"))
+                    .or_else(|| {
+                        case.code
+                            .filter(|expected| *expected != error.code)
+                            .map(|expected| {
+                                format!(
+                                    "expected code {}, got {}: {error}",
+                                    expected.as_str(),
+                                    error.code.as_str()
+                                )
+                            })
+                    }),
+            };
+
+            PolicyTestResult {
+                name: case.name.clone(),
+                passed: failure.is_none(),
+                message: failure.unwrap_or_default(),
+            }
+        })
+        .collect()
+}
+
+fn evaluate(case: &PolicyTestCase, policy: &PolicyConfig) -> ProxyResult<String> {
+    let engine = QueryEngine;
+    let payload = SqlRequest {
+        sql: case.sql.clone(),
+        context: case.context.clone(),
+        params: case.params.clone(),
+        preview_id: None,
+        idempotency_key: None,
+        explain: false,
+    };
+    let (parsed, _) = engine.evaluate_sql(&payload)?;
+    engine.enforce_rules(&payload, &parsed)?;
+    engine.enforce_policy(&payload, &parsed, policy)?;
+    Ok(engine.rewrite(&payload, &parsed, policy, None)?.sql)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_failing_cases() {
+        let policy: PolicyConfig =
+            serde_yaml::from_str("tables:\n  orders:\n    allow_ops: [select]\n").unwrap();
+        let suite: PolicyTestSuite = serde_yaml::from_str(
+            r#"
+cases:
+  - name: reads are allowed
+    sql: SELECT id FROM orders WHERE tenant_id = 'acme'
+    context: { actor: agent, tenant_id: acme }
+    expect: allow
+    rewritten_sql: SELECT id FROM orders WHERE tenant_id = 'acme'
+  - name: deletes are denied
+    sql: DELETE FROM orders WHERE tenant_id = 'acme'
+    context: { actor: agent, tenant_id: acme }
+    expect: deny
+    error: not allowed
+    code: policy_denied
+  - name: wrong expectation
+    sql: DELETE FROM orders WHERE tenant_id = 'acme'
+    context: { actor: agent, tenant_id: acme }
+    expect: allow
+"#,
+        )
+        .unwrap();
+
+        let results = run_suite(&suite, &policy);
+        assert!(results[0].passed, "{}", results[0].message);
+        assert!(results[1].passed, "{}", results[1].message);
+        assert!(!results[2].passed);
+    }
+}
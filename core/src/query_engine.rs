@@ -1,11 +1,30 @@
+use chrono::{DateTime, Datelike, FixedOffset, NaiveTime, Utc, Weekday};
 use serde::{Deserialize, Serialize};
 use sqlparser::{
-    ast::{ObjectName, Statement, TableFactor},
+    ast::{
+        Assignment, BinaryOperator, Expr, ObjectName, OnConflict, OnConflictAction, OnInsert,
+        SetExpr, Statement, TableFactor, UnaryOperator, Value, visit_expressions,
+        visit_expressions_mut,
+    },
     dialect::PostgreSqlDialect,
     parser::Parser,
 };
 
-use crate::policy::{PolicyConfig, RequiredFilter, TablePolicy};
+use std::{collections::HashMap, ops::ControlFlow};
+
+use crate::db::{SchemaSnapshot, SqlParams, placeholder_name};
+use crate::error::{ErrorCode, ProxyError, ProxyResult, Remediation, RuleRef};
+use crate::plan::QueryPlan;
+use crate::policy::{
+    AttributeMatch, ConditionEffect, PolicyCondition, PolicyConfig, RequiredFilter, RuleScope,
+    TablePolicy, TimeWindow, ValueConstraint,
+};
+use crate::remediation;
+use crate::rewrite::{
+    affected_rows_query, apply_masks, apply_row_filters, apply_select_limit, capture_query,
+    parse_expr,
+};
+use crate::trace::{DecisionTrace, TraceOutcome, TraceStep};
 
 #[derive(Clone, Debug, Deserialize, Serialize, schemars::JsonSchema)]
 pub struct QueryContext {
@@ -13,12 +32,138 @@ pub struct QueryContext {
     pub tenant_id: String,
     #[serde(default)]
     pub role: String,
+    /// Set by the HTTP service from the peer address; a value sent by the
+    /// caller is discarded.
+    #[serde(default)]
+    pub client_ip: Option<String>,
+    #[serde(default)]
+    pub attributes: HashMap<String, serde_json::Value>,
+}
+
+impl QueryContext {
+    pub fn attribute(&self, name: &str) -> Option<serde_json::Value> {
+        match name {
+            "actor" => Some(self.actor.clone().into()),
+            "tenant_id" => Some(self.tenant_id.clone().into()),
+            "role" => Some(self.role.clone().into()),
+            "client_ip" => self.client_ip.clone().map(Into::into),
+            _ => {
+                let key = name.strip_prefix("attributes.").unwrap_or(name);
+                self.attributes.get(key).cloned()
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, schemars::JsonSchema)]
 pub struct SqlRequest {
     pub sql: String,
     pub context: QueryContext,
+    #[serde(default)]
+    pub params: SqlParams,
+    /// Preview this commit was checked with; its affected rows must not have
+    /// changed since.
+    #[serde(default)]
+    pub preview_id: Option<String>,
+    /// Commits repeated with the same key by the same actor return the first
+    /// response instead of executing again.
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// Include the decision trace in the preview response.
+    #[serde(default)]
+    pub explain: bool,
+}
+
+/// An ordered list of statements that are previewed and committed together.
+#[derive(Clone, Debug, Deserialize, Serialize, schemars::JsonSchema)]
+pub struct ChangesetRequest {
+    pub statements: Vec<ChangesetStatement>,
+    pub context: QueryContext,
+    #[serde(default)]
+    pub preview_id: Option<String>,
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    #[serde(default)]
+    pub explain: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, schemars::JsonSchema)]
+pub struct ChangesetStatement {
+    pub sql: String,
+    #[serde(default)]
+    pub params: SqlParams,
+}
+
+impl ChangesetRequest {
+    /// Wraps a single request as a one-statement changeset.
+    pub fn from_request(payload: &SqlRequest) -> Self {
+        Self {
+            statements: vec![ChangesetStatement {
+                sql: payload.sql.clone(),
+                params: payload.params.clone(),
+            }],
+            context: payload.context.clone(),
+            preview_id: payload.preview_id.clone(),
+            idempotency_key: payload.idempotency_key.clone(),
+            explain: payload.explain,
+        }
+    }
+
+    /// Splits the changeset into one request per statement sharing the context.
+    pub fn to_requests(&self) -> Vec<SqlRequest> {
+        self.statements
+            .iter()
+            .map(|statement| SqlRequest {
+                sql: statement.sql.clone(),
+                context: self.context.clone(),
+                params: statement.params.clone(),
+                preview_id: None,
+                idempotency_key: None,
+                explain: self.explain,
+            })
+            .collect()
+    }
+}
+
+/// Request to revert a committed query. Without `preview_id` the inverse
+/// changeset is previewed; with the `preview_id` of that preview it is committed.
+#[derive(Clone, Debug, Deserialize, Serialize, schemars::JsonSchema)]
+pub struct RevertRequest {
+    pub context: QueryContext,
+    #[serde(default)]
+    pub preview_id: Option<String>,
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+}
+
+/// Explains a stored query by `id`, or evaluates `request` without running it.
+/// Only queries that passed the policy are stored, so denials are explained by
+/// sending the `request` again.
+#[derive(Clone, Debug, Deserialize, Serialize, schemars::JsonSchema)]
+pub struct ExplainRequest {
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Tenant of the caller, required with `id`; queries of other tenants are
+    /// not explained.
+    #[serde(default)]
+    pub tenant_id: Option<String>,
+    #[serde(default)]
+    pub request: Option<SqlRequest>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ExplainResponse {
+    pub ok: bool,
+    pub allowed: bool,
+    pub traces: Vec<DecisionTrace>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, schemars::JsonSchema)]
+pub struct ChangesetPreviewResponse {
+    pub ok: bool,
+    pub preview_id: String,
+    pub rows_affected: u64,
+    pub statements: Vec<PreviewResponse>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, schemars::JsonSchema)]
@@ -30,9 +175,22 @@ pub struct PreviewResponse {
     pub rows_affected: u64,
     pub rewritten_sql: String,
     pub warnings: Vec<String>,
+    /// Result rows for SELECT statements, capped by `limits.max_result_bytes`.
+    #[serde(default)]
+    pub rows: Vec<serde_json::Value>,
+    /// Whether `rows` stopped at `limits.max_result_bytes`. Reading stops there
+    /// too, so `rows_affected` then counts only the rows read.
+    #[serde(default)]
+    pub truncated: bool,
+    /// Rules checked for the statement, when the request set `explain`.
+    #[serde(default)]
+    pub trace: Option<DecisionTrace>,
+    /// The database's query plan, when a database is configured.
+    #[serde(default)]
+    pub plan: Option<QueryPlan>,
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct CommitResponse {
     pub ok: bool,
     pub preview_id: String,
@@ -44,6 +202,48 @@ pub struct CommitResponse {
 pub struct ErrorResponse {
     pub ok: bool,
     pub error: String,
+    pub code: ErrorCode,
+    pub rule: Option<Box<RuleRef>>,
+    pub suggestion: Option<String>,
+    pub statement: Option<usize>,
+    pub remediation: Option<Box<Remediation>>,
+    pub retry_after: Option<u64>,
+}
+
+impl From<ProxyError> for ErrorResponse {
+    fn from(error: ProxyError) -> Self {
+        Self {
+            ok: false,
+            error: error.message,
+            code: error.code,
+            rule: error.rule,
+            suggestion: error.suggestion,
+            statement: error.statement,
+            remediation: error.remediation,
+            retry_after: error.retry_after,
+        }
+    }
+}
+
+/// Lifecycle of a stored preview. Everything but `previewed` is terminal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryStatus {
+    Previewed,
+    Committed,
+    Failed,
+    Expired,
+}
+
+impl QueryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueryStatus::Previewed => "previewed",
+            QueryStatus::Committed => "committed",
+            QueryStatus::Failed => "failed",
+            QueryStatus::Expired => "expired",
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -51,11 +251,80 @@ pub struct QueryRecord {
     pub id: String,
     pub actor: String,
     pub tenant_id: String,
+    pub role: String,
     pub sql: String,
-    pub status: String,
+    pub status: QueryStatus,
     pub created_at: chrono::DateTime<chrono::Utc>,
+    /// When a `previewed` record stops being committable.
+    pub expires_at: chrono::DateTime<chrono::Utc>,
     pub operation: String,
     pub tables: Vec<String>,
+    #[serde(default)]
+    pub params: SqlParams,
+    /// Rewritten statements when the record is a changeset.
+    #[serde(default)]
+    pub statements: Vec<ChangesetStatement>,
+    /// Query this record reverts, when it was generated by a revert.
+    pub reverts: Option<String>,
+    /// Committed revert that undid this query.
+    pub reverted_by: Option<String>,
+    /// Decision trace of each statement, recorded when it was checked.
+    #[serde(default)]
+    pub trace: Vec<DecisionTrace>,
+}
+
+/// Tenant a stored query is fetched for; records of other tenants are not found.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, schemars::JsonSchema)]
+pub struct QueryGetRequest {
+    #[serde(default)]
+    pub tenant_id: Option<String>,
+}
+
+/// Filters for listing stored queries. Every filter is optional; `table`
+/// matches any table the query touches and `since`/`until` bound `created_at`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, schemars::JsonSchema)]
+pub struct QueryListRequest {
+    #[serde(default)]
+    pub actor: Option<String>,
+    #[serde(default)]
+    pub tenant_id: Option<String>,
+    #[serde(default)]
+    pub role: Option<String>,
+    #[serde(default)]
+    pub status: Option<QueryStatus>,
+    #[serde(default)]
+    pub operation: Option<String>,
+    #[serde(default)]
+    pub table: Option<String>,
+    #[serde(default)]
+    pub since: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub until: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub sort: SortOrder,
+    /// Page size, 50 by default and at most 500.
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// `next_cursor` of the previous page.
+    #[serde(default)]
+    pub cursor: Option<String>,
+}
+
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, schemars::JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Newest,
+    Oldest,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct QueryListResponse {
+    pub ok: bool,
+    pub queries: Vec<QueryRecord>,
+    pub next_cursor: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -63,105 +332,871 @@ pub struct ParsedQuery {
     pub operation: String,
     pub tables: Vec<String>,
     pub has_where: bool,
+    /// Columns named in the INSERT column list or UPDATE SET list.
+    pub written_columns: Vec<String>,
+    /// Values written per column, one entry per INSERT row or SET assignment.
+    pub written_values: Vec<ColumnWrite>,
+    /// Columns changed on existing rows, by an UPDATE or by the `ON CONFLICT
+    /// DO UPDATE` clause of an INSERT.
+    pub updated_columns: Vec<String>,
+    /// False for an INSERT without a column list, whose values cannot be
+    /// matched to columns.
+    pub lists_columns: bool,
+    pub statement: Statement,
+}
+
+/// SQL produced by the policy rewrites, with a note for each change made.
+#[derive(Clone, Debug)]
+pub struct RewrittenQuery {
+    pub sql: String,
+    pub warnings: Vec<String>,
+    /// SELECT over the rows an UPDATE or DELETE will touch, used to detect drift
+    /// between preview and commit.
+    pub affected_rows: Option<AffectedRowsQuery>,
+    /// The write with `RETURNING *`, used to capture changed rows for reverts.
+    pub capture: Option<AffectedRowsQuery>,
+}
+
+#[derive(Clone, Debug)]
+pub struct AffectedRowsQuery {
+    pub table: String,
+    pub sql: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct ColumnWrite {
+    pub column: String,
+    pub value: WrittenValue,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum WrittenValue {
+    Literal(serde_json::Value),
+    /// A normalized bind placeholder such as `?1` or `:name`.
+    Parameter(String),
+    Expression(String),
+}
+
+/// The tightest row limit that applies to a query and the table it came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RowLimit {
+    pub table: String,
+    pub max_rows: u64,
+    /// Path of the limit in the policy file.
+    pub source: String,
+}
+
+impl RowLimit {
+    pub fn check(&self, operation: &str, rows: u64) -> ProxyResult<()> {
+        if rows <= self.max_rows {
+            return Ok(());
+        }
+        let (verb, rule) = if operation == "select" {
+            ("return", "max_rows_returned")
+        } else {
+            ("affect", "max_rows_affected")
+        };
+        let mut error = ProxyError::new(
+            ErrorCode::LimitExceeded,
+            format!(
+                "Query would {verb} {rows} rows, exceeding the limit of {} for table '{}'",
+                self.max_rows, self.table
+            ),
+        )
+        .with_suggestion("Narrow the WHERE clause or split the change into smaller batches");
+        error.rule = Some(Box::new(RuleRef {
+            rule: rule.to_string(),
+            source: self.source.clone(),
+            table: Some(self.table.clone()),
+        }));
+        Err(error)
+    }
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct QueryEngine;
 
 impl QueryEngine {
-    pub fn evaluate_sql(&self, payload: &SqlRequest) -> Result<(ParsedQuery, String), String> {
+    #[tracing::instrument(name = "query_engine.parse", level = "debug", skip_all)]
+    pub fn evaluate_sql(&self, payload: &SqlRequest) -> ProxyResult<(ParsedQuery, String)> {
         let dialect = PostgreSqlDialect {};
-        let mut statements = Parser::parse_sql(&dialect, &payload.sql)
-            .map_err(|err| format!("SQL parse error: {err}"))?;
+        let mut statements = Parser::parse_sql(&dialect, &payload.sql).map_err(|err| {
+            ProxyError::new(ErrorCode::ParseError, format!("SQL parse error: {err}"))
+        })?;
 
         if statements.len() != 1 {
-            return Err("Only single-statement SQL is supported".to_string());
+            return Err(ProxyError::new(
+                ErrorCode::UnsupportedStatement,
+                "Only single-statement SQL is supported",
+            )
+            .with_suggestion("Send one statement per request, or use a changeset"));
         }
 
-        let statement = statements
+        let mut statement = statements
             .pop()
-            .ok_or_else(|| "Missing SQL statement".to_string())?;
+            .ok_or_else(|| ProxyError::new(ErrorCode::ParseError, "Missing SQL statement"))?;
+        bind_placeholders(&mut statement, &payload.params).map_err(ProxyError::invalid)?;
 
-        let (operation, tables, has_where) = analyze_statement(statement.clone())?;
+        let parsed = analyze_statement(statement.clone())?;
         let rewritten_sql = statement.to_string();
 
-        Ok((
-            ParsedQuery {
-                operation,
-                tables,
-                has_where,
-            },
-            rewritten_sql,
-        ))
+        Ok((parsed, rewritten_sql))
+    }
+
+    pub fn enforce_rules(&self, payload: &SqlRequest, parsed: &ParsedQuery) -> ProxyResult<()> {
+        let mut trace = DecisionTrace::new(&payload.context.role);
+        self.trace_rules(payload, parsed, &mut trace);
+        trace.result()
     }
 
-    pub fn enforce_rules(&self, payload: &SqlRequest, parsed: &ParsedQuery) -> Result<(), String> {
-        if (parsed.operation == "update" || parsed.operation == "delete") && !parsed.has_where {
-            return Err("UPDATE/DELETE requires a WHERE clause".to_string());
+    pub fn enforce_policy(
+        &self,
+        payload: &SqlRequest,
+        parsed: &ParsedQuery,
+        policy: &PolicyConfig,
+    ) -> ProxyResult<()> {
+        let mut trace = DecisionTrace::new(&payload.context.role);
+        self.trace_policy(payload, parsed, policy, &mut trace);
+        trace.result()
+    }
+
+    /// Parses the request and checks it against the built-in and policy rules,
+    /// returning the trace of every rule checked.
+    #[tracing::instrument(name = "query_engine.authorize", level = "debug", skip_all)]
+    pub fn authorize(
+        &self,
+        payload: &SqlRequest,
+        policy: &PolicyConfig,
+    ) -> ProxyResult<(ParsedQuery, DecisionTrace)> {
+        let (parsed, _) = self.evaluate_sql(payload)?;
+        let trace = self.trace(payload, &parsed, policy);
+        trace.result()?;
+        Ok((parsed, trace))
+    }
+
+    /// Like [`QueryEngine::authorize`], but returns the trace for denied
+    /// requests too. Rewrites and row limits are not part of the trace.
+    pub fn explain(&self, payload: &SqlRequest, policy: &PolicyConfig) -> DecisionTrace {
+        match self.evaluate_sql(payload) {
+            Ok((parsed, _)) => self.trace(payload, &parsed, policy),
+            Err(error) => {
+                let mut trace = DecisionTrace::new(&payload.context.role);
+                trace.record(
+                    "parse",
+                    "builtin",
+                    RuleScope::Builtin,
+                    None,
+                    Err(error.message),
+                );
+                trace
+            }
         }
+    }
 
-        if !parsed.tables.is_empty()
-            && !payload.sql.contains("tenant_id")
-            && payload.context.tenant_id != "*"
-        {
-            return Err("Tenant filter missing; tenant_id must be enforced".to_string());
+    /// Attaches a remediation to a denial: the predicate, columns or
+    /// operations that would satisfy the failed rule, and a rewritten SQL when
+    /// one passes every rule.
+    #[tracing::instrument(name = "query_engine.remediate", level = "debug", skip_all)]
+    pub fn remediate(
+        &self,
+        payload: &SqlRequest,
+        policy: &PolicyConfig,
+        schema: Option<&SchemaSnapshot>,
+        mut error: ProxyError,
+    ) -> ProxyError {
+        if let Some(remediation) = remediation::remediate(self, payload, policy, schema, &error) {
+            error.remediation = Some(Box::new(remediation));
         }
+        error
+    }
 
-        Ok(())
+    fn trace(
+        &self,
+        payload: &SqlRequest,
+        parsed: &ParsedQuery,
+        policy: &PolicyConfig,
+    ) -> DecisionTrace {
+        let mut trace = DecisionTrace::new(&payload.context.role);
+        trace.record("parse", "builtin", RuleScope::Builtin, None, Ok(()));
+        self.trace_rules(payload, parsed, &mut trace);
+        self.trace_policy(payload, parsed, policy, &mut trace);
+        trace
     }
 
-    pub fn enforce_policy(
+    /// Records the built-in guards that apply regardless of policy.
+    pub fn trace_rules(
+        &self,
+        payload: &SqlRequest,
+        parsed: &ParsedQuery,
+        trace: &mut DecisionTrace,
+    ) {
+        let mut builtin = |rule: &str, result: Result<(), String>| {
+            trace.record(rule, "builtin", RuleScope::Builtin, None, result)
+        };
+
+        if parsed.operation == "update" || parsed.operation == "delete" {
+            builtin(
+                "where_required",
+                if parsed.has_where {
+                    Ok(())
+                } else {
+                    Err("UPDATE/DELETE requires a WHERE clause".to_string())
+                },
+            );
+        }
+
+        if !parsed.updated_columns.is_empty() {
+            let changes_tenant = parsed
+                .updated_columns
+                .iter()
+                .any(|column| column.eq_ignore_ascii_case("tenant_id"));
+            builtin(
+                "tenant_id_immutable",
+                if changes_tenant {
+                    Err("UPDATE must not change tenant_id".to_string())
+                } else {
+                    Ok(())
+                },
+            );
+        }
+
+        if !parsed.tables.is_empty() {
+            let missing = !payload.sql.contains("tenant_id") && payload.context.tenant_id != "*";
+            builtin(
+                "tenant_filter",
+                if missing {
+                    Err("Tenant filter missing; tenant_id must be enforced".to_string())
+                } else {
+                    Ok(())
+                },
+            );
+        }
+    }
+
+    /// Records the table rules of every table the query touches, noting whether
+    /// they came from the role section or the global fallback.
+    pub fn trace_policy(
         &self,
         payload: &SqlRequest,
         parsed: &ParsedQuery,
         policy: &PolicyConfig,
-    ) -> Result<(), String> {
+        trace: &mut DecisionTrace,
+    ) {
         let role = payload.context.role.as_str();
         for table in &parsed.tables {
-            if let Some(table_policy) = policy.table_policy_for(role, table) {
-                self.validate_table_policy(payload, parsed, table_policy)?;
+            match policy.resolve_table_policy(role, table) {
+                Some((scope, table_policy)) => {
+                    let detail = match scope {
+                        RuleScope::Role => format!("Using role '{role}' rules"),
+                        _ if role.is_empty() => "Using global table rules".to_string(),
+                        _ => format!(
+                            "Role '{role}' has no rules for the table; using global table rules"
+                        ),
+                    };
+                    trace.push(TraceStep {
+                        rule: "table_policy".to_string(),
+                        source: scope.section(role, table),
+                        scope,
+                        table: Some(table.clone()),
+                        outcome: TraceOutcome::Passed,
+                        detail: Some(detail),
+                    });
+                    self.trace_table_policy(payload, parsed, table, table_policy, scope, trace);
+                }
+                None => trace.push(TraceStep {
+                    rule: "table_policy".to_string(),
+                    source: "builtin".to_string(),
+                    scope: RuleScope::Builtin,
+                    table: Some(table.clone()),
+                    outcome: TraceOutcome::NoPolicy,
+                    detail: Some("No policy for the table; only built-in rules apply".to_string()),
+                }),
             }
         }
+    }
 
-        Ok(())
+    /// Applies policy-driven rewrites to the parsed statement. `schema` is used to
+    /// expand `SELECT *` over masked tables.
+    #[tracing::instrument(name = "query_engine.rewrite", level = "debug", skip_all)]
+    pub fn rewrite(
+        &self,
+        payload: &SqlRequest,
+        parsed: &ParsedQuery,
+        policy: &PolicyConfig,
+        schema: Option<&SchemaSnapshot>,
+    ) -> ProxyResult<RewrittenQuery> {
+        self.apply_rewrites(payload, parsed, policy, schema)
+            .map_err(|message| ProxyError::new(ErrorCode::PolicyDenied, message))
     }
 
-    fn validate_table_policy(
+    fn apply_rewrites(
         &self,
         payload: &SqlRequest,
         parsed: &ParsedQuery,
+        policy: &PolicyConfig,
+        schema: Option<&SchemaSnapshot>,
+    ) -> Result<RewrittenQuery, String> {
+        let mut statement = parsed.statement.clone();
+        let mut warnings = Vec::new();
+
+        let mut row_filters = HashMap::new();
+        for (table, template) in policy.row_filters_for_role(&payload.context.role) {
+            let predicate = render_row_filter(&payload.context, &table, &template)?;
+            if parsed.operation == "insert"
+                && parsed
+                    .tables
+                    .iter()
+                    .any(|target| same_table(target, &table))
+            {
+                ensure_insert_matches_row_filter(payload, parsed, &table, &predicate)?;
+            }
+            row_filters.insert(table, predicate);
+        }
+        apply_row_filters(&mut statement, &row_filters, &mut warnings)?;
+
+        let masks = policy.masks_for_role(&payload.context.role);
+        apply_masks(&mut statement, &masks, schema, &mut warnings)?;
+
+        if let Statement::Query(query) = &mut statement
+            && let Some(ceiling) = self.select_limit(payload, parsed, policy)
+        {
+            apply_select_limit(query, ceiling, &mut warnings);
+        }
+
+        Ok(RewrittenQuery {
+            sql: statement.to_string(),
+            warnings,
+            affected_rows: affected_rows_query(&statement),
+            capture: capture_query(&statement),
+        })
+    }
+
+    fn select_limit(
+        &self,
+        payload: &SqlRequest,
+        parsed: &ParsedQuery,
+        policy: &PolicyConfig,
+    ) -> Option<u64> {
+        let role = payload.context.role.as_str();
+        parsed
+            .tables
+            .iter()
+            .filter_map(|table| {
+                policy
+                    .table_policy_for(role, table)
+                    .and_then(|table_policy| table_policy.select_limit)
+            })
+            .min()
+            .or(policy.limits.select_limit)
+    }
+
+    #[tracing::instrument(name = "query_engine.row_limit", level = "debug", skip_all)]
+    pub fn row_limit(
+        &self,
+        payload: &SqlRequest,
+        parsed: &ParsedQuery,
+        policy: &PolicyConfig,
+    ) -> Option<RowLimit> {
+        let role = payload.context.role.as_str();
+        parsed
+            .tables
+            .iter()
+            .filter_map(|table| {
+                let (scope, table_policy) = policy.resolve_table_policy(role, table)?;
+                let (rule, max_rows) = if parsed.operation == "select" {
+                    ("max_rows_returned", table_policy.max_rows_returned)
+                } else {
+                    ("max_rows_affected", table_policy.max_rows_affected)
+                };
+                Some(RowLimit {
+                    table: table.clone(),
+                    max_rows: max_rows?,
+                    source: format!("{}.{rule}", scope.section(role, table)),
+                })
+            })
+            .min_by_key(|limit| limit.max_rows)
+    }
+
+    fn trace_table_policy(
+        &self,
+        payload: &SqlRequest,
+        parsed: &ParsedQuery,
+        table: &str,
         table_policy: &TablePolicy,
-    ) -> Result<(), String> {
-        if !table_policy.allow_ops.is_empty()
-            && !table_policy
+        scope: RuleScope,
+        trace: &mut DecisionTrace,
+    ) {
+        let section = scope.section(&payload.context.role, table);
+        let mut check = |rule: &str, source: String, result: Result<(), String>| {
+            trace.record(rule, &source, scope, Some(table), result)
+        };
+
+        if !table_policy.allow_ops.is_empty() {
+            let allowed = table_policy
                 .allow_ops
                 .iter()
-                .any(|op| op == &parsed.operation)
-        {
+                .any(|op| op == &parsed.operation);
+            check(
+                "allow_ops",
+                format!("{section}.allow_ops"),
+                if allowed {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "Operation '{}' is not allowed for table '{table}'",
+                        parsed.operation
+                    ))
+                },
+            );
+        }
+
+        if parsed.operation == "insert" || parsed.operation == "update" {
+            if !table_policy.writable_columns.is_empty()
+                || !table_policy.immutable_columns.is_empty()
+            {
+                check(
+                    "writable_columns",
+                    format!("{section}.writable_columns"),
+                    ensure_writable_columns(parsed, table_policy),
+                );
+            }
+            for (index, constraint) in table_policy.value_constraints.iter().enumerate() {
+                check(
+                    "value_constraints",
+                    format!("{section}.value_constraints[{index}]"),
+                    ensure_value_constraint(payload, parsed, constraint),
+                );
+            }
+        }
+
+        for (index, required) in table_policy.required_filters.iter().enumerate() {
+            check(
+                "required_filters",
+                format!("{section}.required_filters[{index}]"),
+                ensure_required_filter(payload, required),
+            );
+        }
+
+        if !table_policy.deny_columns.is_empty() {
+            let denied: Vec<&str> = table_policy
+                .deny_columns
+                .iter()
+                .filter(|column| payload.sql.contains(column.as_str()))
+                .map(String::as_str)
+                .collect();
+            check(
+                "deny_columns",
+                format!("{section}.deny_columns"),
+                if denied.is_empty() {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "Query references denied columns of table '{table}': {}",
+                        denied.join(", ")
+                    ))
+                },
+            );
+        }
+
+        for (index, expression) in table_policy.required_expressions.iter().enumerate() {
+            check(
+                "required_expressions",
+                format!("{section}.required_expressions[{index}]"),
+                ensure_required_expression(payload, expression),
+            );
+        }
+
+        let now = Utc::now();
+        for (index, condition) in table_policy.conditions.iter().enumerate() {
+            check(
+                "conditions",
+                format!("{section}.conditions[{index}]"),
+                ensure_condition(&payload.context, &parsed.operation, condition, now),
+            );
+        }
+    }
+}
+
+fn ensure_condition(
+    context: &QueryContext,
+    operation: &str,
+    condition: &PolicyCondition,
+    now: DateTime<Utc>,
+) -> Result<(), String> {
+    if !condition.ops.is_empty() && !condition.ops.iter().any(|op| op == operation) {
+        return Ok(());
+    }
+
+    let mut matched = true;
+    for matcher in &condition.when {
+        matched &= attribute_matches(context, matcher);
+    }
+    if let Some(window) = &condition.during {
+        matched &= time_window_contains(window, now)?;
+    }
+
+    let allowed = match condition.effect {
+        ConditionEffect::Require => matched,
+        ConditionEffect::Deny => !matched,
+    };
+    if allowed {
+        Ok(())
+    } else {
+        let name = if condition.name.is_empty() {
+            "unnamed"
+        } else {
+            condition.name.as_str()
+        };
+        Err(format!(
+            "Policy condition '{name}' rejected operation '{operation}'"
+        ))
+    }
+}
+
+fn attribute_matches(context: &QueryContext, matcher: &AttributeMatch) -> bool {
+    let Some(value) = context.attribute(&matcher.attribute) else {
+        return false;
+    };
+
+    if let Some(expected) = &matcher.equals
+        && expected != &value
+    {
+        return false;
+    }
+    if !matcher.one_of.is_empty() && !matcher.one_of.contains(&value) {
+        return false;
+    }
+    if let Some(pattern) = &matcher.pattern {
+        let text = match &value {
+            serde_json::Value::String(text) => text.clone(),
+            other => other.to_string(),
+        };
+        if !glob_matches(pattern, &text) {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Matches `text` against a pattern where `*` stands for any run of characters.
+pub(crate) fn glob_matches(pattern: &str, text: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == text;
+    }
+
+    let first = parts[0];
+    let last = parts[parts.len() - 1];
+    if text.len() < first.len() + last.len() || !text.starts_with(first) || !text.ends_with(last) {
+        return false;
+    }
+
+    let mut rest = &text[first.len()..text.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    true
+}
+
+fn time_window_contains(window: &TimeWindow, now: DateTime<Utc>) -> Result<bool, String> {
+    let offset = FixedOffset::east_opt(window.utc_offset_minutes * 60)
+        .ok_or_else(|| format!("Invalid utc_offset_minutes {}", window.utc_offset_minutes))?;
+    let local = now.with_timezone(&offset);
+
+    if !window.days.is_empty() {
+        let mut days = Vec::new();
+        for day in &window.days {
+            days.push(
+                day.parse::<Weekday>()
+                    .map_err(|_| format!("Invalid weekday '{day}' in time window"))?,
+            );
+        }
+        if !days.contains(&local.weekday()) {
+            return Ok(false);
+        }
+    }
+
+    let time = local.time();
+    let start = parse_window_time(window.start.as_deref())?;
+    let end = parse_window_time(window.end.as_deref())?;
+    let inside = match (start, end) {
+        (Some(start), Some(end)) if start <= end => time >= start && time < end,
+        (Some(start), Some(end)) => time >= start || time < end,
+        (Some(start), None) => time >= start,
+        (None, Some(end)) => time < end,
+        (None, None) => true,
+    };
+
+    Ok(inside)
+}
+
+fn parse_window_time(value: Option<&str>) -> Result<Option<NaiveTime>, String> {
+    value
+        .map(|value| {
+            NaiveTime::parse_from_str(value, "%H:%M")
+                .map_err(|_| format!("Invalid time '{value}' in time window"))
+        })
+        .transpose()
+}
+
+/// Context fields row filters and `equals_context` may read. Free-form
+/// `attributes` are chosen by the caller, so a check against them checks nothing.
+const TRUSTED_ATTRIBUTES: [&str; 3] = ["actor", "tenant_id", "role"];
+
+/// Substitutes `{{context.<attribute>}}` placeholders with SQL literals.
+fn render_row_filter(
+    context: &QueryContext,
+    table: &str,
+    template: &str,
+) -> Result<String, String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..]
+            .find("}}")
+            .map(|end| start + end)
+            .ok_or_else(|| format!("Unterminated placeholder in row filter for table '{table}'"))?;
+        let name = rest[start + 2..end].trim();
+        let attribute = name.strip_prefix("context.").unwrap_or(name);
+        if !TRUSTED_ATTRIBUTES.contains(&attribute) {
             return Err(format!(
-                "Operation '{}' is not allowed for table",
-                parsed.operation
+                "Row filter for table '{table}' can only use context.actor, context.tenant_id or context.role, not '{attribute}'"
             ));
         }
+        let literal = match context.attribute(attribute) {
+            Some(serde_json::Value::String(text)) => format!("'{}'", text.replace('\'', "''")),
+            Some(serde_json::Value::Number(number)) => number.to_string(),
+            Some(serde_json::Value::Bool(value)) => value.to_string().to_uppercase(),
+            _ => {
+                return Err(format!(
+                    "Row filter for table '{table}' needs context attribute '{attribute}'"
+                ));
+            }
+        };
+        rendered.push_str(&rest[..start]);
+        rendered.push_str(&literal);
+        rest = &rest[end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Checks that every row an INSERT writes would pass the table's row filter.
+/// Only filters made of `column = value` comparisons joined with AND can be
+/// checked; INSERTs into tables with any other filter are rejected.
+fn ensure_insert_matches_row_filter(
+    payload: &SqlRequest,
+    parsed: &ParsedQuery,
+    table: &str,
+    predicate: &str,
+) -> Result<(), String> {
+    if !parsed.updated_columns.is_empty() {
+        return Err(format!(
+            "INSERT with an upsert clause is not allowed on row-filtered table '{table}'"
+        ));
+    }
+    if !parsed.lists_columns {
+        return Err(format!(
+            "INSERT must list its columns explicitly to check the row filter on table '{table}'"
+        ));
+    }
 
-        for required in &table_policy.required_filters {
-            ensure_required_filter(payload, required)?;
+    let expr = parse_expr(predicate)?;
+    let mut equalities = Vec::new();
+    if !collect_equalities(&expr, &mut equalities) {
+        return Err(format!(
+            "Row filter for table '{table}' cannot be checked on INSERT; use `column = value` comparisons joined with AND"
+        ));
+    }
+
+    for (column, expected) in equalities {
+        let violation =
+            || format!("INSERT into '{table}' must set column '{column}' to match its row filter");
+        let mut writes = parsed
+            .written_values
+            .iter()
+            .filter(|write| write.column.eq_ignore_ascii_case(&column))
+            .peekable();
+        if writes.peek().is_none() {
+            return Err(violation());
+        }
+        for write in writes {
+            let value = bound_value(payload, write)?;
+            if literal_text(value) != literal_text(&expected) {
+                return Err(violation());
+            }
         }
+    }
+    Ok(())
+}
 
-        if !table_policy.deny_columns.is_empty()
-            && table_policy
-                .deny_columns
+fn collect_equalities(expr: &Expr, equalities: &mut Vec<(String, serde_json::Value)>) -> bool {
+    match expr {
+        Expr::Nested(inner) => collect_equalities(inner, equalities),
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => collect_equalities(left, equalities) && collect_equalities(right, equalities),
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::Eq,
+            right,
+        } => {
+            let (column, value) = match (column_name(left), column_name(right)) {
+                (Some(column), None) => (column, right),
+                (None, Some(column)) => (column, left),
+                _ => return false,
+            };
+            match literal_value(value) {
+                Some(value) => {
+                    equalities.push((column, value));
+                    true
+                }
+                None => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+fn column_name(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Identifier(ident) => Some(ident.value.clone()),
+        Expr::CompoundIdentifier(parts) => parts.last().map(|ident| ident.value.clone()),
+        _ => None,
+    }
+}
+
+fn ensure_writable_columns(parsed: &ParsedQuery, table_policy: &TablePolicy) -> Result<(), String> {
+    if !parsed.lists_columns
+        && (!table_policy.writable_columns.is_empty() || !table_policy.immutable_columns.is_empty())
+    {
+        return Err("INSERT must list its columns explicitly for this table".to_string());
+    }
+
+    let contains = |columns: &[String], column: &str| {
+        columns
+            .iter()
+            .any(|candidate| candidate.eq_ignore_ascii_case(column))
+    };
+
+    for column in &parsed.written_columns {
+        if !table_policy.writable_columns.is_empty()
+            && !contains(&table_policy.writable_columns, column)
+        {
+            return Err(format!("Column '{column}' is not writable"));
+        }
+    }
+    for column in &parsed.updated_columns {
+        if contains(&table_policy.immutable_columns, column) {
+            return Err(format!("Column '{column}' is immutable"));
+        }
+    }
+
+    Ok(())
+}
+
+fn ensure_value_constraint(
+    payload: &SqlRequest,
+    parsed: &ParsedQuery,
+    constraint: &ValueConstraint,
+) -> Result<(), String> {
+    let context = &payload.context;
+    let column = constraint.column.as_str();
+    if !parsed.lists_columns {
+        return Err(format!(
+            "INSERT must list its columns explicitly to check column '{column}'"
+        ));
+    }
+    if parsed.operation == "insert"
+        && !constraint.allow_default
+        && !parsed
+            .written_columns
+            .iter()
+            .any(|written| written.eq_ignore_ascii_case(column))
+    {
+        return Err(format!(
+            "INSERT must write column '{column}', which has a value constraint"
+        ));
+    }
+    for write in &parsed.written_values {
+        if !write.column.eq_ignore_ascii_case(column) {
+            continue;
+        }
+
+        let value = bound_value(payload, write)?;
+        let violation = || format!("Value {value} for column '{column}' violates policy");
+
+        if constraint.min.is_some() || constraint.max.is_some() {
+            let number = value.as_f64().ok_or_else(violation)?;
+            if constraint.min.is_some_and(|min| number < min)
+                || constraint.max.is_some_and(|max| number > max)
+            {
+                return Err(violation());
+            }
+        }
+
+        if !constraint.one_of.is_empty()
+            && !constraint
+                .one_of
                 .iter()
-                .any(|column| payload.sql.contains(column))
+                .any(|allowed| literal_text(allowed) == literal_text(value))
         {
-            return Err("Query references denied columns".to_string());
+            return Err(violation());
         }
 
-        for expression in &table_policy.required_expressions {
-            ensure_required_expression(payload, expression)?;
+        if let Some(attribute) = &constraint.equals_context {
+            let attribute = attribute.strip_prefix("context.").unwrap_or(attribute);
+            if !TRUSTED_ATTRIBUTES.contains(&attribute) {
+                return Err(format!(
+                    "equals_context for column '{column}' can only use context.actor, context.tenant_id or context.role, not '{attribute}'"
+                ));
+            }
+            let expected = context.attribute(attribute).ok_or_else(violation)?;
+            if literal_text(&expected) != literal_text(value) {
+                return Err(violation());
+            }
         }
 
-        Ok(())
+        if let Some(pattern) = &constraint.pattern {
+            let text = value.as_str().ok_or_else(violation)?;
+            if !pattern.is_match(text) {
+                return Err(violation());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The literal or bound value a write stores; other expressions are rejected.
+fn bound_value<'a>(
+    payload: &'a SqlRequest,
+    write: &'a ColumnWrite,
+) -> Result<&'a serde_json::Value, String> {
+    let column = &write.column;
+    match &write.value {
+        WrittenValue::Literal(value) => Ok(value),
+        WrittenValue::Parameter(placeholder) => payload
+            .params
+            .value_for(placeholder)
+            .ok_or_else(|| format!("Missing bind parameter {placeholder} for column '{column}'")),
+        WrittenValue::Expression(expression) => Err(format!(
+            "Column '{column}' must be written as a literal value, got '{expression}'"
+        )),
+    }
+}
+
+fn literal_text(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(text) => text.clone(),
+        other => other.to_string(),
     }
 }
 
@@ -183,8 +1218,6 @@ fn ensure_required_expression(payload: &SqlRequest, expression: &str) -> Result<
         .replace("==", "=")
         .replace("&&", "and")
         .replace("||", "or")
-        .replace("true", "true")
-        .replace("false", "false")
         .replace(" ", "");
 
     let sql = payload.sql.to_lowercase().replace(' ', "");
@@ -195,37 +1228,258 @@ fn ensure_required_expression(payload: &SqlRequest, expression: &str) -> Result<
     }
 }
 
-fn analyze_statement(statement: Statement) -> Result<(String, Vec<String>, bool), String> {
+fn analyze_statement(statement: Statement) -> ProxyResult<ParsedQuery> {
+    let mut parsed = ParsedQuery {
+        operation: String::new(),
+        tables: Vec::new(),
+        has_where: true,
+        written_columns: Vec::new(),
+        written_values: Vec::new(),
+        updated_columns: Vec::new(),
+        lists_columns: true,
+        statement: statement.clone(),
+    };
+
     match statement {
         Statement::Query(query) => {
-            let tables = extract_tables_from_query(&query.to_string());
-            Ok(("select".to_string(), tables, true))
+            parsed.operation = "select".to_string();
+            parsed.tables = extract_tables_from_query(&query.to_string());
+        }
+        Statement::Insert {
+            table_name,
+            columns,
+            source,
+            on,
+            ..
+        } => {
+            parsed.operation = "insert".to_string();
+            parsed.tables = vec![table_name_to_string(&table_name)];
+            parsed.lists_columns = !columns.is_empty();
+            parsed.written_columns = columns.into_iter().map(|column| column.value).collect();
+            parsed.written_values = insert_values(&parsed.written_columns, source.as_deref());
+            let upsert = match on {
+                Some(OnInsert::DuplicateKeyUpdate(assignments)) => assignments,
+                Some(OnInsert::OnConflict(OnConflict {
+                    action: OnConflictAction::DoUpdate(update),
+                    ..
+                })) => update.assignments,
+                _ => Vec::new(),
+            };
+            add_assignments(&mut parsed, &upsert);
         }
-        Statement::Insert { table_name, .. } => Ok((
-            "insert".to_string(),
-            vec![table_name_to_string(&table_name)],
-            true,
-        )),
         Statement::Update {
-            table, selection, ..
-        } => Ok((
-            "update".to_string(),
-            vec![table_name_from_table_with_joins(&table)],
-            selection.is_some(),
-        )),
+            table,
+            assignments,
+            selection,
+            ..
+        } => {
+            parsed.operation = "update".to_string();
+            parsed.tables = vec![table_name_from_table_with_joins(&table)];
+            parsed.has_where = selection.is_some();
+            add_assignments(&mut parsed, &assignments);
+        }
         Statement::Delete {
             from, selection, ..
         } => {
-            let mut tables = Vec::new();
+            parsed.operation = "delete".to_string();
             if let Some(table) = from.first() {
-                tables.push(table_name_from_table_with_joins(table));
+                parsed.tables.push(table_name_from_table_with_joins(table));
             }
-            Ok(("delete".to_string(), tables, selection.is_some()))
+            parsed.has_where = selection.is_some();
         }
         Statement::Drop { .. } | Statement::AlterTable { .. } | Statement::Truncate { .. } => {
-            Err("Destructive DDL statements are not allowed".to_string())
+            return Err(ProxyError::new(
+                ErrorCode::UnsupportedStatement,
+                "Destructive DDL statements are not allowed",
+            ));
+        }
+        _ => {
+            return Err(ProxyError::new(
+                ErrorCode::UnsupportedStatement,
+                "Statement type not supported",
+            )
+            .with_suggestion("Use a single SELECT, INSERT, UPDATE or DELETE"));
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Records SET assignments as written and updated columns.
+fn add_assignments(parsed: &mut ParsedQuery, assignments: &[Assignment]) {
+    for assignment in assignments {
+        if let Some(ident) = assignment.id.last() {
+            if !parsed.written_columns.contains(&ident.value) {
+                parsed.written_columns.push(ident.value.clone());
+            }
+            parsed.updated_columns.push(ident.value.clone());
+            parsed.written_values.push(ColumnWrite {
+                column: ident.value.clone(),
+                value: written_value(&assignment.value),
+            });
+        }
+    }
+}
+
+fn insert_values(columns: &[String], source: Option<&sqlparser::ast::Query>) -> Vec<ColumnWrite> {
+    let Some(source) = source else {
+        return Vec::new();
+    };
+
+    match source.body.as_ref() {
+        SetExpr::Values(values) => values
+            .rows
+            .iter()
+            .flat_map(|row| {
+                columns.iter().zip(row).map(|(column, expr)| ColumnWrite {
+                    column: column.clone(),
+                    value: written_value(expr),
+                })
+            })
+            .collect(),
+        _ => columns
+            .iter()
+            .map(|column| ColumnWrite {
+                column: column.clone(),
+                value: WrittenValue::Expression(source.to_string()),
+            })
+            .collect(),
+    }
+}
+
+fn written_value(expr: &Expr) -> WrittenValue {
+    if let Expr::Value(Value::Placeholder(placeholder)) = expr {
+        return WrittenValue::Parameter(placeholder.clone());
+    }
+    match literal_value(expr) {
+        Some(value) => WrittenValue::Literal(value),
+        None => WrittenValue::Expression(expr.to_string()),
+    }
+}
+
+/// `params` narrowed to the named parameters `sql` references, for statements
+/// derived from a request that drop some of its placeholders.
+pub(crate) fn params_used_by(sql: &str, params: &SqlParams) -> SqlParams {
+    let SqlParams::Named(values) = params else {
+        return params.clone();
+    };
+    let Ok(statements) = Parser::parse_sql(&PostgreSqlDialect {}, sql) else {
+        return params.clone();
+    };
+    let mut used = Vec::new();
+    let _ = visit_expressions(&statements, |expr| {
+        if let Expr::Value(Value::Placeholder(placeholder)) = expr {
+            used.push(placeholder_name(placeholder).to_string());
+        }
+        ControlFlow::<()>::Continue(())
+    });
+    SqlParams::Named(
+        values
+            .iter()
+            .filter(|(key, _)| used.iter().any(|name| name == placeholder_name(key)))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect(),
+    )
+}
+
+/// Numbers bare `?` placeholders and rewrites `$N` to `?N` so positional
+/// parameters bind by index, then checks placeholders and params line up.
+fn bind_placeholders(statement: &mut Statement, params: &SqlParams) -> Result<(), String> {
+    let mut next_index = 0;
+    let mut numbered = false;
+    let mut positional = Vec::new();
+    let mut named = Vec::new();
+    let _ = visit_expressions_mut(statement, |expr| {
+        if let Expr::Value(Value::Placeholder(placeholder)) = expr {
+            if placeholder == "?" {
+                next_index += 1;
+                *placeholder = format!("?{next_index}");
+            } else if let Some(index) = placeholder.strip_prefix(['$', '?'])
+                && index.parse::<usize>().is_ok()
+            {
+                numbered = true;
+                *placeholder = format!("?{index}");
+            }
+
+            match placeholder.strip_prefix('?') {
+                Some(index) => positional.push(index.parse::<usize>().unwrap_or_default()),
+                None => named.push(placeholder_name(placeholder).to_string()),
+            }
+        }
+        ControlFlow::<()>::Continue(())
+    });
+
+    if next_index > 0 && numbered {
+        return Err("Cannot mix '?' with numbered placeholders".to_string());
+    }
+
+    match params {
+        SqlParams::Positional(values) => {
+            if let Some(name) = named.first() {
+                return Err(format!(
+                    "Named placeholder ':{name}' requires params to be an object"
+                ));
+            }
+            if let Some(index) = positional
+                .iter()
+                .find(|index| **index == 0 || **index > values.len())
+            {
+                return Err(format!("Bind parameter {index} is not provided"));
+            }
+            if let Some(unused) = (1..=values.len()).find(|index| !positional.contains(index)) {
+                return Err(format!("Bind parameter {unused} is not used by the query"));
+            }
+        }
+        SqlParams::Named(values) => {
+            if !positional.is_empty() {
+                return Err("Positional placeholders require params to be an array".to_string());
+            }
+            let keys: Vec<&str> = values.keys().map(|key| placeholder_name(key)).collect();
+            if let Some(name) = named.iter().find(|name| !keys.contains(&name.as_str())) {
+                return Err(format!("Bind parameter '{name}' is not provided"));
+            }
+            if let Some(unused) = keys
+                .iter()
+                .find(|key| !named.iter().any(|name| name == *key))
+            {
+                return Err(format!(
+                    "Bind parameter '{unused}' is not used by the query"
+                ));
+            }
         }
-        _ => Err("Statement type not supported".to_string()),
+    }
+
+    Ok(())
+}
+
+fn literal_value(expr: &Expr) -> Option<serde_json::Value> {
+    match expr {
+        Expr::Value(value) => match value {
+            Value::Number(number, _) => number
+                .parse::<i64>()
+                .map(Into::into)
+                .ok()
+                .or_else(|| number.parse::<f64>().ok().map(Into::into)),
+            Value::SingleQuotedString(text)
+            | Value::EscapedStringLiteral(text)
+            | Value::NationalStringLiteral(text) => Some(text.clone().into()),
+            Value::DollarQuotedString(text) => Some(text.value.clone().into()),
+            Value::Boolean(value) => Some((*value).into()),
+            Value::Null => Some(serde_json::Value::Null),
+            _ => None,
+        },
+        Expr::UnaryOp {
+            op: UnaryOperator::Minus,
+            expr,
+        } => match literal_value(expr)? {
+            serde_json::Value::Number(number) => number
+                .as_i64()
+                .map(|value| (-value).into())
+                .or_else(|| number.as_f64().map(|value| (-value).into())),
+            _ => None,
+        },
+        Expr::Nested(expr) => literal_value(expr),
+        _ => None,
     }
 }
 
@@ -248,7 +1502,7 @@ fn table_name_from_table_with_joins(table: &sqlparser::ast::TableWithJoins) -> S
     }
 }
 
-fn table_name_to_string(name: &ObjectName) -> String {
+pub(crate) fn table_name_to_string(name: &ObjectName) -> String {
     name.0
         .iter()
         .map(|ident| ident.value.clone())
@@ -256,6 +1510,21 @@ fn table_name_to_string(name: &ObjectName) -> String {
         .join(".")
 }
 
+/// Whether two table names refer to the same table, ignoring case and any
+/// schema qualifier such as `main.`.
+pub(crate) fn same_table(left: &str, right: &str) -> bool {
+    let base = |name: &str| name.rsplit('.').next().unwrap_or(name).to_string();
+    base(left).eq_ignore_ascii_case(&base(right))
+}
+
+/// The entry of `entries`, keyed by policy table name, for the table `name`.
+pub(crate) fn table_entry<'a, V>(entries: &'a HashMap<String, V>, name: &str) -> Option<&'a V> {
+    entries
+        .iter()
+        .find(|(table, _)| same_table(table, name))
+        .map(|(_, entry)| entry)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -267,30 +1536,75 @@ mod tests {
                 actor: "agent:test".to_string(),
                 tenant_id: "acme".to_string(),
                 role: "employee".to_string(),
+                client_ip: None,
+                attributes: HashMap::new(),
             },
+            params: SqlParams::default(),
+            preview_id: None,
+            idempotency_key: None,
+            explain: false,
         }
     }
 
+    #[test]
+    fn traces_rule_sources_and_role_fallback() {
+        let engine = QueryEngine;
+        let policy: PolicyConfig = serde_yaml::from_str(
+            "roles:\n  employee:\n    orders:\n      allow_ops: [select]\ntables:\n  users:\n    deny_columns: [ssn]\n",
+        )
+        .unwrap();
+
+        let trace = engine.explain(
+            &request("UPDATE orders SET status = 'paid' WHERE tenant_id = 'acme'"),
+            &policy,
+        );
+        assert!(!trace.allowed);
+        let failed = trace
+            .steps
+            .iter()
+            .find(|step| step.outcome == TraceOutcome::Failed)
+            .unwrap();
+        assert_eq!(failed.source, "roles.employee.orders.allow_ops");
+        assert_eq!(failed.scope, RuleScope::Role);
+        assert!(failed.detail.as_deref().unwrap().contains("'orders'"));
+
+        let trace = engine.explain(
+            &request("SELECT id FROM users WHERE tenant_id = 'acme'"),
+            &policy,
+        );
+        assert!(trace.allowed);
+        let table = trace
+            .steps
+            .iter()
+            .find(|step| step.rule == "table_policy")
+            .unwrap();
+        assert_eq!(
+            (table.scope, table.source.as_str()),
+            (RuleScope::Global, "tables.users")
+        );
+    }
+
     #[test]
     fn rejects_multiple_statements() {
-        let engine = QueryEngine::default();
+        let engine = QueryEngine;
         let payload = request("SELECT 1; SELECT 2");
         let error = engine.evaluate_sql(&payload).unwrap_err();
-        assert!(error.contains("single-statement"));
+        assert!(error.message.contains("single-statement"));
+        assert_eq!(error.code, ErrorCode::UnsupportedStatement);
     }
 
     #[test]
     fn blocks_delete_without_where() {
-        let engine = QueryEngine::default();
+        let engine = QueryEngine;
         let payload = request("DELETE FROM users");
         let (parsed, _sql) = engine.evaluate_sql(&payload).unwrap();
         let error = engine.enforce_rules(&payload, &parsed).unwrap_err();
-        assert!(error.contains("WHERE clause"));
+        assert!(error.message.contains("WHERE clause"));
     }
 
     #[test]
     fn allows_delete_with_where() {
-        let engine = QueryEngine::default();
+        let engine = QueryEngine;
         let payload = request("DELETE FROM users WHERE tenant_id = 'acme'");
         let (parsed, _sql) = engine.evaluate_sql(&payload).unwrap();
         engine.enforce_rules(&payload, &parsed).unwrap();
@@ -298,19 +1612,414 @@ mod tests {
 
     #[test]
     fn blocks_missing_tenant_filter() {
-        let engine = QueryEngine::default();
+        let engine = QueryEngine;
         let payload = request("SELECT * FROM users");
         let (parsed, _sql) = engine.evaluate_sql(&payload).unwrap();
         let error = engine.enforce_rules(&payload, &parsed).unwrap_err();
-        assert!(error.contains("tenant_id"));
+        assert!(error.message.contains("tenant_id"));
+        assert_eq!(error.code, ErrorCode::TenantViolation);
+        assert_eq!(error.rule.unwrap().rule, "tenant_filter");
+        assert!(error.suggestion.is_some());
     }
 
     #[test]
     fn extracts_table_for_update() {
-        let engine = QueryEngine::default();
+        let engine = QueryEngine;
         let payload = request("UPDATE users SET name = 'Jane' WHERE tenant_id = 'acme'");
         let (parsed, _sql) = engine.evaluate_sql(&payload).unwrap();
         assert_eq!(parsed.tables, vec!["users".to_string()]);
         assert!(parsed.has_where);
     }
+
+    fn validate_table_policy(
+        engine: &QueryEngine,
+        payload: &SqlRequest,
+        parsed: &ParsedQuery,
+        table_policy: &TablePolicy,
+    ) -> ProxyResult<()> {
+        let mut trace = DecisionTrace::new(&payload.context.role);
+        let table = parsed.tables[0].clone();
+        engine.trace_table_policy(
+            payload,
+            parsed,
+            &table,
+            table_policy,
+            RuleScope::Global,
+            &mut trace,
+        );
+        trace.result()
+    }
+
+    fn table_policy(yaml: &str) -> TablePolicy {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn blocks_tenant_id_update() {
+        let engine = QueryEngine;
+        let payload = request("UPDATE carts SET tenant_id = 'other' WHERE tenant_id = 'acme'");
+        let (parsed, _sql) = engine.evaluate_sql(&payload).unwrap();
+        let error = engine.enforce_rules(&payload, &parsed).unwrap_err();
+        assert!(error.message.contains("tenant_id"));
+    }
+
+    #[test]
+    fn enforces_writable_and_immutable_columns() {
+        let engine = QueryEngine;
+        let carts = table_policy(
+            "allow_ops: [insert, update]\nwritable_columns: [status, note, diner_id]\nimmutable_columns: [diner_id]",
+        );
+
+        let payload =
+            request("UPDATE carts SET subtotal_cents = 0 WHERE tenant_id = 'acme' AND id = 1");
+        let (parsed, _sql) = engine.evaluate_sql(&payload).unwrap();
+        let error = validate_table_policy(&engine, &payload, &parsed, &carts).unwrap_err();
+        assert!(error.message.contains("subtotal_cents"));
+
+        let payload = request("UPDATE carts SET diner_id = 2 WHERE tenant_id = 'acme' AND id = 1");
+        let (parsed, _sql) = engine.evaluate_sql(&payload).unwrap();
+        let error = validate_table_policy(&engine, &payload, &parsed, &carts).unwrap_err();
+        assert!(error.message.contains("immutable"));
+
+        let payload = request("INSERT INTO carts (status, diner_id) VALUES ('active', 2)");
+        let (parsed, _sql) = engine.evaluate_sql(&payload).unwrap();
+        validate_table_policy(&engine, &payload, &parsed, &carts).unwrap();
+
+        let payload = request("INSERT INTO carts VALUES (1, 'active')");
+        let (parsed, _sql) = engine.evaluate_sql(&payload).unwrap();
+        assert!(validate_table_policy(&engine, &payload, &parsed, &carts).is_err());
+
+        let payload = request(
+            "INSERT INTO carts (status, note) VALUES ('active', 'x') ON CONFLICT (id) DO UPDATE SET diner_id = 9",
+        );
+        let (parsed, _sql) = engine.evaluate_sql(&payload).unwrap();
+        let error = validate_table_policy(&engine, &payload, &parsed, &carts).unwrap_err();
+        assert!(error.message.contains("immutable"));
+
+        let payload = request(
+            "INSERT INTO carts (status, tenant_id) VALUES ('active', 'acme') ON CONFLICT (id) DO UPDATE SET tenant_id = 'other'",
+        );
+        let (parsed, _sql) = engine.evaluate_sql(&payload).unwrap();
+        let error = engine.enforce_rules(&payload, &parsed).unwrap_err();
+        assert!(error.message.contains("tenant_id"));
+    }
+
+    #[test]
+    fn enforces_value_constraints_on_written_literals() {
+        let engine = QueryEngine;
+        let cart_items = table_policy(
+            "value_constraints:\n  - column: quantity\n    min: 1\n    max: 20\n  - column: tenant_id\n    equals_context: context.tenant_id\n  - column: note\n    pattern: '^[a-z ]*$'\n    allow_default: true",
+        );
+        let check = |sql: &str| {
+            let payload = request(sql);
+            let (parsed, _sql) = engine.evaluate_sql(&payload).unwrap();
+            validate_table_policy(&engine, &payload, &parsed, &cart_items)
+        };
+
+        check("INSERT INTO cart_items (quantity, tenant_id, note) VALUES (2, 'acme', 'no onions')")
+            .unwrap();
+        assert!(check("INSERT INTO cart_items (quantity, tenant_id) VALUES (21, 'acme')").is_err());
+        assert!(check("INSERT INTO cart_items (quantity, tenant_id) VALUES (-1, 'acme')").is_err());
+        assert!(check("INSERT INTO cart_items (quantity, tenant_id) VALUES (1, 'other')").is_err());
+        assert!(check("UPDATE cart_items SET note = 'DROP' WHERE tenant_id = 'acme'").is_err());
+
+        let error = check("UPDATE cart_items SET quantity = quantity + 1 WHERE tenant_id = 'acme'")
+            .unwrap_err();
+        assert!(error.message.contains("literal"));
+
+        let error = check("INSERT INTO cart_items VALUES (1, 9999, 'acme')").unwrap_err();
+        assert!(error.message.contains("explicitly"));
+        assert!(
+            check(
+                "INSERT INTO cart_items (quantity, tenant_id) VALUES (2, 'acme') ON CONFLICT (id) DO UPDATE SET quantity = 9999"
+            )
+            .is_err()
+        );
+
+        // A constrained column left out of an INSERT would take its default.
+        let error = check("INSERT INTO cart_items (quantity) VALUES (2)").unwrap_err();
+        assert!(error.message.contains("must write column 'tenant_id'"));
+
+        // Caller-supplied attributes cannot be the expected value.
+        let by_attribute =
+            table_policy("value_constraints:\n  - column: diner_id\n    equals_context: diner_id");
+        let mut payload = request("INSERT INTO cart_items (diner_id) VALUES (7)");
+        payload
+            .context
+            .attributes
+            .insert("diner_id".to_string(), 7.into());
+        let (parsed, _sql) = engine.evaluate_sql(&payload).unwrap();
+        let error = validate_table_policy(&engine, &payload, &parsed, &by_attribute).unwrap_err();
+        assert!(error.message.contains("context.tenant_id"));
+
+        let invalid = serde_yaml::from_str::<TablePolicy>(
+            "value_constraints:\n  - column: note\n    pattern: '(unclosed'",
+        );
+        assert!(invalid.unwrap_err().to_string().contains("invalid pattern"));
+    }
+
+    #[test]
+    fn injects_and_lowers_select_limit() {
+        let engine = QueryEngine;
+        let policy: PolicyConfig = serde_yaml::from_str(
+            "limits:\n  select_limit: 100\nroles:\n  employee:\n    orders:\n      select_limit: 20",
+        )
+        .unwrap();
+        let rewrite = |sql: &str| {
+            let payload = request(sql);
+            let (parsed, _sql) = engine.evaluate_sql(&payload).unwrap();
+            engine.rewrite(&payload, &parsed, &policy, None).unwrap()
+        };
+
+        let rewritten = rewrite("SELECT * FROM users WHERE tenant_id = 'acme'");
+        assert!(rewritten.sql.ends_with("LIMIT 100"));
+        assert_eq!(rewritten.warnings.len(), 1);
+
+        let rewritten = rewrite("SELECT * FROM orders WHERE tenant_id = 'acme' LIMIT 500");
+        assert!(rewritten.sql.ends_with("LIMIT 20"));
+        assert!(rewritten.warnings[0].contains("lowered"));
+
+        let rewritten = rewrite("SELECT * FROM orders WHERE tenant_id = 'acme' LIMIT 5");
+        assert!(rewritten.sql.ends_with("LIMIT 5"));
+        assert!(rewritten.warnings.is_empty());
+    }
+
+    #[test]
+    fn masks_columns_through_wildcards_joins_and_subqueries() {
+        let engine = QueryEngine;
+        let policy: PolicyConfig = serde_yaml::from_str(
+            "roles:\n  employee:\n    diners:\n      mask_columns:\n        - column: vip_level\n          mask: constant\n          value: hidden",
+        )
+        .unwrap();
+        let schema: SchemaSnapshot = serde_json::from_value(serde_json::json!({
+            "tables": [{"name": "diners", "columns": [
+                {"name": "id", "data_type": "INTEGER", "nullable": false},
+                {"name": "vip_level", "data_type": "TEXT", "nullable": false}
+            ]}]
+        }))
+        .unwrap();
+        let rewrite = |sql: &str| {
+            let payload = request(sql);
+            let (parsed, _sql) = engine.evaluate_sql(&payload).unwrap();
+            engine.rewrite(&payload, &parsed, &policy, Some(&schema))
+        };
+
+        let rewritten = rewrite("SELECT * FROM diners WHERE tenant_id = 'acme'").unwrap();
+        assert!(
+            rewritten
+                .sql
+                .starts_with("SELECT diners.id, 'hidden' AS vip_level FROM")
+        );
+        assert!(rewritten.warnings[0].contains("diners.vip_level"));
+
+        let rewritten = rewrite(
+            "SELECT r.id, d.vip_level FROM reservations AS r JOIN diners AS d ON d.id = r.id WHERE r.tenant_id = 'acme'",
+        )
+        .unwrap();
+        assert!(rewritten.sql.contains("'hidden' AS vip_level"));
+
+        let rewritten = rewrite(
+            "SELECT x.vip_level FROM (SELECT vip_level FROM diners WHERE tenant_id = 'acme') AS x",
+        )
+        .unwrap();
+        assert!(
+            rewritten
+                .sql
+                .contains("(SELECT 'hidden' AS vip_level FROM diners")
+        );
+
+        let error =
+            rewrite("SELECT upper(vip_level) FROM diners WHERE tenant_id = 'acme'").unwrap_err();
+        assert!(error.message.contains("masked"));
+
+        let error =
+            rewrite("SELECT id FROM diners WHERE tenant_id = 'acme' AND vip_level LIKE 'g%'")
+                .unwrap_err();
+        assert!(error.message.contains("cannot be used in WHERE"));
+
+        let error = rewrite("SELECT id FROM diners WHERE tenant_id = 'acme' ORDER BY vip_level")
+            .unwrap_err();
+        assert!(error.message.contains("ORDER BY"));
+
+        // Case and a schema qualifier do not get around the mask.
+        for sql in [
+            "SELECT vip_level FROM DINERS WHERE tenant_id = 'acme'",
+            "SELECT vip_level FROM main.diners WHERE tenant_id = 'acme'",
+            "SELECT * FROM main.Diners WHERE tenant_id = 'acme'",
+        ] {
+            let rewritten = rewrite(sql).unwrap();
+            assert!(rewritten.sql.contains("'hidden' AS vip_level"));
+        }
+    }
+
+    #[test]
+    fn injects_row_filters_into_reads_joins_and_writes() {
+        let engine = QueryEngine;
+        let policy: PolicyConfig = serde_yaml::from_str(
+            "roles:\n  employee:\n    carts:\n      row_filter: \"owner = {{context.actor}}\"",
+        )
+        .unwrap();
+        let rewrite = |sql: &str| {
+            let payload = request(sql);
+            let (parsed, _sql) = engine.evaluate_sql(&payload).unwrap();
+            engine.rewrite(&payload, &parsed, &policy, None)
+        };
+
+        let rewritten = rewrite("SELECT * FROM carts WHERE tenant_id = 'acme'").unwrap();
+        assert!(
+            rewritten
+                .sql
+                .contains("FROM (SELECT * FROM carts WHERE owner = 'agent:test') AS carts")
+        );
+
+        let rewritten = rewrite(
+            "SELECT o.id FROM orders AS o LEFT JOIN carts AS c ON c.id = o.id WHERE o.tenant_id = 'acme'",
+        )
+        .unwrap();
+        assert!(
+            rewritten
+                .sql
+                .contains("(SELECT * FROM carts WHERE owner = 'agent:test') AS c")
+        );
+
+        let rewritten =
+            rewrite("UPDATE carts SET status = 'closed' WHERE tenant_id = 'acme'").unwrap();
+        assert!(
+            rewritten
+                .sql
+                .ends_with("WHERE (tenant_id = 'acme') AND (carts.owner = 'agent:test')")
+        );
+
+        rewrite("INSERT INTO carts (owner, tenant_id) VALUES ('agent:test', 'acme')").unwrap();
+        let error = rewrite("INSERT INTO carts (owner, tenant_id) VALUES ('agent:other', 'acme')")
+            .unwrap_err();
+        assert!(error.message.contains("row filter"));
+        let error = rewrite("INSERT INTO carts (tenant_id) VALUES ('acme')").unwrap_err();
+        assert!(error.message.contains("'owner'"));
+
+        // Case and a schema qualifier do not get around the filter.
+        let rewritten = rewrite("SELECT * FROM CARTS WHERE tenant_id = 'acme'").unwrap();
+        assert!(
+            rewritten
+                .sql
+                .contains("FROM (SELECT * FROM CARTS WHERE owner = 'agent:test') AS CARTS")
+        );
+        let rewritten = rewrite("SELECT * FROM main.carts WHERE tenant_id = 'acme'").unwrap();
+        assert!(
+            rewritten
+                .sql
+                .contains("FROM (SELECT * FROM main.carts WHERE owner = 'agent:test') AS carts")
+        );
+        let rewritten =
+            rewrite("UPDATE main.Carts SET status = 'closed' WHERE tenant_id = 'acme'").unwrap();
+        assert!(
+            rewritten.sql.contains("owner = 'agent:test'"),
+            "{}",
+            rewritten.sql
+        );
+        let rewritten = rewrite("DELETE FROM CARTS WHERE tenant_id = 'acme'").unwrap();
+        assert!(
+            rewritten.sql.contains("owner = 'agent:test'"),
+            "{}",
+            rewritten.sql
+        );
+        let error =
+            rewrite("INSERT INTO main.CARTS (owner, tenant_id) VALUES ('agent:other', 'acme')")
+                .unwrap_err();
+        assert!(error.message.contains("row filter"));
+
+        let policy: PolicyConfig = serde_yaml::from_str(
+            "roles:\n  employee:\n    carts:\n      row_filter: \"diner_id = {{context.diner_id}}\"",
+        )
+        .unwrap();
+        let mut payload = request("SELECT * FROM carts WHERE tenant_id = 'acme'");
+        payload
+            .context
+            .attributes
+            .insert("diner_id".to_string(), 7.into());
+        let (parsed, _sql) = engine.evaluate_sql(&payload).unwrap();
+        let error = engine
+            .rewrite(&payload, &parsed, &policy, None)
+            .unwrap_err();
+        assert!(error.message.contains("diner_id"));
+    }
+
+    #[test]
+    fn validates_bind_parameters_and_checks_bound_values() {
+        let engine = QueryEngine;
+        let cart_items =
+            table_policy("value_constraints:\n  - column: quantity\n    min: 1\n    max: 20");
+        let mut payload =
+            request("INSERT INTO cart_items (quantity, tenant_id) VALUES (?, ?), ($1, $2)");
+        payload.params = SqlParams::Positional(vec![5.into(), "acme".into()]);
+        let error = engine.evaluate_sql(&payload).unwrap_err();
+        assert!(error.message.contains("mix"));
+
+        payload.sql = "DELETE FROM cart_items WHERE tenant_id = ? AND id = ?".to_string();
+        payload.params = SqlParams::Positional(vec!["acme".into()]);
+        let error = engine.evaluate_sql(&payload).unwrap_err();
+        assert!(error.message.contains("2 is not provided"));
+
+        payload.sql = "INSERT INTO cart_items (quantity, tenant_id) VALUES ($1, $2)".to_string();
+        payload.params = SqlParams::Positional(vec![5.into(), "acme".into()]);
+        let (parsed, sql) = engine.evaluate_sql(&payload).unwrap();
+        assert!(sql.contains("VALUES (?1, ?2)"));
+        validate_table_policy(&engine, &payload, &parsed, &cart_items).unwrap();
+
+        payload.params = SqlParams::Positional(vec![50.into(), "acme".into()]);
+        let (parsed, _sql) = engine.evaluate_sql(&payload).unwrap();
+        assert!(validate_table_policy(&engine, &payload, &parsed, &cart_items).is_err());
+
+        payload.sql = "UPDATE cart_items SET quantity = :qty WHERE tenant_id = 'acme'".to_string();
+        payload.params = SqlParams::Named(HashMap::from([("qty".to_string(), 2.into())]));
+        let (parsed, _sql) = engine.evaluate_sql(&payload).unwrap();
+        validate_table_policy(&engine, &payload, &parsed, &cart_items).unwrap();
+
+        payload.params = SqlParams::Named(HashMap::from([("other".to_string(), 2.into())]));
+        assert!(engine.evaluate_sql(&payload).is_err());
+    }
+
+    fn condition(yaml: &str) -> PolicyCondition {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn deny_condition_blocks_matching_actor() {
+        let payload = request("UPDATE users SET name = 'Jane' WHERE tenant_id = 'acme'");
+        let read_only = condition(
+            "name: read-only-test-agents\neffect: deny\nops: [insert, update, delete]\nwhen:\n  - attribute: actor\n    pattern: 'agent:test*'",
+        );
+        let error =
+            ensure_condition(&payload.context, "update", &read_only, Utc::now()).unwrap_err();
+        assert!(error.contains("read-only-test-agents"));
+        ensure_condition(&payload.context, "select", &read_only, Utc::now()).unwrap();
+    }
+
+    #[test]
+    fn require_condition_checks_time_window_and_attributes() {
+        let mut payload = request("INSERT INTO payments (tenant_id) VALUES ('acme')");
+        payload
+            .context
+            .attributes
+            .insert("model".to_string(), "gpt-4.1".into());
+        let business_hours = condition(
+            "ops: [insert]\nwhen:\n  - attribute: model\n    in: [gpt-4.1]\nduring:\n  days: [mon, tue, wed, thu, fri]\n  start: '09:00'\n  end: '17:00'",
+        );
+
+        let monday_noon = "2026-10-19T12:00:00Z".parse().unwrap();
+        let monday_night = "2026-10-19T22:00:00Z".parse().unwrap();
+        let sunday_noon = "2026-10-18T12:00:00Z".parse().unwrap();
+        ensure_condition(&payload.context, "insert", &business_hours, monday_noon).unwrap();
+        assert!(
+            ensure_condition(&payload.context, "insert", &business_hours, monday_night).is_err()
+        );
+        assert!(
+            ensure_condition(&payload.context, "insert", &business_hours, sunday_noon).is_err()
+        );
+
+        payload.context.attributes.clear();
+        assert!(
+            ensure_condition(&payload.context, "insert", &business_hours, monday_noon).is_err()
+        );
+    }
 }
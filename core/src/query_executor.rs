@@ -1,70 +1,1073 @@
 use crate::{
-    db::SQLDB,
-    policy::PolicyConfig,
-    query_engine::{PreviewResponse, QueryEngine, SqlRequest},
+    db::{
+        ColumnSchema, SQLDB, SchemaSnapshot, SqlParams, SqlTransaction, TransactionMode,
+        with_transaction,
+    },
+    error::{ErrorCode, ProxyError, ProxyResult},
+    metrics::Metrics,
+    plan::QueryPlan,
+    policy::{PlanLimits, PolicyConfig},
+    query_engine::{
+        AffectedRowsQuery, ChangesetPreviewResponse, ChangesetRequest, PreviewResponse,
+        QueryEngine, RowLimit, SqlRequest, params_used_by,
+    },
+    revert::ChangeCapture,
+    trace::DecisionTrace,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlparser::ast::Statement;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Instant,
 };
-use std::sync::Arc;
 use uuid::Uuid;
 
 #[derive(Clone, Default)]
 pub struct QueryExecutor {
     engine: QueryEngine,
+    metrics: Option<Arc<Metrics>>,
+    /// Schema read by the last planned write, reused for remediation hints so
+    /// denials don't read it again.
+    schema: Arc<Mutex<Option<SchemaSnapshot>>>,
 }
 
 #[derive(Clone, Debug)]
 pub struct ExecutedQuery {
     pub preview: PreviewResponse,
     pub rewritten_sql: String,
+    pub row_limit: Option<RowLimit>,
+    pub fingerprint: Option<RowFingerprint>,
+    /// Rows changed by a commit, for reverting it later.
+    pub changes: Vec<ChangeCapture>,
+    pub trace: DecisionTrace,
+}
+
+#[derive(Clone, Debug)]
+pub struct ExecutedChangeset {
+    pub preview: ChangesetPreviewResponse,
+    pub fingerprints: Vec<Option<RowFingerprint>>,
+    pub changes: Vec<ChangeCapture>,
+    /// Decision trace of each statement.
+    pub traces: Vec<DecisionTrace>,
+}
+
+/// Content hashes of the rows an UPDATE or DELETE touches, keyed by primary key.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct RowFingerprint {
+    pub rows: BTreeMap<String, String>,
+}
+
+impl RowFingerprint {
+    fn new(rows: &[serde_json::Value], primary_key: &[String]) -> Self {
+        let rows = rows
+            .iter()
+            .map(|row| {
+                let hash = Sha256::digest(row.to_string().as_bytes())
+                    .iter()
+                    .take(8)
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect::<String>();
+                let key = if primary_key.is_empty() {
+                    hash.clone()
+                } else {
+                    let values: Vec<&serde_json::Value> = primary_key
+                        .iter()
+                        .map(|column| &row[column.as_str()])
+                        .collect();
+                    serde_json::to_string(&values).unwrap_or_default()
+                };
+                (key, hash)
+            })
+            .collect();
+        Self { rows }
+    }
+
+    /// Number of rows added, removed or modified between the two fingerprints.
+    pub fn changed_rows(&self, other: &RowFingerprint) -> u64 {
+        let changed = self
+            .rows
+            .iter()
+            .filter(|(key, hash)| other.rows.get(*key) != Some(*hash))
+            .count();
+        let added = other
+            .rows
+            .keys()
+            .filter(|key| !self.rows.contains_key(*key))
+            .count();
+        (changed + added) as u64
+    }
+}
+
+/// A statement that passed policy checks, ready to run against the database.
+struct PlannedStatement {
+    operation: String,
+    tables: Vec<String>,
+    sql: String,
+    params: SqlParams,
+    warnings: Vec<String>,
+    row_limit: Option<RowLimit>,
+    affected_rows: Option<AffectedRowsQuery>,
+    capture: Option<AffectedRowsQuery>,
+    primary_key: Vec<String>,
+    blob_columns: Vec<String>,
+    stale_row_tolerance: u64,
+    /// Serialized size of the result rows kept for the response.
+    max_result_bytes: usize,
+    trace: DecisionTrace,
+    explain: bool,
+    statement: Statement,
+}
+
+struct StatementOutcome {
+    rows_affected: u64,
+    rows: Vec<serde_json::Value>,
+    truncated: bool,
+    fingerprint: Option<RowFingerprint>,
+    change: Option<ChangeCapture>,
+    plan: Option<QueryPlan>,
+}
+
+impl PlannedStatement {
+    /// Explains the rewritten statement and rejects plans over `limits`. The
+    /// plan is only checked when a plan limit is set.
+    fn analyze(&self, tx: &dyn SqlTransaction, limits: &PlanLimits) -> ProxyResult<QueryPlan> {
+        let plan = QueryPlan::analyze(tx, &self.sql, &self.params, &self.statement, limits)
+            .map_err(ProxyError::db)?;
+        if limits.is_set() {
+            plan.check(limits)?;
+        }
+        Ok(plan)
+    }
+
+    /// Runs the statement and checks its row limit against the real count. When
+    /// `expected` is given, the affected rows must still match the preview.
+    fn run(
+        &self,
+        tx: &dyn SqlTransaction,
+        expected: Option<&RowFingerprint>,
+    ) -> ProxyResult<StatementOutcome> {
+        let before = match &self.affected_rows {
+            Some(affected) => tx
+                .query(&affected.sql, &params_used_by(&affected.sql, &self.params))
+                .map_err(ProxyError::db)?,
+            None => Vec::new(),
+        };
+        let fingerprint = self
+            .affected_rows
+            .as_ref()
+            .map(|_| RowFingerprint::new(&before, &self.primary_key));
+        if let (Some(expected), Some(current), Some(affected)) =
+            (expected, &fingerprint, &self.affected_rows)
+        {
+            let changed = expected.changed_rows(current);
+            if changed > self.stale_row_tolerance {
+                return Err(ProxyError::new(
+                    ErrorCode::StalePreview,
+                    format!(
+                        "{changed} affected rows in table '{}' changed since preview (tolerance {})",
+                        affected.table, self.stale_row_tolerance
+                    ),
+                )
+                .with_suggestion("Preview the query again and commit the new preview_id"));
+            }
+        }
+
+        let mut change = None;
+        let mut truncated = false;
+        let (rows_affected, rows) = if self.operation == "select" {
+            // Past the byte cap, rows are only read as far as the row limit needs.
+            let count_to = self
+                .row_limit
+                .as_ref()
+                .map_or(0, |limit| limit.max_rows.saturating_add(1));
+            let capped = tx
+                .query_capped(&self.sql, &self.params, self.max_result_bytes, count_to)
+                .map_err(ProxyError::db)?;
+            truncated = capped.truncated;
+            (capped.read, capped.rows)
+        } else if let Some(capture) = &self.capture {
+            let returned = tx
+                .query(&capture.sql, &self.params)
+                .map_err(ProxyError::db)?;
+            let rows_affected = returned.len() as u64;
+            let (before, after) = match self.operation.as_str() {
+                "delete" => (returned, Vec::new()),
+                _ => (before, returned),
+            };
+            change = Some(ChangeCapture {
+                table: capture.table.clone(),
+                operation: self.operation.clone(),
+                primary_key: self.primary_key.clone(),
+                blob_columns: self.blob_columns.clone(),
+                before,
+                after,
+            });
+            (rows_affected, Vec::new())
+        } else {
+            (
+                tx.execute(&self.sql, &self.params)
+                    .map_err(ProxyError::db)?,
+                Vec::new(),
+            )
+        };
+        if let Some(limit) = &self.row_limit {
+            limit.check(&self.operation, rows_affected)?;
+        }
+        Ok(StatementOutcome {
+            rows_affected,
+            rows,
+            truncated,
+            fingerprint,
+            change,
+            plan: None,
+        })
+    }
+
+    /// The preview response plus what a commit needs from the run; `changes`
+    /// is left for the caller to fill in.
+    fn into_executed(self, outcome: Option<StatementOutcome>) -> ExecutedQuery {
+        let rewritten_sql = self.sql.clone();
+        let row_limit = self.row_limit.clone();
+        let trace = self.trace.clone();
+        let fingerprint = outcome
+            .as_ref()
+            .and_then(|outcome| outcome.fingerprint.clone());
+        ExecutedQuery {
+            preview: self.into_preview(Uuid::new_v4().to_string(), outcome),
+            rewritten_sql,
+            row_limit,
+            fingerprint,
+            changes: Vec::new(),
+            trace,
+        }
+    }
+
+    fn into_preview(
+        self,
+        preview_id: String,
+        outcome: Option<StatementOutcome>,
+    ) -> PreviewResponse {
+        let mut warnings = self.warnings;
+        let (rows_affected, rows, truncated, plan) = match outcome {
+            Some(outcome) => (
+                outcome.rows_affected,
+                outcome.rows,
+                outcome.truncated,
+                outcome.plan,
+            ),
+            None => {
+                warnings
+                    .push("Preview executed in dry-run mode; no database configured".to_string());
+                (0, Vec::new(), false, None)
+            }
+        };
+        if truncated {
+            warnings.push(format!(
+                "Result truncated to {} rows to stay under {} bytes; rows_affected counts only the rows read",
+                rows.len(),
+                self.max_result_bytes
+            ));
+        }
+
+        PreviewResponse {
+            ok: true,
+            preview_id,
+            operation: self.operation,
+            tables: self.tables,
+            rows_affected,
+            rewritten_sql: self.sql,
+            warnings,
+            rows,
+            truncated,
+            trace: self.explain.then_some(self.trace),
+            plan,
+        }
+    }
 }
 
 impl QueryExecutor {
     pub fn new(engine: QueryEngine) -> Self {
-        Self { engine }
+        Self {
+            engine,
+            metrics: None,
+            schema: Arc::default(),
+        }
+    }
+
+    /// Records the time spent in database transactions to `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    fn describe_schema(&self, db: &Arc<dyn SQLDB>) -> ProxyResult<SchemaSnapshot> {
+        let schema = db.describe_schema().map_err(ProxyError::db)?;
+        if let Ok(mut cached) = self.schema.lock() {
+            *cached = Some(schema.clone());
+        }
+        Ok(schema)
+    }
+
+    /// The cached schema, read from `db` only before any write was planned.
+    fn cached_schema(&self, db: &Arc<dyn SQLDB>) -> Option<SchemaSnapshot> {
+        let cached = self.schema.lock().ok()?.clone();
+        cached.or_else(|| self.describe_schema(db).ok())
     }
 
+    fn timed<T>(&self, phase: &'static str, work: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let output = work();
+        if let Some(metrics) = &self.metrics {
+            metrics.observe(
+                "agentproxy_db_duration_seconds",
+                &[("phase", phase)],
+                started.elapsed().as_secs_f64(),
+            );
+        }
+        output
+    }
+
+    /// Checks the request against policy and, with a database configured, runs it
+    /// in a rolled-back transaction to report the rows it would touch.
     pub fn preview(
         &self,
         payload: &SqlRequest,
         policy: &PolicyConfig,
         db: Option<&Arc<dyn SQLDB>>,
-    ) -> Result<ExecutedQuery, String> {
-        let (parsed, rewritten_sql) = self.engine.evaluate_sql(payload)?;
-        self.engine.enforce_rules(payload, &parsed)?;
-        self.engine.enforce_policy(payload, &parsed, policy)?;
-
-        let preview_id = Uuid::new_v4().to_string();
-        let preview = PreviewResponse {
-            ok: true,
-            preview_id,
-            operation: parsed.operation,
-            tables: parsed.tables,
-            rows_affected: 0,
-            rewritten_sql: rewritten_sql.clone(),
-            warnings: if db.is_some() {
-                Vec::new()
-            } else {
-                vec!["Preview executed in dry-run mode; no database configured".to_string()]
-            },
+    ) -> ProxyResult<ExecutedQuery> {
+        let planned = self.plan(payload, policy, db)?;
+        let outcome = match db {
+            Some(db) => Some(self.timed("preview", || {
+                with_transaction(db.as_ref(), TransactionMode::Rollback, |tx| {
+                    let plan = planned.analyze(tx, &policy.limits.plan)?;
+                    let mut outcome = planned.run(tx, None)?;
+                    outcome.plan = Some(plan);
+                    Ok::<_, ProxyError>(outcome)
+                })
+            })?),
+            None => None,
         };
 
-        Ok(ExecutedQuery {
-            preview,
-            rewritten_sql,
-        })
+        Ok(planned.into_executed(outcome))
     }
 
+    /// Checks the request, then executes it in a committed transaction. The row
+    /// limit is checked again against the real count and rolls the change back.
     pub fn commit(
         &self,
         payload: &SqlRequest,
         policy: &PolicyConfig,
         db: Option<&Arc<dyn SQLDB>>,
-    ) -> Result<ExecutedQuery, String> {
-        let mut executed = self.preview(payload, policy, db)?;
-        if let Some(db) = db {
-            let rows = db.execute(&executed.rewritten_sql)?;
-            executed.preview.rows_affected = rows;
-        }
+    ) -> ProxyResult<ExecutedQuery> {
+        self.commit_previewed(payload, policy, db, None)
+    }
+
+    /// Commits like [`QueryExecutor::commit`], first failing with `stale_preview`
+    /// when the affected rows no longer match the fingerprint of an earlier preview.
+    /// The fingerprint is checked inside the commit transaction; the plan is not
+    /// analyzed again.
+    pub fn commit_previewed(
+        &self,
+        payload: &SqlRequest,
+        policy: &PolicyConfig,
+        db: Option<&Arc<dyn SQLDB>>,
+        expected: Option<&RowFingerprint>,
+    ) -> ProxyResult<ExecutedQuery> {
+        let planned = self.plan(payload, policy, db)?;
+        let mut outcome = match db {
+            // A SELECT has nothing to commit, so it reads in a rolled-back transaction.
+            Some(db) if planned.operation == "select" => Some(self.timed("preview", || {
+                with_transaction(db.as_ref(), TransactionMode::Rollback, |tx| {
+                    planned.run(tx, expected)
+                })
+            })?),
+            Some(db) => Some(self.timed("commit", || {
+                with_transaction(db.as_ref(), TransactionMode::Commit, |tx| {
+                    planned.run(tx, expected)
+                })
+            })?),
+            None => None,
+        };
+        let changes = outcome
+            .as_mut()
+            .and_then(|outcome| outcome.change.take())
+            .into_iter()
+            .collect();
 
+        let mut executed = planned.into_executed(outcome);
+        executed.changes = changes;
         Ok(executed)
     }
+
+    /// Checks every statement of the changeset individually, then runs them all in
+    /// one rolled-back transaction so later statements see earlier changes.
+    pub fn preview_changeset(
+        &self,
+        request: &ChangesetRequest,
+        policy: &PolicyConfig,
+        db: Option<&Arc<dyn SQLDB>>,
+    ) -> ProxyResult<ExecutedChangeset> {
+        let plans = self.plan_changeset(request, policy, db)?;
+        let outcomes: Vec<Option<StatementOutcome>> = match db {
+            Some(db) => self
+                .timed("preview", || {
+                    with_transaction(db.as_ref(), TransactionMode::Rollback, |tx| {
+                        let analyzed = plans
+                            .iter()
+                            .enumerate()
+                            .map(|(index, planned)| {
+                                planned
+                                    .analyze(tx, &policy.limits.plan)
+                                    .map_err(|error| error.in_statement(index))
+                            })
+                            .collect::<ProxyResult<Vec<_>>>()?;
+                        let mut outcomes = run_all(&plans, tx, None)?;
+                        for (outcome, plan) in outcomes.iter_mut().zip(analyzed) {
+                            outcome.plan = Some(plan);
+                        }
+                        Ok::<_, ProxyError>(outcomes)
+                    })
+                })?
+                .into_iter()
+                .map(Some)
+                .collect(),
+            None => plans.iter().map(|_| None).collect(),
+        };
+
+        Ok(executed_changeset(plans, outcomes))
+    }
+
+    /// Checks the changeset, then executes every statement in a single committed
+    /// transaction; any failure or exceeded row limit rolls back all of them.
+    pub fn commit_changeset(
+        &self,
+        request: &ChangesetRequest,
+        policy: &PolicyConfig,
+        db: Option<&Arc<dyn SQLDB>>,
+    ) -> ProxyResult<ExecutedChangeset> {
+        self.commit_changeset_previewed(request, policy, db, None)
+    }
+
+    /// Commits like [`QueryExecutor::commit_changeset`], first failing with
+    /// `stale_preview` when any statement's affected rows no longer match the
+    /// fingerprints of an earlier preview. The fingerprints are checked inside the
+    /// commit transaction; plans are not analyzed again.
+    pub fn commit_changeset_previewed(
+        &self,
+        request: &ChangesetRequest,
+        policy: &PolicyConfig,
+        db: Option<&Arc<dyn SQLDB>>,
+        expected: Option<&[Option<RowFingerprint>]>,
+    ) -> ProxyResult<ExecutedChangeset> {
+        let plans = self.plan_changeset(request, policy, db)?;
+        let mut outcomes: Vec<Option<StatementOutcome>> = match db {
+            Some(db) => self
+                .timed("commit", || {
+                    with_transaction(db.as_ref(), TransactionMode::Commit, |tx| {
+                        run_all(&plans, tx, expected)
+                    })
+                })?
+                .into_iter()
+                .map(Some)
+                .collect(),
+            None => plans.iter().map(|_| None).collect(),
+        };
+        let changes = outcomes
+            .iter_mut()
+            .filter_map(|outcome| outcome.as_mut()?.change.take())
+            .collect();
+
+        let mut executed = executed_changeset(plans, outcomes);
+        executed.changes = changes;
+        Ok(executed)
+    }
+
+    fn plan_changeset(
+        &self,
+        request: &ChangesetRequest,
+        policy: &PolicyConfig,
+        db: Option<&Arc<dyn SQLDB>>,
+    ) -> ProxyResult<Vec<PlannedStatement>> {
+        if request.statements.is_empty() {
+            return Err(ProxyError::invalid(
+                "Changeset must contain at least one statement",
+            ));
+        }
+
+        request
+            .to_requests()
+            .iter()
+            .enumerate()
+            .map(|(index, payload)| {
+                self.plan(payload, policy, db)
+                    .map_err(|error| error.in_statement(index))
+            })
+            .collect()
+    }
+
+    fn plan(
+        &self,
+        payload: &SqlRequest,
+        policy: &PolicyConfig,
+        db: Option<&Arc<dyn SQLDB>>,
+    ) -> ProxyResult<PlannedStatement> {
+        let (parsed, trace) = match self.engine.authorize(payload, policy) {
+            Ok(authorized) => authorized,
+            Err(error) => {
+                let schema = db.and_then(|db| self.cached_schema(db));
+                return Err(self
+                    .engine
+                    .remediate(payload, policy, schema.as_ref(), error));
+            }
+        };
+        let row_limit = self.engine.row_limit(payload, &parsed, policy);
+        let needs_schema = matches!(parsed.operation.as_str(), "insert" | "update" | "delete")
+            || !policy.masks_for_role(&payload.context.role).is_empty();
+        let schema = match db {
+            Some(db) if needs_schema => Some(self.describe_schema(db)?),
+            _ => None,
+        };
+        let rewritten = self
+            .engine
+            .rewrite(payload, &parsed, policy, schema.as_ref())?;
+        let target = rewritten
+            .capture
+            .as_ref()
+            .or(rewritten.affected_rows.as_ref());
+        let target_table = match (target, &schema) {
+            (Some(affected), Some(schema)) => schema
+                .tables
+                .iter()
+                .find(|table| table.name.eq_ignore_ascii_case(&affected.table)),
+            _ => None,
+        };
+        let columns_where = |keep: fn(&ColumnSchema) -> bool| -> Vec<String> {
+            target_table
+                .map(|table| {
+                    table
+                        .columns
+                        .iter()
+                        .filter(|column| keep(column))
+                        .map(|column| column.name.clone())
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let primary_key = columns_where(|column| column.primary_key);
+        // SQLite gives columns declared BLOB, or with no type, BLOB affinity.
+        let blob_columns = columns_where(|column| {
+            column.data_type.is_empty() || column.data_type.to_ascii_uppercase().contains("BLOB")
+        });
+
+        Ok(PlannedStatement {
+            operation: parsed.operation,
+            tables: parsed.tables,
+            sql: rewritten.sql,
+            params: payload.params.clone(),
+            warnings: rewritten.warnings,
+            row_limit,
+            affected_rows: rewritten.affected_rows,
+            capture: rewritten.capture,
+            primary_key,
+            blob_columns,
+            stale_row_tolerance: policy.limits.stale_row_tolerance,
+            max_result_bytes: policy.limits.max_result_bytes,
+            trace,
+            explain: payload.explain,
+            statement: parsed.statement,
+        })
+    }
+}
+
+/// Runs every statement of a changeset. `max_rows_affected` also holds for the
+/// rows a table loses or gains across all statements, so splitting a write
+/// into several statements does not raise the limit.
+/// Builds the changeset response from each statement's plan and, with a
+/// database configured, its outcome.
+fn executed_changeset(
+    plans: Vec<PlannedStatement>,
+    outcomes: Vec<Option<StatementOutcome>>,
+) -> ExecutedChangeset {
+    let preview_id = Uuid::new_v4().to_string();
+    let fingerprints = outcomes
+        .iter()
+        .map(|outcome| {
+            outcome
+                .as_ref()
+                .and_then(|outcome| outcome.fingerprint.clone())
+        })
+        .collect();
+    let traces = plans.iter().map(|planned| planned.trace.clone()).collect();
+    let statements: Vec<PreviewResponse> = plans
+        .into_iter()
+        .zip(outcomes)
+        .map(|(planned, outcome)| planned.into_preview(preview_id.clone(), outcome))
+        .collect();
+
+    ExecutedChangeset {
+        preview: ChangesetPreviewResponse {
+            ok: true,
+            rows_affected: statements
+                .iter()
+                .map(|statement| statement.rows_affected)
+                .sum(),
+            preview_id,
+            statements,
+        },
+        fingerprints,
+        changes: Vec::new(),
+        traces,
+    }
+}
+
+fn run_all(
+    plans: &[PlannedStatement],
+    tx: &dyn SqlTransaction,
+    expected: Option<&[Option<RowFingerprint>]>,
+) -> ProxyResult<Vec<StatementOutcome>> {
+    let mut totals: HashMap<&str, u64> = HashMap::new();
+    plans
+        .iter()
+        .enumerate()
+        .map(|(index, planned)| {
+            let expected = expected
+                .and_then(|expected| expected.get(index))
+                .and_then(Option::as_ref);
+            let outcome = planned
+                .run(tx, expected)
+                .map_err(|error| error.in_statement(index))?;
+            if planned.operation != "select"
+                && let Some(limit) = &planned.row_limit
+            {
+                let total = totals.entry(limit.table.as_str()).or_default();
+                *total += outcome.rows_affected;
+                limit
+                    .check(&planned.operation, *total)
+                    .map_err(|error| error.in_statement(index))?;
+            }
+            Ok(outcome)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::SqliteDb,
+        query_engine::{ChangesetStatement, QueryContext},
+    };
+    use std::collections::HashMap;
+
+    fn setup() -> (Arc<dyn SQLDB>, PolicyConfig) {
+        let db = SqliteDb::new(":memory:").unwrap();
+        db.execute("CREATE TABLE cart_items (id INTEGER PRIMARY KEY, tenant_id TEXT NOT NULL)")
+            .unwrap();
+        for id in 1..=3 {
+            db.execute(&format!(
+                "INSERT INTO cart_items (id, tenant_id) VALUES ({id}, 'acme')"
+            ))
+            .unwrap();
+        }
+        let policy =
+            serde_yaml::from_str("tables:\n  cart_items:\n    max_rows_affected: 2\n").unwrap();
+        (Arc::new(db), policy)
+    }
+
+    fn request(sql: &str) -> SqlRequest {
+        SqlRequest {
+            sql: sql.to_string(),
+            context: QueryContext {
+                actor: "agent:test".to_string(),
+                tenant_id: "acme".to_string(),
+                role: String::new(),
+                client_ip: None,
+                attributes: HashMap::new(),
+            },
+            params: SqlParams::default(),
+            preview_id: None,
+            idempotency_key: None,
+            explain: false,
+        }
+    }
+
+    #[test]
+    fn preview_reports_rows_without_changing_data() {
+        let (db, policy) = setup();
+        let executor = QueryExecutor::default();
+        let executed = executor
+            .preview(
+                &request("DELETE FROM cart_items WHERE tenant_id = 'acme' AND id < 3"),
+                &policy,
+                Some(&db),
+            )
+            .unwrap();
+        assert_eq!(executed.preview.rows_affected, 2);
+
+        let remaining = executor
+            .preview(
+                &request("SELECT * FROM cart_items WHERE tenant_id = 'acme'"),
+                &policy,
+                Some(&db),
+            )
+            .unwrap();
+        assert_eq!(remaining.preview.rows_affected, 3);
+    }
+
+    #[test]
+    fn rejects_changes_exceeding_max_rows_affected() {
+        let (db, policy) = setup();
+        let executor = QueryExecutor::default();
+        let error = executor
+            .commit(
+                &request("DELETE FROM cart_items WHERE tenant_id = 'acme'"),
+                &policy,
+                Some(&db),
+            )
+            .unwrap_err();
+        assert!(error.message.contains("limit of 2"));
+
+        let remaining = executor
+            .preview(
+                &request("SELECT * FROM cart_items WHERE tenant_id = 'acme'"),
+                &policy,
+                Some(&db),
+            )
+            .unwrap();
+        assert_eq!(remaining.preview.rows_affected, 3);
+    }
+
+    /// Runs `statement` outside the proxy just before each committing
+    /// transaction, like a concurrent writer between the preview and commit.
+    struct ConcurrentWriter {
+        db: SqliteDb,
+        statement: &'static str,
+    }
+
+    impl SQLDB for ConcurrentWriter {
+        fn execute(&self, sql: &str) -> Result<u64, String> {
+            self.db.execute(sql)
+        }
+
+        fn transaction(
+            &self,
+            mode: TransactionMode,
+            work: &mut dyn FnMut(&dyn SqlTransaction) -> Result<(), String>,
+        ) -> Result<(), String> {
+            if mode == TransactionMode::Commit {
+                self.db.execute(self.statement)?;
+            }
+            self.db.transaction(mode, work)
+        }
+
+        fn describe_schema(&self) -> Result<crate::db::SchemaSnapshot, String> {
+            self.db.describe_schema()
+        }
+    }
+
+    #[test]
+    fn rechecks_max_rows_affected_in_the_commit_transaction() {
+        let (_, policy) = setup();
+        let db = SqliteDb::new(":memory:").unwrap();
+        db.execute("CREATE TABLE cart_items (id INTEGER PRIMARY KEY, tenant_id TEXT NOT NULL)")
+            .unwrap();
+        db.execute("INSERT INTO cart_items (id, tenant_id) VALUES (1, 'acme'), (2, 'acme')")
+            .unwrap();
+        let db: Arc<dyn SQLDB> = Arc::new(ConcurrentWriter {
+            db,
+            statement: "INSERT OR IGNORE INTO cart_items (id, tenant_id) VALUES (3, 'acme')",
+        });
+        let executor = QueryExecutor::default();
+        let payload = request("DELETE FROM cart_items WHERE tenant_id = 'acme'");
+
+        // The preview pass sees two rows; the commit pass sees the third.
+        let error = executor.commit(&payload, &policy, Some(&db)).unwrap_err();
+        assert!(error.message.contains("limit of 2"));
+
+        let remaining = executor
+            .preview(
+                &request("SELECT * FROM cart_items WHERE tenant_id = 'acme'"),
+                &policy,
+                Some(&db),
+            )
+            .unwrap();
+        assert_eq!(remaining.preview.rows_affected, 3);
+    }
+
+    struct SchemaReads {
+        db: SqliteDb,
+        reads: std::sync::atomic::AtomicUsize,
+    }
+
+    impl SQLDB for SchemaReads {
+        fn execute(&self, sql: &str) -> Result<u64, String> {
+            self.db.execute(sql)
+        }
+
+        fn transaction(
+            &self,
+            mode: TransactionMode,
+            work: &mut dyn FnMut(&dyn SqlTransaction) -> Result<(), String>,
+        ) -> Result<(), String> {
+            self.db.transaction(mode, work)
+        }
+
+        fn describe_schema(&self) -> Result<SchemaSnapshot, String> {
+            self.reads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.db.describe_schema()
+        }
+    }
+
+    #[test]
+    fn reuses_the_schema_for_denial_hints() {
+        let (_, policy) = setup();
+        let db = SqliteDb::new(":memory:").unwrap();
+        db.execute("CREATE TABLE cart_items (id INTEGER PRIMARY KEY, tenant_id TEXT NOT NULL)")
+            .unwrap();
+        let counted = Arc::new(SchemaReads {
+            db,
+            reads: Default::default(),
+        });
+        let db: Arc<dyn SQLDB> = counted.clone();
+        let executor = QueryExecutor::default();
+
+        executor
+            .preview(
+                &request("DELETE FROM cart_items WHERE tenant_id = 'acme' AND id = 1"),
+                &policy,
+                Some(&db),
+            )
+            .unwrap();
+        for _ in 0..2 {
+            executor
+                .preview(&request("DELETE FROM cart_items"), &policy, Some(&db))
+                .unwrap_err();
+        }
+        assert_eq!(counted.reads.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn attaches_plans_and_counts_rows_only_when_a_plan_limit_is_set() {
+        let (db, mut policy) = setup();
+        let payload = request("SELECT * FROM cart_items WHERE tenant_id = 'acme'");
+        let executor = QueryExecutor::default();
+        let plan = executor
+            .preview(&payload, &policy, Some(&db))
+            .unwrap()
+            .preview
+            .plan
+            .unwrap();
+        assert!(!plan.steps.is_empty());
+        assert_eq!(plan.estimated_cost, 0);
+
+        policy.limits.plan.max_full_scans = Some(1);
+        let plan = executor
+            .preview(&payload, &policy, Some(&db))
+            .unwrap()
+            .preview
+            .plan
+            .unwrap();
+        assert_eq!(plan.estimated_cost, 3);
+
+        // Commits reuse the preview's checks and skip plan analysis.
+        let payload = request("DELETE FROM cart_items WHERE tenant_id = 'acme' AND id = 1");
+        let executed = executor.commit(&payload, &policy, Some(&db)).unwrap();
+        assert_eq!(executed.preview.rows_affected, 1);
+        assert!(executed.preview.plan.is_none());
+    }
+
+    #[test]
+    fn caps_serialized_result_size() {
+        let (db, mut policy) = setup();
+        policy.limits.max_result_bytes = 60;
+        let executed = QueryExecutor::default()
+            .preview(
+                &request("SELECT * FROM cart_items WHERE tenant_id = 'acme'"),
+                &policy,
+                Some(&db),
+            )
+            .unwrap();
+        assert_eq!(executed.preview.rows.len(), 2);
+        assert!(executed.preview.truncated);
+        assert!(executed.preview.warnings[0].contains("truncated"));
+        // Reading stops at the cap, so the overflowing row is the last one read.
+        assert_eq!(executed.preview.rows_affected, 3);
+
+        let mut limited: PolicyConfig =
+            serde_yaml::from_str("tables:\n  cart_items:\n    max_rows_returned: 2\n").unwrap();
+        limited.limits.max_result_bytes = 30;
+        let err = QueryExecutor::default()
+            .preview(
+                &request("SELECT * FROM cart_items WHERE tenant_id = 'acme'"),
+                &limited,
+                Some(&db),
+            )
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::LimitExceeded);
+    }
+
+    #[test]
+    fn masks_hashed_columns_in_results() {
+        let (db, _) = setup();
+        db.execute("ALTER TABLE cart_items ADD COLUMN note TEXT NOT NULL DEFAULT 'secret'")
+            .unwrap();
+        let policy = serde_yaml::from_str(
+            "tables:\n  cart_items:\n    mask_columns:\n      - column: note\n        mask: hash\n",
+        )
+        .unwrap();
+        let executed = QueryExecutor::default()
+            .preview(
+                &request("SELECT * FROM cart_items WHERE tenant_id = 'acme' AND id = 1"),
+                &policy,
+                Some(&db),
+            )
+            .unwrap();
+        let note = executed.preview.rows[0]["note"].as_str().unwrap();
+        assert_eq!(note.len(), 16);
+        assert_ne!(note, "secret");
+    }
+
+    #[test]
+    fn commits_with_bind_parameters() {
+        let (db, policy) = setup();
+        let executor = QueryExecutor::default();
+        let mut payload = request("DELETE FROM cart_items WHERE tenant_id = $1 AND id = $2");
+        payload.params = SqlParams::Positional(vec!["acme".into(), 2.into()]);
+        let executed = executor.commit(&payload, &policy, Some(&db)).unwrap();
+        assert_eq!(executed.preview.rows_affected, 1);
+
+        let mut payload = request("SELECT id FROM cart_items WHERE tenant_id = :tenant");
+        payload.params = SqlParams::Named(HashMap::from([("tenant".to_string(), "acme".into())]));
+        let executed = executor.preview(&payload, &policy, Some(&db)).unwrap();
+        assert_eq!(executed.preview.rows.len(), 2);
+
+        // The affected-rows SELECT derived from the UPDATE drops `:new_id`.
+        let mut payload =
+            request("UPDATE cart_items SET id = :new_id WHERE tenant_id = 'acme' AND id = :id");
+        payload.params = SqlParams::Named(HashMap::from([
+            ("new_id".to_string(), 10.into()),
+            ("id".to_string(), 1.into()),
+        ]));
+        let executed = executor.commit(&payload, &policy, Some(&db)).unwrap();
+        assert_eq!(executed.preview.rows_affected, 1);
+
+        // Keys bind with or without their prefix, including `@`.
+        let mut payload =
+            request("UPDATE cart_items SET id = :new_id WHERE tenant_id = 'acme' AND id = $id");
+        payload.params = SqlParams::Named(HashMap::from([
+            ("@new_id".to_string(), 11.into()),
+            ("@id".to_string(), 10.into()),
+        ]));
+        let executed = executor.commit(&payload, &policy, Some(&db)).unwrap();
+        assert_eq!(executed.preview.rows_affected, 1);
+
+        let params = SqlParams::Named(HashMap::from([("tenant".to_string(), "acme".into())]));
+        let error = with_transaction(db.as_ref(), TransactionMode::Rollback, |tx| {
+            tx.query("SELECT id FROM cart_items", &params)
+                .map_err(ProxyError::db)
+        })
+        .unwrap_err();
+        assert_eq!(error.code, ErrorCode::DbError);
+        assert!(error.message.contains("'tenant' is not used"));
+    }
+
+    fn changeset(statements: &[&str]) -> ChangesetRequest {
+        ChangesetRequest {
+            statements: statements
+                .iter()
+                .map(|sql| ChangesetStatement {
+                    sql: sql.to_string(),
+                    params: SqlParams::default(),
+                })
+                .collect(),
+            context: request("").context,
+            preview_id: None,
+            idempotency_key: None,
+            explain: false,
+        }
+    }
+
+    #[test]
+    fn commits_changesets_atomically() {
+        let (db, policy) = setup();
+        let executor = QueryExecutor::default();
+
+        let preview = executor
+            .preview_changeset(
+                &changeset(&[
+                    "INSERT INTO cart_items (id, tenant_id) VALUES (4, 'acme')",
+                    "DELETE FROM cart_items WHERE tenant_id = 'acme' AND id >= 4",
+                ]),
+                &policy,
+                Some(&db),
+            )
+            .unwrap();
+        assert_eq!(preview.preview.statements.len(), 2);
+        assert_eq!(preview.preview.statements[1].rows_affected, 1);
+        assert_eq!(preview.preview.rows_affected, 2);
+
+        // Each DELETE is within max_rows_affected, but together they are not.
+        let error = executor
+            .commit_changeset(
+                &changeset(&[
+                    "DELETE FROM cart_items WHERE tenant_id = 'acme' AND id = 1",
+                    "DELETE FROM cart_items WHERE tenant_id = 'acme' AND id = 2",
+                    "DELETE FROM cart_items WHERE tenant_id = 'acme' AND id = 3",
+                ]),
+                &policy,
+                Some(&db),
+            )
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::LimitExceeded);
+        assert_eq!(error.statement, Some(3));
+
+        let error = executor
+            .commit_changeset(
+                &changeset(&[
+                    "INSERT INTO cart_items (id, tenant_id) VALUES (4, 'acme')",
+                    "INSERT INTO cart_items (id, tenant_id) VALUES (4, 'acme')",
+                ]),
+                &policy,
+                Some(&db),
+            )
+            .unwrap_err();
+        assert!(error.message.starts_with("Statement 2"));
+        assert_eq!(error.statement, Some(2));
+
+        let remaining = executor
+            .preview(
+                &request("SELECT * FROM cart_items WHERE tenant_id = 'acme'"),
+                &policy,
+                Some(&db),
+            )
+            .unwrap();
+        assert_eq!(remaining.preview.rows_affected, 3);
+    }
+
+    #[test]
+    fn rejects_commits_whose_affected_rows_drifted() {
+        let (db, mut policy) = setup();
+        let executor = QueryExecutor::default();
+        let payload = request("DELETE FROM cart_items WHERE tenant_id = 'acme' AND id <= 2");
+
+        let preview = executor.preview(&payload, &policy, Some(&db)).unwrap();
+        let fingerprint = preview.fingerprint.unwrap();
+        assert_eq!(fingerprint.rows.len(), 2);
+
+        db.execute("UPDATE cart_items SET tenant_id = 'other' WHERE id = 1")
+            .unwrap();
+        let error = executor
+            .commit_previewed(&payload, &policy, Some(&db), Some(&fingerprint))
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::StalePreview);
+        assert!(error.message.starts_with("1 affected rows"));
+
+        policy.limits.stale_row_tolerance = 1;
+        let committed = executor
+            .commit_previewed(&payload, &policy, Some(&db), Some(&fingerprint))
+            .unwrap();
+        assert_eq!(committed.preview.rows_affected, 1);
+    }
 }
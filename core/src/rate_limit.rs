@@ -0,0 +1,428 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+use crate::{
+    error::{ErrorCode, ProxyError, ProxyResult, RuleRef},
+    policy::{RateLimitRule, RateScope},
+    query_engine::{QueryContext, glob_matches},
+};
+
+/// What a rate limit counts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateKind {
+    Previews,
+    Commits,
+    RowsWritten,
+}
+
+impl RateKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RateKind::Previews => "previews",
+            RateKind::Commits => "commits",
+            RateKind::RowsWritten => "rows_written",
+        }
+    }
+}
+
+/// Rule index, actor/role/tenant value and kind of a bucket.
+type BucketKey = (usize, String, RateKind);
+
+/// Buckets kept before idle ones are dropped to make room for new keys.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Key of the bucket a rule's new keys share once there is no room for them,
+/// so rotating the actor, role or tenant does not escape the limit.
+const OVERFLOW_KEY: &str = "*";
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    used: u64,
+    limit: u64,
+    window_secs: u64,
+}
+
+impl Bucket {
+    /// Whether the bucket has refilled completely, so dropping it changes nothing
+    /// but its `used` count.
+    fn idle(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let rate = self.limit as f64 / self.window_secs.max(1) as f64;
+        self.tokens + elapsed * rate >= self.limit as f64
+    }
+}
+
+/// Token buckets for the `rate_limits` of a policy, shared by the HTTP
+/// service and the MCP server.
+pub struct RateLimiter {
+    rules: Vec<RateLimitRule>,
+    buckets: Mutex<HashMap<BucketKey, Bucket>>,
+    max_buckets: usize,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct UsageRequest {
+    #[serde(default)]
+    pub scope: Option<RateScope>,
+    /// Actor, role or tenant id.
+    #[serde(default)]
+    pub key: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct UsageResponse {
+    pub ok: bool,
+    pub buckets: Vec<RateUsage>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RateUsage {
+    pub scope: RateScope,
+    pub key: String,
+    pub kind: RateKind,
+    /// Path of the limit in the policy file, such as `rate_limits[0].commits`.
+    pub source: String,
+    pub limit: u64,
+    pub window_secs: u64,
+    /// Tokens left; negative after a commit wrote more rows than were left.
+    pub remaining: i64,
+    /// Tokens taken since the bucket was created; idle buckets may be dropped.
+    pub used: u64,
+    #[serde(default)]
+    pub retry_after: Option<u64>,
+}
+
+impl RateLimiter {
+    pub fn new(rules: Vec<RateLimitRule>) -> Self {
+        Self {
+            rules,
+            buckets: Mutex::new(HashMap::new()),
+            max_buckets: MAX_BUCKETS,
+        }
+    }
+
+    /// Takes `cost` tokens per kind from every bucket matching the context, or
+    /// none at all when one of them is used up. A cost of 0 only checks that
+    /// the bucket is not exhausted, as commits do for rows written before the
+    /// row count is known.
+    pub fn acquire(&self, context: &QueryContext, costs: &[(RateKind, u64)]) -> ProxyResult<()> {
+        self.acquire_at(context, costs, Instant::now())
+    }
+
+    /// Takes `amount` tokens regardless of what is left, so the next request
+    /// waits for the debt to be refilled.
+    pub fn charge(&self, context: &QueryContext, kind: RateKind, amount: u64) {
+        self.charge_at(context, kind, amount, Instant::now());
+    }
+
+    pub fn usage(&self, request: &UsageRequest) -> Vec<RateUsage> {
+        self.usage_at(request, Instant::now())
+    }
+
+    fn acquire_at(
+        &self,
+        context: &QueryContext,
+        costs: &[(RateKind, u64)],
+        now: Instant,
+    ) -> ProxyResult<()> {
+        let mut buckets = self
+            .buckets
+            .lock()
+            .map_err(|_| ProxyError::internal("Rate limiter lock poisoned"))?;
+        let matched: Vec<_> = costs
+            .iter()
+            .flat_map(|(kind, cost)| {
+                self.matching(context, *kind)
+                    .map(move |(key, limit, window)| (key, limit, window, *cost))
+            })
+            .collect();
+        let matched: Vec<_> = matched
+            .into_iter()
+            .map(|(key, limit, window, cost)| {
+                (self.slot(&mut buckets, key, now), limit, window, cost)
+            })
+            .collect();
+
+        let mut exhausted: Option<(f64, &BucketKey, u64, u64)> = None;
+        for (key, limit, window, _) in &matched {
+            let bucket = refill(&mut buckets, key, *limit, *window, now);
+            if bucket.tokens < 1.0 {
+                let wait = (1.0 - bucket.tokens) * *window as f64 / *limit as f64;
+                if exhausted.is_none_or(|(longest, ..)| wait > longest) {
+                    exhausted = Some((wait, key, *limit, *window));
+                }
+            }
+        }
+        if let Some((wait, (index, value, kind), limit, window)) = exhausted {
+            let rule = &self.rules[*index];
+            let retry_after = wait.ceil().max(1.0) as u64;
+            let mut error = ProxyError::new(
+                ErrorCode::RateLimited,
+                format!(
+                    "Rate limit of {limit} {} per {window}s exceeded for {} '{value}'",
+                    kind.as_str(),
+                    rule.scope.as_str()
+                ),
+            )
+            .with_suggestion(format!("Retry after {retry_after} seconds"));
+            error.rule = Some(Box::new(RuleRef {
+                rule: kind.as_str().to_string(),
+                source: format!("rate_limits[{index}].{}", kind.as_str()),
+                table: None,
+            }));
+            error.retry_after = Some(retry_after);
+            return Err(error);
+        }
+
+        for (key, _, _, cost) in matched {
+            if let Some(bucket) = buckets.get_mut(&key) {
+                bucket.tokens -= cost as f64;
+                bucket.used += cost;
+            }
+        }
+        Ok(())
+    }
+
+    fn charge_at(&self, context: &QueryContext, kind: RateKind, amount: u64, now: Instant) {
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return;
+        };
+        for (key, limit, window) in self.matching(context, kind) {
+            let key = self.slot(&mut buckets, key, now);
+            let bucket = refill(&mut buckets, &key, limit, window, now);
+            bucket.tokens -= amount as f64;
+            bucket.used += amount;
+        }
+    }
+
+    fn usage_at(&self, request: &UsageRequest, now: Instant) -> Vec<RateUsage> {
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return Vec::new();
+        };
+        let keys: Vec<BucketKey> = buckets
+            .keys()
+            .filter(|(index, value, _)| {
+                let scope = self.rules[*index].scope;
+                request.scope.is_none_or(|wanted| wanted == scope)
+                    && request.key.as_ref().is_none_or(|wanted| wanted == value)
+            })
+            .cloned()
+            .collect();
+
+        let mut usage: Vec<RateUsage> = keys
+            .into_iter()
+            .filter_map(|key| {
+                let rule = &self.rules[key.0];
+                let limit = limit_for(rule, key.2)?;
+                let bucket = refill(&mut buckets, &key, limit, rule.window_secs, now);
+                let (index, value, kind) = key;
+                Some(RateUsage {
+                    scope: rule.scope,
+                    key: value,
+                    kind,
+                    source: format!("rate_limits[{index}].{}", kind.as_str()),
+                    limit,
+                    window_secs: rule.window_secs,
+                    remaining: bucket.tokens.floor() as i64,
+                    used: bucket.used,
+                    retry_after: (bucket.tokens < 1.0).then(|| {
+                        ((1.0 - bucket.tokens) * rule.window_secs as f64 / limit as f64)
+                            .ceil()
+                            .max(1.0) as u64
+                    }),
+                })
+            })
+            .collect();
+        usage.sort_by(|a, b| a.source.cmp(&b.source).then_with(|| a.key.cmp(&b.key)));
+        usage
+    }
+
+    /// The bucket to use for `key`: its own when it has one or there is room
+    /// for it after dropping idle buckets, else the rule's overflow bucket.
+    fn slot(
+        &self,
+        buckets: &mut HashMap<BucketKey, Bucket>,
+        key: BucketKey,
+        now: Instant,
+    ) -> BucketKey {
+        if buckets.contains_key(&key) || buckets.len() < self.max_buckets {
+            return key;
+        }
+        buckets.retain(|_, bucket| !bucket.idle(now));
+        if buckets.len() < self.max_buckets {
+            key
+        } else {
+            (key.0, OVERFLOW_KEY.to_string(), key.2)
+        }
+    }
+
+    /// Buckets of every rule that limits `kind` for the context, with the
+    /// limit and window of the rule.
+    fn matching<'a>(
+        &'a self,
+        context: &'a QueryContext,
+        kind: RateKind,
+    ) -> impl Iterator<Item = (BucketKey, u64, u64)> + 'a {
+        self.rules
+            .iter()
+            .enumerate()
+            .filter_map(move |(index, rule)| {
+                let limit = limit_for(rule, kind)?;
+                let value = match rule.scope {
+                    RateScope::Actor => &context.actor,
+                    RateScope::Role => &context.role,
+                    RateScope::Tenant => &context.tenant_id,
+                };
+                if !rule
+                    .pattern
+                    .as_deref()
+                    .is_none_or(|pattern| glob_matches(pattern, value))
+                {
+                    return None;
+                }
+                Some(((index, value.clone(), kind), limit, rule.window_secs))
+            })
+    }
+}
+
+fn limit_for(rule: &RateLimitRule, kind: RateKind) -> Option<u64> {
+    match kind {
+        RateKind::Previews => rule.previews,
+        RateKind::Commits => rule.commits,
+        RateKind::RowsWritten => rule.rows_written,
+    }
+    .filter(|limit| *limit > 0)
+}
+
+/// Returns the bucket for `key`, full when new, topped up for the time since
+/// its last update.
+fn refill<'a>(
+    buckets: &'a mut HashMap<BucketKey, Bucket>,
+    key: &BucketKey,
+    limit: u64,
+    window_secs: u64,
+    now: Instant,
+) -> &'a mut Bucket {
+    let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+        tokens: limit as f64,
+        updated: now,
+        used: 0,
+        limit,
+        window_secs,
+    });
+    let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+    let rate = limit as f64 / window_secs.max(1) as f64;
+    bucket.tokens = (bucket.tokens + elapsed * rate).min(limit as f64);
+    bucket.updated = now;
+    bucket
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn context(actor: &str) -> QueryContext {
+        QueryContext {
+            actor: actor.to_string(),
+            tenant_id: "acme".to_string(),
+            role: String::new(),
+            client_ip: None,
+            attributes: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn limits_per_key_and_refills_over_the_window() {
+        let rules: Vec<RateLimitRule> = serde_yaml::from_str(
+            r#"
+- scope: actor
+  pattern: "agent:*"
+  window_secs: 60
+  commits: 2
+  rows_written: 10
+"#,
+        )
+        .unwrap();
+        let limiter = RateLimiter::new(rules);
+        let start = Instant::now();
+        let agent = context("agent:a");
+        let commit = [(RateKind::Commits, 1), (RateKind::RowsWritten, 0)];
+
+        limiter.acquire_at(&agent, &commit, start).unwrap();
+        limiter.acquire_at(&agent, &commit, start).unwrap();
+        let error = limiter.acquire_at(&agent, &commit, start).unwrap_err();
+        assert_eq!(error.code, ErrorCode::RateLimited);
+        assert_eq!(error.retry_after, Some(30));
+        assert_eq!(error.rule.unwrap().source, "rate_limits[0].commits");
+
+        // Other actors have their own buckets, and unmatched ones none at all.
+        limiter
+            .acquire_at(&context("agent:b"), &commit, start)
+            .unwrap();
+        for _ in 0..5 {
+            limiter
+                .acquire_at(&context("human"), &commit, start)
+                .unwrap();
+        }
+
+        let later = start + Duration::from_secs(30);
+        limiter.acquire_at(&agent, &commit, later).unwrap();
+
+        limiter.charge_at(&agent, RateKind::RowsWritten, 25, later);
+        let error = limiter
+            .acquire_at(&agent, &[(RateKind::RowsWritten, 0)], later)
+            .unwrap_err();
+        assert_eq!(error.retry_after, Some(96));
+
+        let usage = limiter.usage_at(
+            &UsageRequest {
+                scope: Some(RateScope::Actor),
+                key: Some("agent:a".to_string()),
+            },
+            later,
+        );
+        let rows = usage
+            .iter()
+            .find(|usage| usage.kind == RateKind::RowsWritten)
+            .unwrap();
+        assert_eq!((rows.remaining, rows.used), (-15, 25));
+    }
+
+    #[test]
+    fn drops_idle_buckets_and_shares_one_when_full() {
+        let rules: Vec<RateLimitRule> = serde_yaml::from_str(
+            r#"
+- scope: actor
+  window_secs: 60
+  commits: 1
+"#,
+        )
+        .unwrap();
+        let mut limiter = RateLimiter::new(rules);
+        limiter.max_buckets = 2;
+        let start = Instant::now();
+        let commit = [(RateKind::Commits, 1)];
+
+        for actor in ["a", "b", "c"] {
+            limiter.acquire_at(&context(actor), &commit, start).unwrap();
+        }
+        // "c" took the shared bucket, so a fourth new actor is limited too.
+        let error = limiter
+            .acquire_at(&context("d"), &commit, start)
+            .unwrap_err();
+        assert!(error.message.contains("'*'"));
+
+        // Once the buckets have refilled they make room for new actors.
+        let later = start + Duration::from_secs(60);
+        limiter.acquire_at(&context("e"), &commit, later).unwrap();
+        let keys: Vec<_> = limiter
+            .usage_at(&UsageRequest::default(), later)
+            .into_iter()
+            .map(|usage| usage.key)
+            .collect();
+        assert_eq!(keys, vec!["e"]);
+    }
+}
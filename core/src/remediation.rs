@@ -0,0 +1,255 @@
+use crate::{
+    db::SchemaSnapshot,
+    error::{ProxyError, Remediation},
+    policy::PolicyConfig,
+    query_engine::{QueryEngine, SqlRequest},
+    rewrite::apply_remediation,
+};
+
+/// Rounds of fixes tried when building `suggested_sql`, since fixing one rule
+/// can reveal the next one the query breaks.
+const MAX_FIXES: usize = 4;
+
+/// Builds the remediation for a denial, or `None` when the failed rule has no
+/// machine-readable fix.
+pub(crate) fn remediate(
+    engine: &QueryEngine,
+    payload: &SqlRequest,
+    policy: &PolicyConfig,
+    schema: Option<&SchemaSnapshot>,
+    error: &ProxyError,
+) -> Option<Remediation> {
+    let mut remediation = hint(payload, policy, schema, error)?;
+
+    let mut candidate = payload.clone();
+    let mut fix = remediation.clone();
+    for _ in 0..MAX_FIXES {
+        if fix.add_predicate.is_none() && fix.remove_columns.is_empty() {
+            break;
+        }
+        let Ok(sql) = apply_remediation(
+            &candidate.sql,
+            fix.add_predicate.as_deref(),
+            &fix.remove_columns,
+            schema,
+        ) else {
+            break;
+        };
+        if sql == candidate.sql {
+            break;
+        }
+        candidate.sql = sql;
+
+        match engine.authorize(&candidate, policy) {
+            Ok(_) => {
+                remediation.suggested_sql = Some(candidate.sql);
+                break;
+            }
+            Err(next) => match hint(&candidate, policy, schema, &next) {
+                Some(next) => fix = next,
+                None => break,
+            },
+        }
+    }
+
+    Some(remediation)
+}
+
+fn hint(
+    payload: &SqlRequest,
+    policy: &PolicyConfig,
+    schema: Option<&SchemaSnapshot>,
+    error: &ProxyError,
+) -> Option<Remediation> {
+    let rule = error.rule.as_ref()?;
+    let context = &payload.context;
+    let mut remediation = Remediation::default();
+
+    if rule.rule == "tenant_filter" {
+        remediation.add_predicate = Some(format!("tenant_id = {}", quote(&context.tenant_id)));
+        return Some(remediation);
+    }
+
+    let table = rule.table.as_deref()?;
+    let (_, table_policy) = policy.resolve_table_policy(&context.role, table)?;
+    let schema_columns = schema
+        .and_then(|schema| {
+            schema
+                .tables
+                .iter()
+                .find(|candidate| candidate.name.eq_ignore_ascii_case(table))
+        })
+        .map(|table| {
+            table
+                .columns
+                .iter()
+                .map(|column| column.name.clone())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let without = |columns: Vec<String>, excluded: &[String]| {
+        columns
+            .into_iter()
+            .filter(|column| {
+                !excluded
+                    .iter()
+                    .any(|candidate| candidate.eq_ignore_ascii_case(column))
+            })
+            .collect::<Vec<_>>()
+    };
+
+    match rule.rule.as_str() {
+        "allow_ops" => remediation.allowed_operations = table_policy.allow_ops.clone(),
+        "writable_columns" => {
+            let writable = if table_policy.writable_columns.is_empty() {
+                schema_columns
+            } else {
+                table_policy.writable_columns.clone()
+            };
+            let is_update = QueryEngine
+                .evaluate_sql(payload)
+                .is_ok_and(|(parsed, _)| parsed.operation == "update");
+            remediation.allowed_columns = if is_update {
+                without(writable, &table_policy.immutable_columns)
+            } else {
+                writable
+            };
+        }
+        "required_filters" => {
+            let required = table_policy
+                .required_filters
+                .get(rule_index(&rule.source)?)?;
+            match context.attribute(&required.column) {
+                Some(value) if required.operator == "=" => {
+                    remediation.add_predicate =
+                        Some(format!("{} = {}", required.column, literal(&value)));
+                }
+                _ => remediation.filter_columns = vec![required.column.clone()],
+            }
+        }
+        "deny_columns" => {
+            remediation.remove_columns = table_policy
+                .deny_columns
+                .iter()
+                .filter(|column| payload.sql.contains(column.as_str()))
+                .cloned()
+                .collect();
+            remediation.allowed_columns = without(schema_columns, &table_policy.deny_columns);
+        }
+        _ => return None,
+    }
+    Some(remediation)
+}
+
+/// Index of the rule in a source path such as `tables.orders.required_filters[1]`.
+fn rule_index(source: &str) -> Option<usize> {
+    source.strip_suffix(']')?.rsplit_once('[')?.1.parse().ok()
+}
+
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
+
+fn literal(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(text) => quote(text),
+        serde_json::Value::Null => "NULL".to_string(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{ColumnSchema, TableSchema},
+        query_engine::QueryContext,
+    };
+
+    fn request(sql: &str) -> SqlRequest {
+        SqlRequest {
+            sql: sql.to_string(),
+            context: QueryContext {
+                actor: "agent".to_string(),
+                tenant_id: "acme".to_string(),
+                role: String::new(),
+                client_ip: None,
+                attributes: [("region".to_string(), serde_json::json!("eu"))].into(),
+            },
+            params: Default::default(),
+            preview_id: None,
+            idempotency_key: None,
+            explain: false,
+        }
+    }
+
+    fn remediation(sql: &str, policy: &PolicyConfig, schema: &SchemaSnapshot) -> Remediation {
+        let engine = QueryEngine;
+        let payload = request(sql);
+        let error = engine.authorize(&payload, policy).unwrap_err();
+        engine
+            .remediate(&payload, policy, Some(schema), error)
+            .remediation
+            .map(|remediation| *remediation)
+            .unwrap()
+    }
+
+    #[test]
+    fn suggests_predicates_columns_and_passing_sql() {
+        let policy: PolicyConfig = serde_yaml::from_str(
+            r#"
+tables:
+  users:
+    allow_ops: [select]
+    deny_columns: [ssn]
+    required_filters:
+      - column: region
+"#,
+        )
+        .unwrap();
+        let column = |name: &str| ColumnSchema {
+            name: name.to_string(),
+            data_type: "TEXT".to_string(),
+            nullable: true,
+            primary_key: name == "id",
+        };
+        let schema = SchemaSnapshot {
+            tables: vec![TableSchema {
+                name: "users".to_string(),
+                columns: vec![
+                    column("id"),
+                    column("tenant_id"),
+                    column("region"),
+                    column("ssn"),
+                ],
+            }],
+        };
+
+        let missing = remediation("SELECT id FROM users WHERE id = 1", &policy, &schema);
+        assert_eq!(missing.add_predicate.as_deref(), Some("tenant_id = 'acme'"));
+        assert_eq!(
+            missing.suggested_sql.as_deref(),
+            Some("SELECT id FROM users WHERE id = 1 AND tenant_id = 'acme' AND region = 'eu'")
+        );
+
+        let denied = remediation(
+            "SELECT id, ssn FROM users WHERE tenant_id = 'acme' AND region = 'eu'",
+            &policy,
+            &schema,
+        );
+        assert_eq!(denied.remove_columns, vec!["ssn"]);
+        assert_eq!(denied.allowed_columns, vec!["id", "tenant_id", "region"]);
+        assert_eq!(
+            denied.suggested_sql.as_deref(),
+            Some("SELECT id FROM users WHERE tenant_id = 'acme' AND region = 'eu'")
+        );
+
+        let write = remediation(
+            "DELETE FROM users WHERE tenant_id = 'acme'",
+            &policy,
+            &schema,
+        );
+        assert_eq!(write.allowed_operations, vec!["select"]);
+        assert_eq!(write.suggested_sql, None);
+    }
+}
@@ -0,0 +1,253 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    db::SQLDB,
+    error::ProxyResult,
+    policy::PolicyConfig,
+    query_engine::{ChangesetRequest, SqlRequest},
+    query_executor::QueryExecutor,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureKind {
+    Preview,
+    Commit,
+}
+
+/// Outcome of policy evaluation for a request. `rewritten_sql` is only
+/// recorded for previews.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Decision {
+    pub allowed: bool,
+    #[serde(default)]
+    pub rewritten_sql: Option<String>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// A captured single statement or changeset.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum CapturedPayload {
+    Sql(SqlRequest),
+    Changeset(ChangesetRequest),
+}
+
+impl CapturedPayload {
+    fn sql(&self) -> String {
+        match self {
+            CapturedPayload::Sql(request) => request.sql.clone(),
+            CapturedPayload::Changeset(changeset) => changeset
+                .statements
+                .iter()
+                .map(|statement| statement.sql.as_str())
+                .collect::<Vec<_>>()
+                .join("; "),
+        }
+    }
+}
+
+/// One line of a capture file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CapturedRequest {
+    pub timestamp: DateTime<Utc>,
+    pub kind: CaptureKind,
+    pub request: CapturedPayload,
+    pub decision: Decision,
+}
+
+/// A captured request whose decision differs under the replayed policy.
+#[derive(Clone, Debug, Serialize)]
+pub struct ReplayChange {
+    pub line: usize,
+    pub sql: String,
+    pub before: Decision,
+    pub after: Decision,
+}
+
+/// Appends incoming requests and their decisions to a JSONL file.
+pub struct CaptureLog {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl CaptureLog {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn append(
+        &self,
+        kind: CaptureKind,
+        request: CapturedPayload,
+        decision: Decision,
+    ) -> Result<(), String> {
+        let entry = CapturedRequest {
+            timestamp: Utc::now(),
+            kind,
+            request,
+            decision,
+        };
+        let line = serde_json::to_string(&entry).map_err(|err| err.to_string())?;
+        let _guard = self
+            .lock
+            .lock()
+            .map_err(|_| "Capture log lock poisoned".to_string())?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|err| format!("Failed to open capture file: {err}"))?;
+        writeln!(file, "{line}").map_err(|err| format!("Failed to write capture file: {err}"))
+    }
+}
+
+pub fn read_capture(path: impl AsRef<Path>) -> Result<Vec<CapturedRequest>, String> {
+    let file =
+        File::open(path.as_ref()).map_err(|err| format!("Failed to open capture file: {err}"))?;
+    let mut entries = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|err| format!("Failed to read capture file: {err}"))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .map_err(|err| format!("Line {}: invalid capture entry: {err}", index + 1))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Previews every captured preview against `policy` and returns the ones whose
+/// decision changed. Commits are skipped: their outcome also depends on the
+/// preview they used (expiry, reuse, drift) and on idempotency keys, which a
+/// replay cannot reproduce, and a write is only committed after a captured
+/// preview. Without `db` previews run in dry-run mode, so limits that depend on
+/// row counts are not re-evaluated.
+pub fn replay(
+    entries: &[CapturedRequest],
+    policy: &PolicyConfig,
+    db: Option<&Arc<dyn SQLDB>>,
+) -> Vec<ReplayChange> {
+    let executor = QueryExecutor::default();
+    entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| entry.kind == CaptureKind::Preview)
+        .filter_map(|(index, entry)| {
+            let result: ProxyResult<Option<String>> = match &entry.request {
+                CapturedPayload::Sql(request) => {
+                    let mut request = request.clone();
+                    request.preview_id = None;
+                    executor
+                        .preview(&request, policy, db)
+                        .map(|executed| Some(executed.rewritten_sql))
+                }
+                CapturedPayload::Changeset(changeset) => {
+                    let mut changeset = changeset.clone();
+                    changeset.preview_id = None;
+                    executor
+                        .preview_changeset(&changeset, policy, db)
+                        .map(|_| None)
+                }
+            };
+            let after = match result {
+                Ok(rewritten_sql) => Decision {
+                    allowed: true,
+                    rewritten_sql: entry.decision.rewritten_sql.as_ref().and(rewritten_sql),
+                    error: None,
+                },
+                Err(error) => Decision {
+                    allowed: false,
+                    rewritten_sql: None,
+                    error: Some(error.message),
+                },
+            };
+            (after != entry.decision).then(|| ReplayChange {
+                line: index + 1,
+                sql: entry.request.sql(),
+                before: entry.decision.clone(),
+                after,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::SqlParams, query_engine::QueryContext};
+    use std::collections::HashMap;
+
+    #[test]
+    fn reports_decisions_changed_by_a_new_policy() {
+        let request = SqlRequest {
+            sql: "DELETE FROM orders WHERE tenant_id = 'acme' AND id = 1".to_string(),
+            context: QueryContext {
+                actor: "agent:test".to_string(),
+                tenant_id: "acme".to_string(),
+                role: String::new(),
+                client_ip: None,
+                attributes: HashMap::new(),
+            },
+            params: SqlParams::default(),
+            preview_id: None,
+            idempotency_key: None,
+            explain: false,
+        };
+        let allowed = Decision {
+            allowed: true,
+            rewritten_sql: None,
+            error: None,
+        };
+        let entries = vec![
+            CapturedRequest {
+                timestamp: Utc::now(),
+                kind: CaptureKind::Preview,
+                request: CapturedPayload::Sql(request.clone()),
+                decision: Decision {
+                    rewritten_sql: Some(request.sql.clone()),
+                    ..allowed.clone()
+                },
+            },
+            CapturedRequest {
+                timestamp: Utc::now(),
+                kind: CaptureKind::Preview,
+                request: CapturedPayload::Changeset(ChangesetRequest::from_request(&request)),
+                decision: allowed,
+            },
+            // Failed for a reason a replay cannot reproduce, so it is skipped.
+            CapturedRequest {
+                timestamp: Utc::now(),
+                kind: CaptureKind::Commit,
+                request: CapturedPayload::Sql(request),
+                decision: Decision {
+                    allowed: false,
+                    rewritten_sql: None,
+                    error: Some("Preview has expired".to_string()),
+                },
+            },
+        ];
+
+        let unchanged: PolicyConfig = serde_yaml::from_str("{}").unwrap();
+        assert!(replay(&entries, &unchanged, None).is_empty());
+
+        let stricter: PolicyConfig =
+            serde_yaml::from_str("tables:\n  orders:\n    allow_ops: [select]\n").unwrap();
+        let changes = replay(&entries, &stricter, None);
+        assert_eq!(changes.len(), 2);
+        assert!(!changes[0].after.allowed);
+        assert_eq!(changes[1].line, 2);
+    }
+}
@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{db::SqlParams, query_engine::ChangesetStatement};
+
+/// Rows a committed statement changed, captured inside the commit transaction.
+/// `before` holds the rows as they were and `after` the rows it left behind.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChangeCapture {
+    pub table: String,
+    pub operation: String,
+    pub primary_key: Vec<String>,
+    /// Columns with BLOB affinity. Their values are captured as hex text, which
+    /// cannot be written back, so changes to such tables are not reverted.
+    #[serde(default)]
+    pub blob_columns: Vec<String>,
+    pub before: Vec<Value>,
+    pub after: Vec<Value>,
+}
+
+/// Builds the statements that undo `changes`, one per row, newest change first.
+/// Every statement matches the row's full captured state, so a row that changed
+/// after the commit is not touched and the revert can detect it.
+pub fn inverse_statements(changes: &[ChangeCapture]) -> Result<Vec<ChangesetStatement>, String> {
+    let mut statements = Vec::new();
+    for change in changes.iter().rev() {
+        if change.primary_key.is_empty() {
+            return Err(format!(
+                "Table '{}' has no primary key; its changes cannot be reverted",
+                change.table
+            ));
+        }
+        if let Some(column) = change.blob_columns.first() {
+            return Err(format!(
+                "Table '{}' has BLOB column '{column}'; its changes cannot be reverted",
+                change.table
+            ));
+        }
+
+        match change.operation.as_str() {
+            "insert" => {
+                for row in &change.after {
+                    statements.push(delete_row(&change.table, object(row)?));
+                }
+            }
+            "delete" => {
+                for row in &change.before {
+                    statements.push(insert_row(&change.table, object(row)?));
+                }
+            }
+            "update" => {
+                for after in &change.after {
+                    let after = object(after)?;
+                    let before = change
+                        .before
+                        .iter()
+                        .filter_map(Value::as_object)
+                        .find(|before| {
+                            change
+                                .primary_key
+                                .iter()
+                                .all(|column| before.get(column) == after.get(column))
+                        })
+                        .ok_or_else(|| {
+                            format!(
+                                "A primary key in table '{}' was changed; the update cannot be reverted",
+                                change.table
+                            )
+                        })?;
+                    if let Some(statement) = restore_row(&change.table, before, after) {
+                        statements.push(statement);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if statements.is_empty() {
+        return Err("No captured changes to revert".to_string());
+    }
+    Ok(statements)
+}
+
+fn delete_row(table: &str, row: &Map<String, Value>) -> ChangesetStatement {
+    let mut params = Vec::new();
+    let predicate = row_predicate(row, &mut params);
+    statement(format!("DELETE FROM {table} WHERE {predicate}"), params)
+}
+
+fn insert_row(table: &str, row: &Map<String, Value>) -> ChangesetStatement {
+    let columns: Vec<&str> = row.keys().map(String::as_str).collect();
+    let placeholders = vec!["?"; columns.len()].join(", ");
+    statement(
+        format!(
+            "INSERT INTO {table} ({}) VALUES ({placeholders})",
+            columns.join(", ")
+        ),
+        row.values().cloned().collect(),
+    )
+}
+
+/// Sets the columns the update changed back to their old values.
+fn restore_row(
+    table: &str,
+    before: &Map<String, Value>,
+    after: &Map<String, Value>,
+) -> Option<ChangesetStatement> {
+    let mut params = Vec::new();
+    let assignments: Vec<String> = before
+        .iter()
+        .filter(|(column, value)| after.get(*column) != Some(*value))
+        .map(|(column, value)| {
+            params.push(value.clone());
+            format!("{column} = ?")
+        })
+        .collect();
+    if assignments.is_empty() {
+        return None;
+    }
+
+    let predicate = row_predicate(after, &mut params);
+    Some(statement(
+        format!(
+            "UPDATE {table} SET {} WHERE {predicate}",
+            assignments.join(", ")
+        ),
+        params,
+    ))
+}
+
+fn row_predicate(row: &Map<String, Value>, params: &mut Vec<Value>) -> String {
+    row.iter()
+        .map(|(column, value)| {
+            if value.is_null() {
+                format!("{column} IS NULL")
+            } else {
+                params.push(value.clone());
+                format!("{column} = ?")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" AND ")
+}
+
+fn statement(sql: String, params: Vec<Value>) -> ChangesetStatement {
+    ChangesetStatement {
+        sql,
+        params: SqlParams::Positional(params),
+    }
+}
+
+fn object(row: &Value) -> Result<&Map<String, Value>, String> {
+    row.as_object()
+        .ok_or_else(|| "Captured row is not an object".to_string())
+}
@@ -0,0 +1,870 @@
+use sqlparser::{
+    ast::{
+        BinaryOperator, Expr, Ident, ObjectName, Query, Select, SelectItem, SetExpr, Statement,
+        TableAlias, TableFactor, TableWithJoins, Value, Visit, VisitMut, VisitorMut,
+        WildcardAdditionalOptions, visit_expressions, visit_expressions_mut,
+    },
+    dialect::PostgreSqlDialect,
+    parser::Parser,
+};
+use std::{collections::HashMap, ops::ControlFlow};
+
+use crate::{
+    db::SchemaSnapshot,
+    policy::{ColumnMask, MaskKind},
+    query_engine::{AffectedRowsQuery, same_table, table_entry, table_name_to_string},
+};
+
+/// Caps a top-level SELECT at `ceiling` rows, adding or lowering its LIMIT.
+/// A LIMIT that is not a literal, such as a bind parameter, is wrapped in `min`.
+pub(crate) fn apply_select_limit(query: &mut Query, ceiling: u64, warnings: &mut Vec<String>) {
+    let ceiling_expr = Expr::Value(Value::Number(ceiling.to_string(), false));
+    query.limit = match query.limit.take() {
+        None => {
+            warnings.push(format!("LIMIT {ceiling} added to SELECT"));
+            Some(ceiling_expr)
+        }
+        Some(Expr::Value(Value::Number(number, long))) => match number.parse::<u64>() {
+            Ok(limit) if limit <= ceiling => Some(Expr::Value(Value::Number(number, long))),
+            _ => {
+                warnings.push(format!("LIMIT lowered from {number} to {ceiling}"));
+                Some(ceiling_expr)
+            }
+        },
+        Some(limit) => {
+            warnings.push(format!("LIMIT {limit} capped at {ceiling}"));
+            Some(parse_expr(&format!("min({limit}, {ceiling})")).unwrap_or(ceiling_expr))
+        }
+    };
+}
+
+/// Injects row filter predicates (keyed by table name) wherever a filtered table
+/// is read or written. Reads are replaced with a filtered derived table so joins
+/// and subqueries are covered; UPDATE/DELETE targets get the predicate ANDed
+/// into their WHERE clause.
+pub(crate) fn apply_row_filters(
+    statement: &mut Statement,
+    filters: &HashMap<String, String>,
+    warnings: &mut Vec<String>,
+) -> Result<(), String> {
+    if filters.is_empty() {
+        return Ok(());
+    }
+
+    let mut rewriter = RowFilterRewriter {
+        filters,
+        applied: Vec::new(),
+    };
+    let flow = rewriter.filter_statement(statement);
+    if let ControlFlow::Break(error) = flow {
+        return Err(error);
+    }
+
+    for table in rewriter.applied {
+        warnings.push(format!("Row filter applied to table '{table}'"));
+    }
+    Ok(())
+}
+
+struct RowFilterRewriter<'a> {
+    filters: &'a HashMap<String, String>,
+    applied: Vec<String>,
+}
+
+impl VisitorMut for RowFilterRewriter<'_> {
+    type Break = String;
+
+    fn post_visit_table_factor(&mut self, factor: &mut TableFactor) -> ControlFlow<Self::Break> {
+        let TableFactor::Table { name, alias, .. } = factor else {
+            return ControlFlow::Continue(());
+        };
+        let table = table_name_to_string(name);
+        let Some(predicate) = table_entry(self.filters, &table) else {
+            return ControlFlow::Continue(());
+        };
+
+        let sql = format!("SELECT * FROM {name} WHERE {predicate}");
+        let subquery = match parse_query(&sql) {
+            Ok(subquery) => subquery,
+            Err(error) => {
+                return ControlFlow::Break(format!(
+                    "Invalid row filter for table '{table}': {error}"
+                ));
+            }
+        };
+        let alias = alias.clone().or_else(|| {
+            name.0.last().map(|ident| TableAlias {
+                name: ident.clone(),
+                columns: Vec::new(),
+            })
+        });
+
+        *factor = TableFactor::Derived {
+            lateral: false,
+            subquery: Box::new(subquery),
+            alias,
+        };
+        self.record(table);
+        ControlFlow::Continue(())
+    }
+}
+
+impl RowFilterRewriter<'_> {
+    fn filter_statement(&mut self, statement: &mut Statement) -> ControlFlow<String> {
+        match statement {
+            Statement::Update {
+                table,
+                assignments,
+                from,
+                selection,
+                ..
+            } => {
+                self.filter_target(table, selection)?;
+                for join in &mut table.joins {
+                    join.visit(self)?;
+                }
+                assignments.visit(self)?;
+                from.visit(self)?;
+                selection.visit(self)
+            }
+            Statement::Delete {
+                from,
+                using,
+                selection,
+                ..
+            } => {
+                if let Some(target) = from.first_mut() {
+                    self.filter_target(target, selection)?;
+                }
+                for table in from.iter_mut() {
+                    for join in &mut table.joins {
+                        join.visit(self)?;
+                    }
+                }
+                using.visit(self)?;
+                selection.visit(self)
+            }
+            other => other.visit(self),
+        }
+    }
+
+    fn filter_target(
+        &mut self,
+        target: &TableWithJoins,
+        selection: &mut Option<Expr>,
+    ) -> ControlFlow<String> {
+        let TableFactor::Table { name, .. } = &target.relation else {
+            return ControlFlow::Continue(());
+        };
+        let table = table_name_to_string(name);
+        let Some(predicate) = table_entry(self.filters, &table) else {
+            return ControlFlow::Continue(());
+        };
+
+        let mut predicate = match parse_expr(predicate) {
+            Ok(predicate) => predicate,
+            Err(error) => {
+                return ControlFlow::Break(format!(
+                    "Invalid row filter for table '{table}': {error}"
+                ));
+            }
+        };
+        qualify_columns(&mut predicate, &target.relation);
+
+        *selection = Some(match selection.take() {
+            Some(existing) => Expr::BinaryOp {
+                left: Box::new(Expr::Nested(Box::new(existing))),
+                op: BinaryOperator::And,
+                right: Box::new(Expr::Nested(Box::new(predicate))),
+            },
+            None => predicate,
+        });
+        self.record(table);
+        ControlFlow::Continue(())
+    }
+
+    fn record(&mut self, table: String) {
+        if !self.applied.contains(&table) {
+            self.applied.push(table);
+        }
+    }
+}
+
+/// Qualifies the bare column names in `predicate` with the alias or name of
+/// `relation`.
+fn qualify_columns(predicate: &mut Expr, relation: &TableFactor) {
+    let TableFactor::Table { name, alias, .. } = relation else {
+        return;
+    };
+    let qualifier = alias
+        .as_ref()
+        .map(|alias| alias.name.clone())
+        .or_else(|| name.0.last().cloned());
+    if let Some(qualifier) = qualifier {
+        let _ = visit_expressions_mut(predicate, |expr| {
+            if let Expr::Identifier(ident) = expr {
+                *expr = Expr::CompoundIdentifier(vec![qualifier.clone(), ident.clone()]);
+            }
+            ControlFlow::<()>::Continue(())
+        });
+    }
+}
+
+/// Applies a remediation to `sql`: drops the projection items of a SELECT that
+/// read a column in `remove`, expanding `*` from `schema`, and ANDs `predicate`
+/// into the WHERE clause of a SELECT, UPDATE or DELETE. The predicate is
+/// qualified with the first table when the statement reads more than one.
+pub(crate) fn apply_remediation(
+    sql: &str,
+    predicate: Option<&str>,
+    remove: &[String],
+    schema: Option<&SchemaSnapshot>,
+) -> Result<String, String> {
+    let dialect = PostgreSqlDialect {};
+    let mut statements = Parser::parse_sql(&dialect, sql).map_err(|err| err.to_string())?;
+    if statements.len() != 1 {
+        return Err("expected a single statement".to_string());
+    }
+    let mut statement = statements.remove(0);
+
+    if !remove.is_empty() {
+        let Statement::Query(query) = &mut statement else {
+            return Err("only SELECT columns can be removed".to_string());
+        };
+        let SetExpr::Select(select) = query.body.as_mut() else {
+            return Err("only SELECT columns can be removed".to_string());
+        };
+        let mut scope = Vec::new();
+        for table in &select.from {
+            collect_scope(&table.relation, &mut scope);
+            for join in &table.joins {
+                collect_scope(&join.relation, &mut scope);
+            }
+        }
+        let mut projection = Vec::new();
+        for item in std::mem::take(&mut select.projection) {
+            match item {
+                SelectItem::Wildcard(_) => expand_without(&scope, remove, schema, &mut projection)?,
+                SelectItem::UnnamedExpr(ref expr) | SelectItem::ExprWithAlias { ref expr, .. } => {
+                    if !references_any(expr, remove) {
+                        projection.push(item);
+                    }
+                }
+                SelectItem::QualifiedWildcard(..) => {
+                    return Err("cannot expand a qualified wildcard".to_string());
+                }
+            }
+        }
+        if projection.is_empty() {
+            return Err("no columns left to select".to_string());
+        }
+        select.projection = projection;
+    }
+
+    if let Some(predicate) = predicate {
+        let predicate = parse_expr(predicate)?;
+        match &mut statement {
+            Statement::Query(query) => match query.body.as_mut() {
+                SetExpr::Select(select) => {
+                    let joined = select.from.len() > 1
+                        || select.from.iter().any(|table| !table.joins.is_empty());
+                    let Some(first) = select.from.first() else {
+                        return Err("SELECT has no table to filter".to_string());
+                    };
+                    and_predicate(&mut select.selection, predicate, &first.relation, joined);
+                }
+                _ => return Err("only simple SELECTs can be filtered".to_string()),
+            },
+            Statement::Update {
+                table,
+                from,
+                selection,
+                ..
+            } => {
+                let joined = from.is_some() || !table.joins.is_empty();
+                and_predicate(selection, predicate, &table.relation, joined);
+            }
+            Statement::Delete {
+                from,
+                using,
+                selection,
+                ..
+            } => {
+                let joined = using.is_some()
+                    || from.len() > 1
+                    || from.iter().any(|table| !table.joins.is_empty());
+                let Some(first) = from.first() else {
+                    return Err("DELETE has no table to filter".to_string());
+                };
+                and_predicate(selection, predicate, &first.relation, joined);
+            }
+            _ => return Err("only SELECT, UPDATE and DELETE can be filtered".to_string()),
+        }
+    }
+
+    Ok(statement.to_string())
+}
+
+fn and_predicate(
+    selection: &mut Option<Expr>,
+    mut predicate: Expr,
+    relation: &TableFactor,
+    qualify: bool,
+) {
+    if qualify {
+        qualify_columns(&mut predicate, relation);
+    }
+    // AND binds tighter than everything but OR, so only OR needs parentheses.
+    let operand = |expr: Expr| match expr {
+        Expr::BinaryOp {
+            op: BinaryOperator::Or,
+            ..
+        } => Expr::Nested(Box::new(expr)),
+        other => other,
+    };
+    *selection = Some(match selection.take() {
+        Some(existing) => Expr::BinaryOp {
+            left: Box::new(operand(existing)),
+            op: BinaryOperator::And,
+            right: Box::new(operand(predicate)),
+        },
+        None => predicate,
+    });
+}
+
+fn expand_without(
+    scope: &[ScopeEntry],
+    remove: &[String],
+    schema: Option<&SchemaSnapshot>,
+    projection: &mut Vec<SelectItem>,
+) -> Result<(), String> {
+    for entry in scope {
+        let ScopeEntry::Table { name, qualifier } = entry else {
+            return Err("cannot expand * over a subquery".to_string());
+        };
+        let table = schema
+            .and_then(|schema| {
+                schema
+                    .tables
+                    .iter()
+                    .find(|table| same_table(&table.name, name))
+            })
+            .ok_or_else(|| format!("cannot expand * over '{name}' without its schema"))?;
+        for column in &table.columns {
+            if remove
+                .iter()
+                .any(|removed| removed.eq_ignore_ascii_case(&column.name))
+            {
+                continue;
+            }
+            projection.push(SelectItem::UnnamedExpr(if scope.len() == 1 {
+                Expr::Identifier(Ident::new(&column.name))
+            } else {
+                Expr::CompoundIdentifier(vec![Ident::new(qualifier), Ident::new(&column.name)])
+            }));
+        }
+    }
+    Ok(())
+}
+
+fn references_any(expr: &Expr, columns: &[String]) -> bool {
+    visit_expressions(expr, |expr| {
+        let column = match expr {
+            Expr::Identifier(ident) => Some(&ident.value),
+            Expr::CompoundIdentifier(parts) => parts.last().map(|ident| &ident.value),
+            _ => None,
+        };
+        if column.is_some_and(|column| {
+            columns
+                .iter()
+                .any(|candidate| candidate.eq_ignore_ascii_case(column))
+        }) {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    })
+    .is_break()
+}
+
+/// Builds a SELECT returning the current content of the rows an UPDATE or
+/// DELETE targets, using the statement's own tables and WHERE clause.
+pub(crate) fn affected_rows_query(statement: &Statement) -> Option<AffectedRowsQuery> {
+    let (target, mut sources, selection) = match statement {
+        Statement::Update {
+            table,
+            from,
+            selection,
+            ..
+        } => (
+            table,
+            from.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            selection,
+        ),
+        Statement::Delete {
+            from,
+            using,
+            selection,
+            ..
+        } => (
+            from.first()?,
+            using
+                .iter()
+                .flatten()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            selection,
+        ),
+        _ => return None,
+    };
+    let TableFactor::Table { name, alias, .. } = &target.relation else {
+        return None;
+    };
+    let qualifier = match alias {
+        Some(alias) => alias.name.to_string(),
+        None => name.to_string(),
+    };
+    sources.insert(0, target.to_string());
+
+    let mut sql = format!("SELECT {qualifier}.* FROM {}", sources.join(", "));
+    if let Some(selection) = selection {
+        sql.push_str(&format!(" WHERE {selection}"));
+    }
+    Some(AffectedRowsQuery {
+        table: table_name_to_string(name),
+        sql,
+    })
+}
+
+/// Returns the write with `RETURNING *` appended, so the rows it inserted,
+/// updated or deleted can be captured for a later revert. Statements that
+/// already have a RETURNING clause are left alone.
+pub(crate) fn capture_query(statement: &Statement) -> Option<AffectedRowsQuery> {
+    let mut statement = statement.clone();
+    let (table, returning) = match &mut statement {
+        Statement::Insert {
+            table_name,
+            returning,
+            ..
+        } => (table_name_to_string(table_name), returning),
+        Statement::Update {
+            table, returning, ..
+        } => match &table.relation {
+            TableFactor::Table { name, .. } => (table_name_to_string(name), returning),
+            _ => return None,
+        },
+        Statement::Delete {
+            from, returning, ..
+        } => match from.first().map(|target| &target.relation) {
+            Some(TableFactor::Table { name, .. }) => (table_name_to_string(name), returning),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    if returning.is_some() {
+        return None;
+    }
+
+    *returning = Some(vec![SelectItem::Wildcard(
+        WildcardAdditionalOptions::default(),
+    )]);
+    Some(AffectedRowsQuery {
+        table,
+        sql: statement.to_string(),
+    })
+}
+
+fn parse_query(sql: &str) -> Result<Query, String> {
+    let dialect = PostgreSqlDialect {};
+    match Parser::parse_sql(&dialect, sql)
+        .map_err(|err| err.to_string())?
+        .pop()
+    {
+        Some(Statement::Query(query)) => Ok(*query),
+        _ => Err("expected a query".to_string()),
+    }
+}
+
+pub(crate) fn parse_expr(sql: &str) -> Result<Expr, String> {
+    let dialect = PostgreSqlDialect {};
+    Parser::new(&dialect)
+        .try_with_sql(sql)
+        .and_then(|mut parser| parser.parse_expr())
+        .map_err(|err| err.to_string())
+}
+
+/// Rewrites the projection of every query in `statement` (including CTEs,
+/// derived tables and subqueries) so masked columns come back masked.
+/// `masks` is keyed by table name.
+pub(crate) fn apply_masks(
+    statement: &mut Statement,
+    masks: &HashMap<String, Vec<ColumnMask>>,
+    schema: Option<&SchemaSnapshot>,
+    warnings: &mut Vec<String>,
+) -> Result<(), String> {
+    if masks.is_empty() {
+        return Ok(());
+    }
+
+    let mut rewriter = MaskRewriter {
+        masks,
+        schema,
+        applied: Vec::new(),
+    };
+    if let ControlFlow::Break(error) = statement.visit(&mut rewriter) {
+        return Err(error);
+    }
+
+    for (table, column, kind) in rewriter.applied {
+        warnings.push(format!(
+            "Column '{table}.{column}' masked with {}",
+            kind.as_str()
+        ));
+    }
+    Ok(())
+}
+
+enum ScopeEntry {
+    Table { name: String, qualifier: String },
+    Derived { qualifier: Option<String> },
+}
+
+struct MaskRewriter<'a> {
+    masks: &'a HashMap<String, Vec<ColumnMask>>,
+    schema: Option<&'a SchemaSnapshot>,
+    applied: Vec<(String, String, MaskKind)>,
+}
+
+impl VisitorMut for MaskRewriter<'_> {
+    type Break = String;
+
+    // Queries are visited innermost first, so a derived table is already masked
+    // by the time the query selecting from it is rewritten.
+    fn post_visit_query(&mut self, query: &mut Query) -> ControlFlow<Self::Break> {
+        if let SetExpr::Select(select) = query.body.as_ref() {
+            let scope = select_scope(select);
+            if let Err(error) = self.reject_masked_reference(&scope, &query.order_by, "ORDER BY") {
+                return ControlFlow::Break(error);
+            }
+        }
+        match self.mask_set_expr(&mut query.body) {
+            Ok(()) => ControlFlow::Continue(()),
+            Err(error) => ControlFlow::Break(error),
+        }
+    }
+}
+
+impl MaskRewriter<'_> {
+    fn mask_set_expr(&mut self, body: &mut SetExpr) -> Result<(), String> {
+        match body {
+            SetExpr::Select(select) => {
+                let scope = select_scope(select);
+                if !scope.iter().any(|entry| !self.masks_for(entry).is_empty()) {
+                    return Ok(());
+                }
+
+                // Filtering, grouping or joining on a masked column would let the
+                // caller recover its value one guess at a time.
+                self.reject_masked_reference(&scope, &select.selection, "WHERE")?;
+                self.reject_masked_reference(&scope, &select.group_by, "GROUP BY")?;
+                self.reject_masked_reference(&scope, &select.having, "HAVING")?;
+                self.reject_masked_reference(&scope, &select.qualify, "QUALIFY")?;
+                self.reject_masked_reference(&scope, &select.sort_by, "SORT BY")?;
+                for table in &select.from {
+                    for join in &table.joins {
+                        self.reject_masked_reference(&scope, &join.join_operator, "JOIN")?;
+                    }
+                }
+
+                let mut projection = Vec::new();
+                for item in std::mem::take(&mut select.projection) {
+                    self.mask_item(item, &scope, &mut projection)?;
+                }
+                select.projection = projection;
+                Ok(())
+            }
+            SetExpr::SetOperation { left, right, .. } => {
+                self.mask_set_expr(left)?;
+                self.mask_set_expr(right)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn mask_item(
+        &mut self,
+        item: SelectItem,
+        scope: &[ScopeEntry],
+        projection: &mut Vec<SelectItem>,
+    ) -> Result<(), String> {
+        match item {
+            SelectItem::Wildcard(_) => {
+                for entry in scope {
+                    self.expand(entry, projection)?;
+                }
+            }
+            SelectItem::QualifiedWildcard(name, options) => {
+                let qualifier = name.0.last().map(|ident| ident.value.as_str());
+                match scope
+                    .iter()
+                    .find(|entry| qualifier.is_some_and(|q| entry_qualifier(entry) == Some(q)))
+                {
+                    Some(entry) => self.expand(entry, projection)?,
+                    None => projection.push(SelectItem::QualifiedWildcard(name, options)),
+                }
+            }
+            SelectItem::UnnamedExpr(expr) => match self.resolve_mask(scope, &expr)? {
+                Some((table, column, mask)) => {
+                    projection.push(SelectItem::ExprWithAlias {
+                        expr: self.mask_expr(&table, &column, &mask, &expr)?,
+                        alias: Ident::new(column),
+                    });
+                }
+                None => projection.push(SelectItem::UnnamedExpr(expr)),
+            },
+            SelectItem::ExprWithAlias { expr, alias } => match self.resolve_mask(scope, &expr)? {
+                Some((table, column, mask)) => {
+                    projection.push(SelectItem::ExprWithAlias {
+                        expr: self.mask_expr(&table, &column, &mask, &expr)?,
+                        alias,
+                    });
+                }
+                None => projection.push(SelectItem::ExprWithAlias { expr, alias }),
+            },
+        }
+        Ok(())
+    }
+
+    fn expand(
+        &mut self,
+        entry: &ScopeEntry,
+        projection: &mut Vec<SelectItem>,
+    ) -> Result<(), String> {
+        match entry {
+            ScopeEntry::Table { name, qualifier } => {
+                if self.masks_for(entry).is_empty() {
+                    projection.push(qualified_wildcard(qualifier));
+                    return Ok(());
+                }
+
+                let schema = self.schema;
+                let table = schema
+                    .and_then(|schema| {
+                        schema
+                            .tables
+                            .iter()
+                            .find(|table| same_table(&table.name, name))
+                    })
+                    .ok_or_else(|| {
+                        format!("Cannot expand * over masked table '{name}' without its schema")
+                    })?;
+                for column in &table.columns {
+                    let expr = Expr::CompoundIdentifier(vec![
+                        Ident::new(qualifier),
+                        Ident::new(&column.name),
+                    ]);
+                    match self.mask_for(name, &column.name) {
+                        Some(mask) => projection.push(SelectItem::ExprWithAlias {
+                            expr: self.mask_expr(name, &column.name, &mask, &expr)?,
+                            alias: Ident::new(&column.name),
+                        }),
+                        None => projection.push(SelectItem::UnnamedExpr(expr)),
+                    }
+                }
+                Ok(())
+            }
+            ScopeEntry::Derived {
+                qualifier: Some(qualifier),
+            } => {
+                projection.push(qualified_wildcard(qualifier));
+                Ok(())
+            }
+            ScopeEntry::Derived { qualifier: None } => {
+                Err("Cannot expand * over an unaliased subquery when masking columns".to_string())
+            }
+        }
+    }
+
+    /// Finds the mask for a directly selected column. Any other expression that
+    /// reads a masked column is rejected rather than partially masked.
+    fn resolve_mask(
+        &self,
+        scope: &[ScopeEntry],
+        expr: &Expr,
+    ) -> Result<Option<(String, String, ColumnMask)>, String> {
+        let found = match expr {
+            Expr::Identifier(ident) => scope.iter().find_map(|entry| match entry {
+                ScopeEntry::Table { name, .. } => self
+                    .mask_for(name, &ident.value)
+                    .map(|mask| (name.clone(), ident.value.clone(), mask)),
+                ScopeEntry::Derived { .. } => None,
+            }),
+            Expr::CompoundIdentifier(parts) if parts.len() >= 2 => {
+                let qualifier = &parts[parts.len() - 2].value;
+                let column = &parts[parts.len() - 1].value;
+                scope.iter().find_map(|entry| match entry {
+                    ScopeEntry::Table {
+                        name,
+                        qualifier: entry_qualifier,
+                    } if entry_qualifier.eq_ignore_ascii_case(qualifier) => self
+                        .mask_for(name, column)
+                        .map(|mask| (name.clone(), column.clone(), mask)),
+                    _ => None,
+                })
+            }
+            Expr::Nested(inner) => return self.resolve_mask(scope, inner),
+            _ => {
+                if let Some(column) = self.masked_reference(scope, expr) {
+                    return Err(format!(
+                        "Column '{column}' is masked for this role and can only be selected directly"
+                    ));
+                }
+                None
+            }
+        };
+        Ok(found)
+    }
+
+    fn reject_masked_reference<V: Visit>(
+        &self,
+        scope: &[ScopeEntry],
+        node: &V,
+        clause: &str,
+    ) -> Result<(), String> {
+        match self.masked_reference(scope, node) {
+            Some(column) => Err(format!(
+                "Column '{column}' is masked for this role and cannot be used in {clause}"
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Name of the first masked column `node` reads, matched by column name.
+    fn masked_reference<V: Visit>(&self, scope: &[ScopeEntry], node: &V) -> Option<String> {
+        let masked = scope
+            .iter()
+            .flat_map(|entry| self.masks_for(entry))
+            .collect::<Vec<_>>();
+        if masked.is_empty() {
+            return None;
+        }
+        let reference = visit_expressions(node, |expr| {
+            let column = match expr {
+                Expr::Identifier(ident) => Some(&ident.value),
+                Expr::CompoundIdentifier(parts) => parts.last().map(|ident| &ident.value),
+                _ => None,
+            };
+            match column.and_then(|column| {
+                masked
+                    .iter()
+                    .find(|mask| mask.column.eq_ignore_ascii_case(column))
+            }) {
+                Some(mask) => ControlFlow::Break(mask.column.clone()),
+                None => ControlFlow::Continue(()),
+            }
+        });
+        match reference {
+            ControlFlow::Break(column) => Some(column),
+            ControlFlow::Continue(()) => None,
+        }
+    }
+
+    fn masks_for(&self, entry: &ScopeEntry) -> &[ColumnMask] {
+        match entry {
+            ScopeEntry::Table { name, .. } => table_entry(self.masks, name)
+                .map(Vec::as_slice)
+                .unwrap_or_default(),
+            ScopeEntry::Derived { .. } => &[],
+        }
+    }
+
+    fn mask_for(&self, table: &str, column: &str) -> Option<ColumnMask> {
+        table_entry(self.masks, table)?
+            .iter()
+            .find(|mask| mask.column.eq_ignore_ascii_case(column))
+            .cloned()
+    }
+
+    fn mask_expr(
+        &mut self,
+        table: &str,
+        column: &str,
+        mask: &ColumnMask,
+        expr: &Expr,
+    ) -> Result<Expr, String> {
+        let value = expr.to_string();
+        let sql = match mask.mask {
+            MaskKind::Null => "NULL".to_string(),
+            MaskKind::Constant => {
+                let constant = mask.value.as_deref().unwrap_or("***").replace('\'', "''");
+                format!("'{constant}'")
+            }
+            MaskKind::Hash => format!("mask_hash({value})"),
+            MaskKind::Last4 => format!("'****' || substr(CAST({value} AS TEXT), -4)"),
+            MaskKind::PartialEmail => format!(
+                "CASE WHEN instr({value}, '@') > 0 THEN substr({value}, 1, 1) || '***' || substr({value}, instr({value}, '@')) ELSE '***' END"
+            ),
+        };
+
+        let key = (table.to_string(), column.to_string(), mask.mask);
+        if !self.applied.contains(&key) {
+            self.applied.push(key);
+        }
+
+        parse_expr(&sql).map_err(|err| format!("Failed to build mask for column '{column}': {err}"))
+    }
+}
+
+fn select_scope(select: &Select) -> Vec<ScopeEntry> {
+    let mut scope = Vec::new();
+    for table in &select.from {
+        collect_scope(&table.relation, &mut scope);
+        for join in &table.joins {
+            collect_scope(&join.relation, &mut scope);
+        }
+    }
+    scope
+}
+
+fn collect_scope(factor: &TableFactor, scope: &mut Vec<ScopeEntry>) {
+    match factor {
+        TableFactor::Table { name, alias, .. } => {
+            let table = table_name_to_string(name);
+            let qualifier = alias
+                .as_ref()
+                .map(|alias| alias.name.value.clone())
+                .or_else(|| name.0.last().map(|ident| ident.value.clone()))
+                .unwrap_or_else(|| table.clone());
+            scope.push(ScopeEntry::Table {
+                name: table,
+                qualifier,
+            });
+        }
+        TableFactor::NestedJoin {
+            table_with_joins, ..
+        } => {
+            collect_scope(&table_with_joins.relation, scope);
+            for join in &table_with_joins.joins {
+                collect_scope(&join.relation, scope);
+            }
+        }
+        TableFactor::Derived { alias, .. } => scope.push(ScopeEntry::Derived {
+            qualifier: alias.as_ref().map(|alias| alias.name.value.clone()),
+        }),
+        _ => scope.push(ScopeEntry::Derived { qualifier: None }),
+    }
+}
+
+fn entry_qualifier(entry: &ScopeEntry) -> Option<&str> {
+    match entry {
+        ScopeEntry::Table { qualifier, .. } => Some(qualifier),
+        ScopeEntry::Derived { qualifier } => qualifier.as_deref(),
+    }
+}
+
+fn qualified_wildcard(qualifier: &str) -> SelectItem {
+    SelectItem::QualifiedWildcard(
+        ObjectName(vec![Ident::new(qualifier)]),
+        WildcardAdditionalOptions::default(),
+    )
+}
@@ -1,46 +1,125 @@
 use axum::{
     Json, Router,
-    extract::{Path, State},
-    http::StatusCode,
+    extract::{ConnectInfo, MatchedPath, Path, Query, Request, State},
+    http::{HeaderValue, StatusCode, header},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
     routing::{get, post},
 };
-use chrono::Utc;
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::RwLock;
+use chrono::{DateTime, Utc};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{sync::RwLock, task::JoinHandle};
+use tracing::Instrument;
 
-use crate::db::SQLDB;
+use crate::audit::{AuditEvent, AuditLog, AuditRecord};
+use crate::db::{SQLDB, SqlParams, TransactionMode, with_transaction};
+use crate::error::{ErrorCode, ProxyError, ProxyResult};
+use crate::metrics::Metrics;
 use crate::policy::PolicyConfig;
-use crate::query_engine::{CommitResponse, ErrorResponse, QueryEngine, QueryRecord, SqlRequest};
-use crate::query_executor::QueryExecutor;
+use crate::query_engine::{
+    ChangesetPreviewResponse, ChangesetRequest, ChangesetStatement, CommitResponse, ErrorResponse,
+    ExplainRequest, ExplainResponse, PreviewResponse, QueryContext, QueryEngine, QueryGetRequest,
+    QueryListRequest, QueryListResponse, QueryRecord, QueryStatus, RevertRequest, SortOrder,
+    SqlRequest,
+};
+use crate::query_executor::{ExecutedChangeset, ExecutedQuery, QueryExecutor, RowFingerprint};
+use crate::rate_limit::{RateKind, RateLimiter, UsageRequest, UsageResponse};
+use crate::replay::{CaptureKind, CaptureLog, CapturedPayload, Decision};
+use crate::revert::{ChangeCapture, inverse_statements};
 
 #[derive(Clone, Debug)]
 pub(crate) struct StoredQuery {
     pub(crate) record: QueryRecord,
+    /// Statements and params as submitted, which a commit must repeat.
+    pub(crate) request: Vec<ChangesetStatement>,
+    /// Affected-row fingerprints taken during preview, one per statement.
+    pub(crate) fingerprints: Vec<Option<RowFingerprint>>,
+    /// Set while a commit of this preview is running, so it can only be used once.
+    pub(crate) committing: bool,
+    /// Rows the commit changed, used to revert it.
+    pub(crate) changes: Vec<ChangeCapture>,
 }
 
 #[derive(Default)]
 pub(crate) struct QueryStore {
     pub(crate) entries: HashMap<String, StoredQuery>,
+    /// Commits made with an idempotency key, keyed by (actor, key).
+    pub(crate) commits: HashMap<(String, String), IdempotentCommit>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct IdempotentCommit {
+    pub(crate) request: Vec<ChangesetStatement>,
+    /// `None` while the first commit with the key is still running.
+    pub(crate) response: Option<CommitResponse>,
 }
 
+impl IdempotentCommit {
+    /// Whether the key of a finished commit is older than `ttl`. Keys of commits
+    /// still running never expire.
+    fn expired(&self, ttl: chrono::Duration, now: DateTime<Utc>) -> bool {
+        self.response.as_ref().is_some_and(|response| {
+            response
+                .committed_at
+                .checked_add_signed(ttl)
+                .is_some_and(|expires_at| expires_at <= now)
+        })
+    }
+}
+
+/// Body of `/readyz`: ready only when every check passed.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ReadinessResponse {
+    pub ok: bool,
+    pub checks: Vec<ReadinessCheck>,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ReadinessCheck {
+    /// `database` or `query_store`.
+    pub name: &'static str,
+    pub ok: bool,
+    pub detail: String,
+}
+
+/// How long `/readyz` waits for the database and the query store write lock.
+const READINESS_TIMEOUT: Duration = Duration::from_secs(1);
+
 #[derive(Clone)]
 pub struct AppState {
     pub(crate) store: Arc<RwLock<QueryStore>>,
-    pub(crate) engine: QueryEngine,
     pub(crate) executor: QueryExecutor,
     pub(crate) policy: PolicyConfig,
     pub(crate) db: Option<Arc<dyn SQLDB>>,
+    pub(crate) preview_ttl: chrono::Duration,
+    pub(crate) idempotency_ttl: chrono::Duration,
+    pub(crate) record_ttl: chrono::Duration,
+    pub(crate) audit: Option<Arc<AuditLog>>,
+    pub(crate) capture: Option<Arc<CaptureLog>>,
+    pub(crate) rate_limiter: Arc<RateLimiter>,
+    pub(crate) metrics: Arc<Metrics>,
 }
 
 impl AppState {
     pub fn new(policy: PolicyConfig) -> Self {
+        let metrics = Arc::new(Metrics::default());
         Self {
             store: Arc::new(RwLock::new(QueryStore::default())),
-            engine: QueryEngine::default(),
-            executor: QueryExecutor::default(),
+            executor: QueryExecutor::new(QueryEngine).with_metrics(metrics.clone()),
+            rate_limiter: Arc::new(RateLimiter::new(policy.rate_limits.clone())),
+            metrics,
             policy,
             db: None,
+            preview_ttl: chrono::Duration::minutes(15),
+            idempotency_ttl: chrono::Duration::hours(24),
+            record_ttl: chrono::Duration::days(7),
+            audit: None,
+            capture: None,
         }
     }
 
@@ -48,85 +127,1773 @@ impl AppState {
         self.db = Some(db);
         self
     }
+
+    /// Appends every preview, rejection, commit and failed commit to `audit`.
+    pub fn with_audit_log(mut self, audit: AuditLog) -> Self {
+        self.audit = Some(Arc::new(audit));
+        self
+    }
+
+    /// Records every previewed or committed `SqlRequest` with its decision, for
+    /// replaying against another policy.
+    pub fn with_capture_log(mut self, capture: CaptureLog) -> Self {
+        self.capture = Some(Arc::new(capture));
+        self
+    }
+
+    /// Sets how long a preview can be committed after it was made.
+    pub fn with_preview_ttl(mut self, ttl: Duration) -> Self {
+        self.preview_ttl = chrono::Duration::from_std(ttl).unwrap_or(chrono::Duration::MAX);
+        self
+    }
+
+    /// Sets how long a commit's idempotency key replays its response.
+    pub fn with_idempotency_ttl(mut self, ttl: Duration) -> Self {
+        self.idempotency_ttl = chrono::Duration::from_std(ttl).unwrap_or(chrono::Duration::MAX);
+        self
+    }
+
+    /// Sets how long committed and failed records are kept, counted from when
+    /// they were created. A dropped commit can no longer be reverted.
+    pub fn with_record_ttl(mut self, ttl: Duration) -> Self {
+        self.record_ttl = chrono::Duration::from_std(ttl).unwrap_or(chrono::Duration::MAX);
+        self
+    }
+
+    /// Marks previews past their TTL as `expired` and drops expired entries once
+    /// they are another TTL old, committed and failed entries past the record
+    /// TTL, and idempotency keys past theirs. Returns how many entries and keys
+    /// were dropped.
+    pub async fn sweep_expired(&self) -> usize {
+        let now = Utc::now();
+        let mut store = self.store.write().await;
+        for stored in store.entries.values_mut() {
+            if stored.record.status == QueryStatus::Previewed
+                && !stored.committing
+                && stored.record.expires_at <= now
+            {
+                stored.record.status = QueryStatus::Expired;
+            }
+        }
+
+        let before = store.entries.len() + store.commits.len();
+        let settled_until = now.checked_sub_signed(self.record_ttl);
+        store
+            .entries
+            .retain(|_, stored| match stored.record.status {
+                QueryStatus::Expired => self.expiry(stored.record.expires_at) > now,
+                QueryStatus::Committed | QueryStatus::Failed => {
+                    settled_until.is_none_or(|until| stored.record.created_at > until)
+                }
+                QueryStatus::Previewed => true,
+            });
+        store
+            .commits
+            .retain(|_, commit| !commit.expired(self.idempotency_ttl, now));
+        before - store.entries.len() - store.commits.len()
+    }
+
+    /// Runs [`AppState::sweep_expired`] every `period` in the background.
+    pub fn spawn_sweeper(&self, period: Duration) -> JoinHandle<()> {
+        let state = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                state.sweep_expired().await;
+            }
+        })
+    }
+
+    /// Previews a single statement and stores it for a later commit.
+    #[tracing::instrument(
+        name = "preview_sql",
+        skip_all,
+        fields(actor = %payload.context.actor, tenant = %payload.context.tenant_id, preview_id = tracing::field::Empty)
+    )]
+    pub(crate) async fn preview_sql(&self, payload: &SqlRequest) -> ProxyResult<PreviewResponse> {
+        let changeset = ChangesetRequest::from_request(payload);
+        if let Err(error) = self.acquire_preview(&payload.context) {
+            self.audit_preview(&changeset, Err(&error));
+            return Err(error);
+        }
+        let result = self
+            .executor
+            .preview(payload, &self.policy, self.db.as_ref());
+        if let Ok(executed) = &result {
+            tracing::Span::current().record("preview_id", executed.preview.preview_id.as_str());
+        }
+        self.record_outcome("preview", &payload.context, &result);
+        self.audit_preview(
+            &changeset,
+            result.as_ref().map(|executed| {
+                (
+                    executed.preview.preview_id.as_str(),
+                    executed.preview.rows_affected,
+                )
+            }),
+        );
+        self.capture(
+            CaptureKind::Preview,
+            CapturedPayload::Sql(payload.clone()),
+            match &result {
+                Ok(executed) => Decision {
+                    allowed: true,
+                    rewritten_sql: Some(executed.rewritten_sql.clone()),
+                    error: None,
+                },
+                Err(error) => Decision {
+                    allowed: false,
+                    rewritten_sql: None,
+                    error: Some(error.message.clone()),
+                },
+            },
+        );
+        let executed = result?;
+
+        let stored = StoredQuery {
+            record: self.single_record(payload, &executed, QueryStatus::Previewed),
+            request: changeset.statements,
+            fingerprints: vec![executed.fingerprint],
+            committing: false,
+            changes: Vec::new(),
+        };
+        let mut store = self.store.write().await;
+        store
+            .entries
+            .insert(executed.preview.preview_id.clone(), stored);
+
+        Ok(executed.preview)
+    }
+
+    /// Commits a single statement. With a `preview_id` the statement must match
+    /// that preview, which is then used up, and its affected rows must not have
+    /// drifted since. With an `idempotency_key` a repeated commit returns the
+    /// original response instead of executing again.
+    #[tracing::instrument(
+        name = "commit_sql",
+        skip_all,
+        fields(actor = %payload.context.actor, tenant = %payload.context.tenant_id, preview_id = payload.preview_id.as_deref())
+    )]
+    pub(crate) async fn commit_sql(&self, payload: &SqlRequest) -> ProxyResult<CommitResponse> {
+        let changeset = ChangesetRequest::from_request(payload);
+        let result = match self.begin_idempotent(&changeset).await {
+            Ok(Some(response)) => {
+                self.audit_replay(&changeset, &response);
+                return Ok(response);
+            }
+            Ok(None) => {
+                if let Err(error) = self.acquire_commit(&payload.context) {
+                    let result = Err(error.clone());
+                    self.finish_idempotent(&changeset, &result).await;
+                    self.audit_commit(&changeset, &result);
+                    return Err(error);
+                }
+                let result = self.commit_sql_once(payload, &changeset).await;
+                self.finish_idempotent(&changeset, &result).await;
+                result
+            }
+            Err(error) => Err(error),
+        };
+        self.audit_commit(&changeset, &result);
+        self.record_outcome("commit", &payload.context, &result);
+        self.capture(
+            CaptureKind::Commit,
+            CapturedPayload::Sql(payload.clone()),
+            Decision {
+                allowed: result.is_ok(),
+                rewritten_sql: None,
+                error: result.as_ref().err().map(|error| error.message.clone()),
+            },
+        );
+        result
+    }
+
+    async fn commit_sql_once(
+        &self,
+        payload: &SqlRequest,
+        changeset: &ChangesetRequest,
+    ) -> ProxyResult<CommitResponse> {
+        let expected = self.claim_preview(changeset).await?;
+        let result = self.executor.commit_previewed(
+            payload,
+            &self.policy,
+            self.db.as_ref(),
+            expected.first().and_then(Option::as_ref),
+        );
+        let executed = self.settle_preview(changeset, result).await?;
+        if executed.preview.operation != "select" {
+            self.charge_rows_written(&payload.context, executed.preview.rows_affected);
+        }
+
+        let preview_id = match &payload.preview_id {
+            Some(id) => {
+                self.record_changes(id, executed.changes.clone()).await;
+                id.clone()
+            }
+            None => {
+                let stored = StoredQuery {
+                    record: self.single_record(payload, &executed, QueryStatus::Committed),
+                    request: changeset.statements.clone(),
+                    fingerprints: vec![executed.fingerprint.clone()],
+                    committing: false,
+                    changes: executed.changes.clone(),
+                };
+                let mut store = self.store.write().await;
+                store
+                    .entries
+                    .insert(executed.preview.preview_id.clone(), stored);
+                executed.preview.preview_id.clone()
+            }
+        };
+
+        Ok(CommitResponse {
+            ok: true,
+            preview_id,
+            committed_at: Utc::now(),
+            rows_affected: executed.preview.rows_affected,
+        })
+    }
+
+    #[tracing::instrument(
+        name = "preview_changeset",
+        skip_all,
+        fields(actor = %payload.context.actor, tenant = %payload.context.tenant_id, preview_id = tracing::field::Empty)
+    )]
+    pub(crate) async fn preview_changeset(
+        &self,
+        payload: &ChangesetRequest,
+    ) -> ProxyResult<ChangesetPreviewResponse> {
+        if let Err(error) = self.acquire_preview(&payload.context) {
+            self.audit_preview(payload, Err(&error));
+            return Err(error);
+        }
+        let result = self
+            .executor
+            .preview_changeset(payload, &self.policy, self.db.as_ref());
+        if let Ok(executed) = &result {
+            tracing::Span::current().record("preview_id", executed.preview.preview_id.as_str());
+        }
+        self.record_outcome("preview", &payload.context, &result);
+        self.capture(
+            CaptureKind::Preview,
+            CapturedPayload::Changeset(payload.clone()),
+            Decision {
+                allowed: result.is_ok(),
+                rewritten_sql: None,
+                error: result.as_ref().err().map(|error| error.message.clone()),
+            },
+        );
+        self.audit_preview(
+            payload,
+            result.as_ref().map(|executed| {
+                (
+                    executed.preview.preview_id.as_str(),
+                    executed.preview.rows_affected,
+                )
+            }),
+        );
+        let executed = result?;
+
+        let stored = StoredQuery {
+            record: self.changeset_record(payload, &executed, QueryStatus::Previewed),
+            request: payload.statements.clone(),
+            fingerprints: executed.fingerprints,
+            committing: false,
+            changes: Vec::new(),
+        };
+        let mut store = self.store.write().await;
+        store
+            .entries
+            .insert(executed.preview.preview_id.clone(), stored);
+
+        Ok(executed.preview)
+    }
+
+    #[tracing::instrument(
+        name = "commit_changeset",
+        skip_all,
+        fields(actor = %payload.context.actor, tenant = %payload.context.tenant_id, preview_id = payload.preview_id.as_deref())
+    )]
+    pub(crate) async fn commit_changeset(
+        &self,
+        payload: &ChangesetRequest,
+    ) -> ProxyResult<CommitResponse> {
+        let result = match self.begin_idempotent(payload).await {
+            Ok(Some(response)) => {
+                self.audit_replay(payload, &response);
+                return Ok(response);
+            }
+            Ok(None) => {
+                if let Err(error) = self.acquire_commit(&payload.context) {
+                    let result = Err(error.clone());
+                    self.finish_idempotent(payload, &result).await;
+                    self.audit_commit(payload, &result);
+                    return Err(error);
+                }
+                let result = self.commit_changeset_once(payload).await;
+                self.finish_idempotent(payload, &result).await;
+                result
+            }
+            Err(error) => Err(error),
+        };
+        self.audit_commit(payload, &result);
+        self.record_outcome("commit", &payload.context, &result);
+        self.capture(
+            CaptureKind::Commit,
+            CapturedPayload::Changeset(payload.clone()),
+            Decision {
+                allowed: result.is_ok(),
+                rewritten_sql: None,
+                error: result.as_ref().err().map(|error| error.message.clone()),
+            },
+        );
+        result
+    }
+
+    async fn commit_changeset_once(
+        &self,
+        payload: &ChangesetRequest,
+    ) -> ProxyResult<CommitResponse> {
+        let expected = self.claim_preview(payload).await?;
+        let result = self.executor.commit_changeset_previewed(
+            payload,
+            &self.policy,
+            self.db.as_ref(),
+            payload.preview_id.as_ref().map(|_| expected.as_slice()),
+        );
+        let executed = self.settle_preview(payload, result).await?;
+        let rows_written = executed
+            .preview
+            .statements
+            .iter()
+            .filter(|statement| statement.operation != "select")
+            .map(|statement| statement.rows_affected)
+            .sum();
+        self.charge_rows_written(&payload.context, rows_written);
+
+        let preview_id = match &payload.preview_id {
+            Some(id) => {
+                self.record_changes(id, executed.changes.clone()).await;
+                id.clone()
+            }
+            None => {
+                let stored = StoredQuery {
+                    record: self.changeset_record(payload, &executed, QueryStatus::Committed),
+                    request: payload.statements.clone(),
+                    fingerprints: executed.fingerprints.clone(),
+                    committing: false,
+                    changes: executed.changes.clone(),
+                };
+                let mut store = self.store.write().await;
+                store
+                    .entries
+                    .insert(executed.preview.preview_id.clone(), stored);
+                executed.preview.preview_id.clone()
+            }
+        };
+
+        Ok(CommitResponse {
+            ok: true,
+            preview_id,
+            committed_at: Utc::now(),
+            rows_affected: executed.preview.rows_affected,
+        })
+    }
+
+    /// Returns the decision trace stored with a query, or evaluates a request
+    /// against the policy without previewing it.
+    pub(crate) async fn explain_decision(
+        &self,
+        payload: &ExplainRequest,
+    ) -> ProxyResult<ExplainResponse> {
+        let traces = match (&payload.id, &payload.request) {
+            (Some(id), _) => {
+                let Some(tenant_id) = &payload.tenant_id else {
+                    return Err(ProxyError::new(
+                        ErrorCode::InvalidRequest,
+                        "tenant_id is required to explain a query by id".to_string(),
+                    ));
+                };
+                let store = self.store.read().await;
+                let stored = store.entries.get(id).ok_or_else(|| {
+                    ProxyError::new(ErrorCode::NotFound, format!("Query '{id}' not found"))
+                })?;
+                if &stored.record.tenant_id != tenant_id {
+                    return Err(ProxyError::new(
+                        ErrorCode::Forbidden,
+                        "Query belongs to a different tenant".to_string(),
+                    ));
+                }
+                stored.record.trace.clone()
+            }
+            (None, Some(request)) => vec![QueryEngine.explain(request, &self.policy)],
+            (None, None) => {
+                return Err(ProxyError::new(
+                    ErrorCode::InvalidRequest,
+                    "Provide a query id or a request to explain".to_string(),
+                ));
+            }
+        };
+
+        Ok(ExplainResponse {
+            ok: true,
+            allowed: traces.iter().all(|trace| trace.allowed),
+            traces,
+        })
+    }
+
+    /// Lists stored queries matching the filters, one page at a time. Pages are
+    /// ordered by `created_at` then id, and the cursor is the last entry's key.
+    /// `tenant_id` is required, so a caller only sees its own tenant's queries.
+    /// Returns a stored query to its own tenant. Other tenants get `not_found`,
+    /// so ids cannot be probed across tenants.
+    pub(crate) async fn get_query(
+        &self,
+        id: &str,
+        request: &QueryGetRequest,
+    ) -> ProxyResult<QueryRecord> {
+        let Some(tenant_id) = &request.tenant_id else {
+            return Err(ProxyError::new(
+                ErrorCode::InvalidRequest,
+                "tenant_id is required to get a query".to_string(),
+            ));
+        };
+        let store = self.store.read().await;
+        store
+            .entries
+            .get(id)
+            .filter(|stored| &stored.record.tenant_id == tenant_id)
+            .map(|stored| stored.record.clone())
+            .ok_or_else(|| ProxyError::new(ErrorCode::NotFound, "Query not found"))
+    }
+
+    pub(crate) async fn list_queries(
+        &self,
+        request: &QueryListRequest,
+    ) -> ProxyResult<QueryListResponse> {
+        if request.tenant_id.is_none() {
+            return Err(ProxyError::new(
+                ErrorCode::InvalidRequest,
+                "tenant_id is required to list queries".to_string(),
+            ));
+        }
+        let limit = request.limit.unwrap_or(50).clamp(1, 500);
+        let cursor = match &request.cursor {
+            Some(cursor) => Some(parse_cursor(cursor).ok_or_else(|| {
+                ProxyError::new(
+                    ErrorCode::InvalidRequest,
+                    format!("Invalid cursor '{cursor}'"),
+                )
+            })?),
+            None => None,
+        };
+
+        let store = self.store.read().await;
+        let mut records: Vec<&QueryRecord> = store
+            .entries
+            .values()
+            .map(|stored| &stored.record)
+            .filter(|record| record_matches(record, request))
+            .collect();
+        records.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        if request.sort == SortOrder::Newest {
+            records.reverse();
+        }
+        if let Some((created_at, id)) = &cursor {
+            let key = (*created_at, id);
+            records.retain(|record| {
+                let record_key = (cursor_time(record), &record.id);
+                match request.sort {
+                    SortOrder::Newest => record_key < key,
+                    SortOrder::Oldest => record_key > key,
+                }
+            });
+        }
+
+        let next_cursor = match records.get(limit) {
+            Some(_) => records
+                .get(limit - 1)
+                .map(|record| format!("{}:{}", cursor_time(record), record.id)),
+            None => None,
+        };
+        Ok(QueryListResponse {
+            ok: true,
+            queries: records.into_iter().take(limit).cloned().collect(),
+            next_cursor,
+        })
+    }
+
+    /// Previews the changeset that undoes a committed query. Every inverse
+    /// statement must match exactly one row in its committed state; otherwise the
+    /// rows changed since and the revert is refused.
+    #[tracing::instrument(
+        name = "preview_revert",
+        skip_all,
+        fields(query_id = %id, actor = %payload.context.actor, tenant = %payload.context.tenant_id)
+    )]
+    pub(crate) async fn preview_revert(
+        &self,
+        id: &str,
+        payload: &RevertRequest,
+    ) -> ProxyResult<ChangesetPreviewResponse> {
+        let changeset = self.revert_changeset(id, payload).await?;
+        let preview = self.preview_changeset(&changeset).await?;
+        let changed = match self.db {
+            Some(_) => preview
+                .statements
+                .iter()
+                .position(|statement| statement.rows_affected != 1),
+            None => None,
+        };
+
+        let mut store = self.store.write().await;
+        if let Some(stored) = store.entries.get_mut(&preview.preview_id) {
+            stored.record.reverts = Some(id.to_string());
+            if changed.is_some() {
+                stored.record.status = QueryStatus::Failed;
+            }
+        }
+        if let Some(index) = changed {
+            return Err(ProxyError::new(
+                ErrorCode::Conflict,
+                format!(
+                    "Rows changed since query '{id}' was committed (revert statement {}); it cannot be reverted",
+                    index + 1
+                ),
+            ));
+        }
+
+        Ok(preview)
+    }
+
+    /// Commits a revert previewed with [`AppState::preview_revert`].
+    #[tracing::instrument(
+        name = "commit_revert",
+        skip_all,
+        fields(query_id = %id, actor = %payload.context.actor, tenant = %payload.context.tenant_id)
+    )]
+    pub(crate) async fn commit_revert(
+        &self,
+        id: &str,
+        payload: &RevertRequest,
+    ) -> ProxyResult<CommitResponse> {
+        let Some(preview_id) = &payload.preview_id else {
+            return Err(ProxyError::new(
+                ErrorCode::InvalidRequest,
+                "preview_id is required to commit a revert".to_string(),
+            ));
+        };
+        {
+            let store = self.store.read().await;
+            let stored = store.entries.get(preview_id).ok_or_else(|| {
+                ProxyError::new(ErrorCode::NotFound, "Preview not found".to_string())
+            })?;
+            if stored.record.reverts.as_deref() != Some(id) {
+                return Err(ProxyError::new(
+                    ErrorCode::Conflict,
+                    format!("Preview '{preview_id}' is not a revert of query '{id}'"),
+                ));
+            }
+        }
+
+        let mut changeset = self.revert_changeset(id, payload).await?;
+        changeset.preview_id = Some(preview_id.clone());
+        changeset.idempotency_key = payload.idempotency_key.clone();
+        let response = self.commit_changeset(&changeset).await?;
+
+        let mut store = self.store.write().await;
+        if let Some(stored) = store.entries.get_mut(id) {
+            stored.record.reverted_by = Some(response.preview_id.clone());
+        }
+        Ok(response)
+    }
+
+    /// Builds the inverse changeset of a committed query from its captured rows.
+    async fn revert_changeset(
+        &self,
+        id: &str,
+        payload: &RevertRequest,
+    ) -> ProxyResult<ChangesetRequest> {
+        let store = self.store.read().await;
+        let stored = store
+            .entries
+            .get(id)
+            .ok_or_else(|| ProxyError::new(ErrorCode::NotFound, "Query not found".to_string()))?;
+        if stored.record.tenant_id != payload.context.tenant_id {
+            return Err(ProxyError::new(
+                ErrorCode::Forbidden,
+                "Query belongs to a different tenant".to_string(),
+            ));
+        }
+        if stored.record.actor != payload.context.actor
+            && !self.policy.revert_roles.contains(&payload.context.role)
+        {
+            return Err(ProxyError::new(
+                ErrorCode::Forbidden,
+                "Query was committed by a different actor".to_string(),
+            )
+            .with_suggestion("Revert as the committing actor or with a role in revert_roles"));
+        }
+        if stored.record.status != QueryStatus::Committed {
+            return Err(ProxyError::new(
+                ErrorCode::Conflict,
+                format!(
+                    "Query '{id}' is {}; only committed queries can be reverted",
+                    stored.record.status.as_str()
+                ),
+            ));
+        }
+        if let Some(revert) = &stored.record.reverted_by {
+            return Err(ProxyError::new(
+                ErrorCode::Conflict,
+                format!("Query '{id}' was already reverted by '{revert}'"),
+            ));
+        }
+
+        let statements = inverse_statements(&stored.changes)
+            .map_err(|message| ProxyError::new(ErrorCode::Conflict, message))?;
+        Ok(ChangesetRequest {
+            statements,
+            context: payload.context.clone(),
+            preview_id: None,
+            idempotency_key: None,
+            explain: false,
+        })
+    }
+
+    /// Appends a preview, or the rejection that stopped it, to the audit log.
+    fn audit_preview(&self, payload: &ChangesetRequest, outcome: Result<(&str, u64), &ProxyError>) {
+        let (event, preview_id, rows_affected, error) = match outcome {
+            Ok((preview_id, rows)) => (
+                AuditEvent::Preview,
+                Some(preview_id.to_string()),
+                Some(rows),
+                None,
+            ),
+            Err(error) => (
+                AuditEvent::Rejection,
+                None,
+                None,
+                Some(error.message.clone()),
+            ),
+        };
+        self.audit(AuditRecord {
+            event,
+            context: &payload.context,
+            preview_id,
+            sql: statement_sql(payload),
+            rows_affected,
+            error,
+        });
+    }
+
+    fn audit_commit(&self, payload: &ChangesetRequest, result: &ProxyResult<CommitResponse>) {
+        let record = match result {
+            Ok(response) => AuditRecord {
+                event: AuditEvent::Commit,
+                context: &payload.context,
+                preview_id: Some(response.preview_id.clone()),
+                sql: statement_sql(payload),
+                rows_affected: Some(response.rows_affected),
+                error: None,
+            },
+            Err(error) => AuditRecord {
+                event: AuditEvent::Failure,
+                context: &payload.context,
+                preview_id: payload.preview_id.clone(),
+                sql: statement_sql(payload),
+                rows_affected: None,
+                error: Some(error.message.clone()),
+            },
+        };
+        self.audit(record);
+    }
+
+    /// Records a commit answered from its idempotency key, which is not audited
+    /// again as a commit.
+    fn audit_replay(&self, payload: &ChangesetRequest, response: &CommitResponse) {
+        self.audit(AuditRecord {
+            event: AuditEvent::Replay,
+            context: &payload.context,
+            preview_id: Some(response.preview_id.clone()),
+            sql: statement_sql(payload),
+            rows_affected: Some(response.rows_affected),
+            error: None,
+        });
+    }
+
+    fn capture(&self, kind: CaptureKind, payload: CapturedPayload, decision: Decision) {
+        if let Some(capture) = &self.capture
+            && let Err(error) = capture.append(kind, payload, decision)
+        {
+            tracing::error!(%error, "capture append failed");
+        }
+    }
+
+    /// Appends to the audit log. A commit has already run by the time it is
+    /// audited, so a failed append cannot undo it; it is logged at error level
+    /// and counted in `agentproxy_audit_failures_total` for alerting instead.
+    fn audit(&self, record: AuditRecord<'_>) {
+        let event = record.event.as_str();
+        if let Some(audit) = &self.audit
+            && let Err(error) = audit.append(record)
+        {
+            tracing::error!(event, %error, "audit log append failed");
+            self.metrics
+                .inc("agentproxy_audit_failures_total", &[("event", event)]);
+        }
+    }
+
+    async fn record_changes(&self, id: &str, changes: Vec<ChangeCapture>) {
+        let mut store = self.store.write().await;
+        if let Some(stored) = store.entries.get_mut(id) {
+            stored.changes = changes;
+        }
+    }
+
+    /// Returns the stored response when the idempotency key was already used for
+    /// this request, or reserves the key for a new commit.
+    async fn begin_idempotent(
+        &self,
+        payload: &ChangesetRequest,
+    ) -> ProxyResult<Option<CommitResponse>> {
+        let Some(key) = &payload.idempotency_key else {
+            return Ok(None);
+        };
+        let mut store = self.store.write().await;
+        let entry = (payload.context.actor.clone(), key.clone());
+        let expired = store
+            .commits
+            .get(&entry)
+            .is_some_and(|commit| commit.expired(self.idempotency_ttl, Utc::now()));
+        if expired {
+            store.commits.remove(&entry);
+        }
+        match store.commits.get(&entry) {
+            Some(commit) if commit.request != payload.statements => Err(ProxyError::new(
+                ErrorCode::IdempotencyMismatch,
+                format!("Idempotency key '{key}' was already used for a different request"),
+            )),
+            Some(IdempotentCommit { response: None, .. }) => Err(ProxyError::new(
+                ErrorCode::Conflict,
+                format!("A commit with idempotency key '{key}' is still in progress"),
+            )),
+            Some(IdempotentCommit {
+                response: Some(response),
+                ..
+            }) => Ok(Some(response.clone())),
+            None => {
+                let commit = IdempotentCommit {
+                    request: payload.statements.clone(),
+                    response: None,
+                };
+                store.commits.insert(entry, commit);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Takes a preview token, counting the request as rate limited when none
+    /// is left.
+    fn acquire_preview(&self, context: &QueryContext) -> ProxyResult<()> {
+        let result = self
+            .rate_limiter
+            .acquire(context, &[(RateKind::Previews, 1)]);
+        if result.is_err() {
+            self.record_outcome("preview", context, &result);
+        }
+        result
+    }
+
+    /// Takes a commit token and checks that the rows-written quota is not used
+    /// up; the rows are charged once the commit reports how many it wrote.
+    fn acquire_commit(&self, context: &QueryContext) -> ProxyResult<()> {
+        let result = self.rate_limiter.acquire(
+            context,
+            &[(RateKind::Commits, 1), (RateKind::RowsWritten, 0)],
+        );
+        if result.is_err() {
+            self.record_outcome("commit", context, &result);
+        }
+        result
+    }
+
+    /// Counts a preview or commit by outcome and logs it in the request span.
+    /// Rejections are logged by code and rule only, since messages can quote
+    /// values from the query.
+    fn record_outcome<T>(
+        &self,
+        kind: &'static str,
+        context: &QueryContext,
+        result: &ProxyResult<T>,
+    ) {
+        self.metrics.record_request(kind, context, result);
+        match result {
+            Ok(_) => tracing::info!(kind, "request succeeded"),
+            Err(error) => tracing::warn!(
+                kind,
+                code = error.code.as_str(),
+                rule = error.rule.as_ref().map(|rule| rule.source.as_str()),
+                "request rejected"
+            ),
+        }
+    }
+
+    fn charge_rows_written(&self, context: &QueryContext, rows: u64) {
+        self.rate_limiter
+            .charge(context, RateKind::RowsWritten, rows);
+        self.metrics.add(
+            "agentproxy_actor_rows_written_total",
+            &[("actor", self.metrics.actor_label(&context.actor))],
+            rows as f64,
+        );
+    }
+
+    /// Checks that the database answers a trivial query and the query store can
+    /// be locked for writing, each within `READINESS_TIMEOUT`. Without a database the
+    /// proxy runs in dry-run mode, so that check passes.
+    pub(crate) async fn readiness(&self) -> ReadinessResponse {
+        let database = match &self.db {
+            None => ReadinessCheck {
+                name: "database",
+                ok: true,
+                detail: "No database configured; previews run in dry-run mode".to_string(),
+            },
+            Some(db) => {
+                // The connection lock is held for a whole commit, so probe off
+                // the runtime and give up after the timeout.
+                let db = db.clone();
+                let probe = tokio::task::spawn_blocking(move || {
+                    with_transaction(db.as_ref(), TransactionMode::Rollback, |tx| {
+                        tx.query("SELECT 1", &SqlParams::default())
+                            .map_err(ProxyError::db)
+                    })
+                });
+                let (ok, detail) = match tokio::time::timeout(READINESS_TIMEOUT, probe).await {
+                    Ok(Ok(Ok(_))) => (true, "SELECT 1 succeeded".to_string()),
+                    Ok(Ok(Err(error))) => (false, error.message),
+                    Ok(Err(error)) => (false, format!("Database check failed: {error}")),
+                    Err(_) => (
+                        false,
+                        format!(
+                            "SELECT 1 did not finish within {}s",
+                            READINESS_TIMEOUT.as_secs()
+                        ),
+                    ),
+                };
+                ReadinessCheck {
+                    name: "database",
+                    ok,
+                    detail,
+                }
+            }
+        };
+
+        let store = match tokio::time::timeout(READINESS_TIMEOUT, self.store.write()).await {
+            Ok(store) => ReadinessCheck {
+                name: "query_store",
+                ok: true,
+                detail: format!("{} stored queries", store.entries.len()),
+            },
+            Err(_) => ReadinessCheck {
+                name: "query_store",
+                ok: false,
+                detail: format!(
+                    "Write lock not acquired within {}s",
+                    READINESS_TIMEOUT.as_secs()
+                ),
+            },
+        };
+
+        let checks = vec![database, store];
+        ReadinessResponse {
+            ok: checks.iter().all(|check| check.ok),
+            checks,
+        }
+    }
+
+    /// Renders the metrics, refreshing the query store gauges first.
+    pub(crate) async fn render_metrics(&self) -> String {
+        let store = self.store.read().await;
+        for status in [
+            QueryStatus::Previewed,
+            QueryStatus::Committed,
+            QueryStatus::Failed,
+            QueryStatus::Expired,
+        ] {
+            let count = store
+                .entries
+                .values()
+                .filter(|stored| stored.record.status == status)
+                .count();
+            self.metrics.set(
+                "agentproxy_stored_queries",
+                &[("status", status.as_str())],
+                count as f64,
+            );
+        }
+        let pending = store
+            .entries
+            .values()
+            .filter(|stored| stored.record.status == QueryStatus::Previewed && !stored.committing)
+            .count();
+        drop(store);
+        self.metrics
+            .set("agentproxy_pending_previews", &[], pending as f64);
+        self.metrics.render()
+    }
+
+    /// Remembers a successful response for the idempotency key. Failed commits
+    /// release the key so the request can be retried.
+    async fn finish_idempotent(
+        &self,
+        payload: &ChangesetRequest,
+        result: &ProxyResult<CommitResponse>,
+    ) {
+        let Some(key) = &payload.idempotency_key else {
+            return;
+        };
+        let mut store = self.store.write().await;
+        let entry = (payload.context.actor.clone(), key.clone());
+        match result {
+            Ok(response) => {
+                if let Some(commit) = store.commits.get_mut(&entry) {
+                    commit.response = Some(response.clone());
+                }
+            }
+            Err(_) => {
+                store.commits.remove(&entry);
+            }
+        }
+    }
+
+    /// Reserves the preview a commit refers to and returns its fingerprints. A
+    /// preview can be committed once, by the actor and tenant that made it,
+    /// before it expires. Commits that write need a preview unless the policy
+    /// sets `limits.allow_unpreviewed_writes`.
+    async fn claim_preview(
+        &self,
+        payload: &ChangesetRequest,
+    ) -> ProxyResult<Vec<Option<RowFingerprint>>> {
+        let Some(preview_id) = &payload.preview_id else {
+            let writes = payload.to_requests().iter().any(|request| {
+                !matches!(QueryEngine.evaluate_sql(request), Ok((parsed, _)) if parsed.operation == "select")
+            });
+            if writes && !self.policy.limits.allow_unpreviewed_writes {
+                return Err(ProxyError::new(
+                    ErrorCode::InvalidRequest,
+                    "preview_id is required to commit a write".to_string(),
+                )
+                .with_suggestion("Preview the query and commit the returned preview_id"));
+            }
+            return Ok(Vec::new());
+        };
+        let mut store = self.store.write().await;
+        let stored = store
+            .entries
+            .get_mut(preview_id)
+            .ok_or_else(|| ProxyError::new(ErrorCode::NotFound, "Preview not found".to_string()))?;
+        if stored.record.actor != payload.context.actor
+            || stored.record.tenant_id != payload.context.tenant_id
+        {
+            return Err(ProxyError::new(
+                ErrorCode::Forbidden,
+                "Preview belongs to a different actor or tenant".to_string(),
+            ));
+        }
+        if stored.record.status == QueryStatus::Previewed && stored.record.expires_at <= Utc::now()
+        {
+            stored.record.status = QueryStatus::Expired;
+        }
+        match stored.record.status {
+            QueryStatus::Previewed if stored.committing => {
+                return Err(ProxyError::new(
+                    ErrorCode::Conflict,
+                    format!("Preview '{preview_id}' is already being committed"),
+                ));
+            }
+            QueryStatus::Previewed => {}
+            QueryStatus::Expired => {
+                return Err(ProxyError::new(
+                    ErrorCode::Expired,
+                    format!("Preview '{preview_id}' has expired; preview the query again"),
+                ));
+            }
+            status => {
+                return Err(ProxyError::new(
+                    ErrorCode::Conflict,
+                    format!(
+                        "Preview '{preview_id}' is already {}; previews can only be committed once",
+                        status.as_str()
+                    ),
+                ));
+            }
+        }
+        if stored.request != payload.statements {
+            return Err(ProxyError::new(
+                ErrorCode::Conflict,
+                format!("Commit does not match the statements of preview '{preview_id}'"),
+            ));
+        }
+
+        stored.committing = true;
+        Ok(stored.fingerprints.clone())
+    }
+
+    /// Records the outcome of a commit on the preview it used up, if any.
+    async fn settle_preview<T>(
+        &self,
+        payload: &ChangesetRequest,
+        result: ProxyResult<T>,
+    ) -> ProxyResult<T> {
+        if let Some(preview_id) = &payload.preview_id {
+            let mut store = self.store.write().await;
+            if let Some(stored) = store.entries.get_mut(preview_id) {
+                stored.committing = false;
+                stored.record.status = match result {
+                    Ok(_) => QueryStatus::Committed,
+                    Err(_) => QueryStatus::Failed,
+                };
+            }
+        }
+        result
+    }
+
+    fn expiry(&self, from: DateTime<Utc>) -> DateTime<Utc> {
+        from.checked_add_signed(self.preview_ttl)
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+    }
+
+    fn single_record(
+        &self,
+        payload: &SqlRequest,
+        executed: &ExecutedQuery,
+        status: QueryStatus,
+    ) -> QueryRecord {
+        let now = Utc::now();
+        QueryRecord {
+            id: executed.preview.preview_id.clone(),
+            actor: payload.context.actor.clone(),
+            tenant_id: payload.context.tenant_id.clone(),
+            role: payload.context.role.clone(),
+            sql: executed.rewritten_sql.clone(),
+            status,
+            created_at: now,
+            expires_at: self.expiry(now),
+            operation: executed.preview.operation.clone(),
+            tables: executed.preview.tables.clone(),
+            params: payload.params.clone(),
+            statements: Vec::new(),
+            reverts: None,
+            reverted_by: None,
+            trace: vec![executed.trace.clone()],
+        }
+    }
+
+    fn changeset_record(
+        &self,
+        payload: &ChangesetRequest,
+        executed: &ExecutedChangeset,
+        status: QueryStatus,
+    ) -> QueryRecord {
+        let statements: Vec<ChangesetStatement> = executed
+            .preview
+            .statements
+            .iter()
+            .zip(&payload.statements)
+            .map(|(preview, statement)| ChangesetStatement {
+                sql: preview.rewritten_sql.clone(),
+                params: statement.params.clone(),
+            })
+            .collect();
+        let mut tables: Vec<String> = Vec::new();
+        for table in executed
+            .preview
+            .statements
+            .iter()
+            .flat_map(|preview| &preview.tables)
+        {
+            if !tables.contains(table) {
+                tables.push(table.clone());
+            }
+        }
+
+        let now = Utc::now();
+        QueryRecord {
+            id: executed.preview.preview_id.clone(),
+            actor: payload.context.actor.clone(),
+            tenant_id: payload.context.tenant_id.clone(),
+            role: payload.context.role.clone(),
+            sql: statements
+                .iter()
+                .map(|statement| statement.sql.as_str())
+                .collect::<Vec<_>>()
+                .join(";\n"),
+            status,
+            created_at: now,
+            expires_at: self.expiry(now),
+            operation: "changeset".to_string(),
+            tables,
+            params: Default::default(),
+            statements,
+            reverts: None,
+            reverted_by: None,
+            trace: executed.traces.clone(),
+        }
+    }
 }
 
 pub fn router(state: AppState) -> Router {
     Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics))
         .route("/sql/preview", post(preview_sql))
         .route("/sql/commit", post(commit_sql))
+        .route("/sql/explain", post(explain_sql))
+        .route("/changesets/preview", post(preview_changeset))
+        .route("/changesets/commit", post(commit_changeset))
+        .route("/queries", get(list_queries))
         .route("/queries/:id", get(get_query))
+        .route("/queries/:id/revert", post(revert_query))
+        .route("/usage", get(usage))
+        .route_layer(middleware::from_fn_with_state(state.clone(), track_http))
         .with_state(state)
 }
 
-async fn preview_sql(State(state): State<AppState>, Json(payload): Json<SqlRequest>) -> Response {
-    match state
-        .executor
-        .preview(&payload, &state.policy, state.db.as_ref())
-    {
-        Ok(executed) => {
-            let record = QueryRecord {
-                id: executed.preview.preview_id.clone(),
-                actor: payload.context.actor.clone(),
-                tenant_id: payload.context.tenant_id.clone(),
-                sql: executed.rewritten_sql,
-                status: "previewed".to_string(),
-                created_at: Utc::now(),
-                operation: executed.preview.operation.clone(),
-                tables: executed.preview.tables.clone(),
-            };
-
-            let stored = StoredQuery { record };
-
-            let mut store = state.store.write().await;
-            store
-                .entries
-                .insert(executed.preview.preview_id.clone(), stored);
+async fn preview_sql(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Json(mut payload): Json<SqlRequest>,
+) -> Response {
+    apply_peer_address(&mut payload.context, connect_info);
+    respond(state.preview_sql(&payload).await)
+}
 
-            (StatusCode::OK, Json(executed.preview)).into_response()
-        }
-        Err(message) => error_response(StatusCode::BAD_REQUEST, message),
+async fn commit_sql(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Json(mut payload): Json<SqlRequest>,
+) -> Response {
+    apply_peer_address(&mut payload.context, connect_info);
+    respond(state.commit_sql(&payload).await)
+}
+
+async fn explain_sql(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Json(mut payload): Json<ExplainRequest>,
+) -> Response {
+    if let Some(request) = &mut payload.request {
+        apply_peer_address(&mut request.context, connect_info);
     }
+    respond(state.explain_decision(&payload).await)
 }
 
-async fn commit_sql(State(state): State<AppState>, Json(payload): Json<SqlRequest>) -> Response {
-    let preview = match state
-        .executor
-        .commit(&payload, &state.policy, state.db.as_ref())
-    {
-        Ok(executed) => executed.preview,
-        Err(message) => return error_response(StatusCode::BAD_REQUEST, message),
-    };
+async fn preview_changeset(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Json(mut payload): Json<ChangesetRequest>,
+) -> Response {
+    apply_peer_address(&mut payload.context, connect_info);
+    respond(state.preview_changeset(&payload).await)
+}
+
+async fn commit_changeset(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Json(mut payload): Json<ChangesetRequest>,
+) -> Response {
+    apply_peer_address(&mut payload.context, connect_info);
+    respond(state.commit_changeset(&payload).await)
+}
+
+async fn revert_query(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Json(mut payload): Json<RevertRequest>,
+) -> Response {
+    apply_peer_address(&mut payload.context, connect_info);
+    if payload.preview_id.is_some() {
+        respond(state.commit_revert(&id, &payload).await)
+    } else {
+        respond(state.preview_revert(&id, &payload).await)
+    }
+}
+
+async fn list_queries(
+    State(state): State<AppState>,
+    Query(request): Query<QueryListRequest>,
+) -> Response {
+    respond(state.list_queries(&request).await)
+}
+
+/// Runs every routed HTTP request in a span, and logs and counts it by route
+/// template and status.
+async fn track_http(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+    let span = tracing::info_span!("http_request", method = %request.method(), route = %route);
+    let started = Instant::now();
+    let response = next.run(request).instrument(span.clone()).await;
+    span.in_scope(|| {
+        tracing::info!(
+            status = response.status().as_u16(),
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "request finished"
+        )
+    });
+    state.metrics.inc(
+        "agentproxy_http_requests_total",
+        &[("route", &route), ("status", response.status().as_str())],
+    );
+    state.metrics.observe(
+        "agentproxy_http_request_duration_seconds",
+        &[("route", &route)],
+        started.elapsed().as_secs_f64(),
+    );
+    response
+}
+
+/// Liveness: the process is up and serving requests.
+async fn healthz() -> Response {
+    (StatusCode::OK, Json(serde_json::json!({ "ok": true }))).into_response()
+}
 
-    let mut store = state.store.write().await;
-    let stored = match store.entries.get_mut(&preview.preview_id) {
-        Some(stored) => stored,
-        None => return error_response(StatusCode::NOT_FOUND, "Preview not found"),
+async fn readyz(State(state): State<AppState>) -> Response {
+    let readiness = state.readiness().await;
+    let status = if readiness.ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
     };
+    (status, Json(readiness)).into_response()
+}
 
-    stored.record.status = "committed".to_string();
+async fn metrics(State(state): State<AppState>) -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.render_metrics().await,
+    )
+        .into_response()
+}
 
-    let response = CommitResponse {
+async fn usage(State(state): State<AppState>, Query(request): Query<UsageRequest>) -> Response {
+    respond(Ok(UsageResponse {
         ok: true,
-        preview_id: preview.preview_id,
-        committed_at: Utc::now(),
-        rows_affected: preview.rows_affected,
-    };
+        buckets: state.rate_limiter.usage(&request),
+    }))
+}
+
+async fn get_query(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(request): Query<QueryGetRequest>,
+) -> Response {
+    respond(state.get_query(&id, &request).await)
+}
+
+fn statement_sql(payload: &ChangesetRequest) -> Vec<String> {
+    payload
+        .statements
+        .iter()
+        .map(|statement| statement.sql.clone())
+        .collect()
+}
+
+fn record_matches(record: &QueryRecord, request: &QueryListRequest) -> bool {
+    request
+        .actor
+        .as_ref()
+        .is_none_or(|actor| &record.actor == actor)
+        && request
+            .tenant_id
+            .as_ref()
+            .is_none_or(|tenant_id| &record.tenant_id == tenant_id)
+        && request
+            .role
+            .as_ref()
+            .is_none_or(|role| &record.role == role)
+        && request.status.is_none_or(|status| record.status == status)
+        && request
+            .operation
+            .as_ref()
+            .is_none_or(|operation| record.operation.eq_ignore_ascii_case(operation))
+        && request.table.as_ref().is_none_or(|table| {
+            record
+                .tables
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(table))
+        })
+        && request.since.is_none_or(|since| record.created_at >= since)
+        && request.until.is_none_or(|until| record.created_at < until)
+}
+
+fn cursor_time(record: &QueryRecord) -> i64 {
+    record.created_at.timestamp_nanos_opt().unwrap_or(i64::MAX)
+}
 
-    (StatusCode::OK, Json(response)).into_response()
+fn parse_cursor(cursor: &str) -> Option<(i64, String)> {
+    let (created_at, id) = cursor.split_once(':')?;
+    Some((created_at.parse().ok()?, id.to_string()))
 }
 
-async fn get_query(State(state): State<AppState>, Path(id): Path<String>) -> Response {
-    let store = state.store.read().await;
-    match store.entries.get(&id) {
-        Some(stored) => (StatusCode::OK, Json(&stored.record)).into_response(),
-        None => error_response(StatusCode::NOT_FOUND, "Query not found"),
+/// Sets `client_ip` from the peer address, and clears it when there is none so
+/// a caller cannot claim an address in the request body.
+fn apply_peer_address(context: &mut QueryContext, connect_info: Option<ConnectInfo<SocketAddr>>) {
+    context.client_ip = connect_info.map(|ConnectInfo(addr)| addr.ip().to_string());
+}
+
+fn respond<T: serde::Serialize>(result: ProxyResult<T>) -> Response {
+    match result {
+        Ok(body) => (StatusCode::OK, Json(body)).into_response(),
+        Err(error) => error_response(error),
     }
 }
 
-fn error_response(status: StatusCode, message: impl Into<String>) -> Response {
-    let response = ErrorResponse {
-        ok: false,
-        error: message.into(),
-    };
-    (status, Json(response)).into_response()
+fn error_response(error: ProxyError) -> Response {
+    let status = error.status();
+    let retry_after = error.retry_after;
+    let mut response = (status, Json(ErrorResponse::from(error))).into_response();
+    if let Some(seconds) = retry_after {
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::SqliteDb;
+
+    fn state() -> AppState {
+        let db = SqliteDb::new(":memory:").unwrap();
+        db.execute("CREATE TABLE orders (id INTEGER PRIMARY KEY, tenant_id TEXT NOT NULL)")
+            .unwrap();
+        db.execute("INSERT INTO orders (id, tenant_id) VALUES (1, 'acme')")
+            .unwrap();
+        AppState::new(serde_yaml::from_str("{}").unwrap()).with_db(Arc::new(db))
+    }
+
+    fn request(sql: &str) -> SqlRequest {
+        SqlRequest {
+            sql: sql.to_string(),
+            context: QueryContext {
+                actor: "agent:test".to_string(),
+                tenant_id: "acme".to_string(),
+                role: String::new(),
+                client_ip: None,
+                attributes: HashMap::new(),
+            },
+            params: SqlParams::default(),
+            preview_id: None,
+            idempotency_key: None,
+            explain: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn previews_can_only_be_committed_once() {
+        let state = state();
+        let mut payload = request("DELETE FROM orders WHERE tenant_id = 'acme' AND id = 1");
+        let preview = state.preview_sql(&payload).await.unwrap();
+        payload.preview_id = Some(preview.preview_id.clone());
+
+        let committed = state.commit_sql(&payload).await.unwrap();
+        assert_eq!(committed.preview_id, preview.preview_id);
+        assert_eq!(committed.rows_affected, 1);
+
+        let error = state.commit_sql(&payload).await.unwrap_err();
+        assert_eq!(error.code, ErrorCode::Conflict);
+        assert_eq!(error.status(), StatusCode::CONFLICT);
+        assert!(error.message.contains("already committed"));
+    }
+
+    #[tokio::test]
+    async fn writes_need_a_preview_unless_the_policy_allows_them() {
+        let state = state();
+        let payload = request("DELETE FROM orders WHERE tenant_id = 'acme' AND id = 1");
+        let error = state.commit_sql(&payload).await.unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidRequest);
+        assert!(error.message.contains("preview_id is required"));
+
+        let select = request("SELECT * FROM orders WHERE tenant_id = 'acme'");
+        state.commit_sql(&select).await.unwrap();
+
+        let policy = serde_yaml::from_str("limits:\n  allow_unpreviewed_writes: true").unwrap();
+        let state = AppState::new(policy).with_db(state.db.clone().unwrap());
+        let committed = state.commit_sql(&payload).await.unwrap();
+        assert_eq!(committed.rows_affected, 1);
+    }
+
+    #[tokio::test]
+    async fn expired_previews_are_rejected_and_swept() {
+        let state = state().with_preview_ttl(Duration::ZERO);
+        let mut payload = request("DELETE FROM orders WHERE tenant_id = 'acme' AND id = 1");
+        let preview = state.preview_sql(&payload).await.unwrap();
+        payload.preview_id = Some(preview.preview_id.clone());
+
+        let error = state.commit_sql(&payload).await.unwrap_err();
+        assert_eq!(error.code, ErrorCode::Expired);
+        assert_eq!(error.status(), StatusCode::GONE);
+
+        assert_eq!(state.sweep_expired().await, 1);
+        assert!(state.store.read().await.entries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn replays_commits_with_the_same_idempotency_key() {
+        // A replay does not take the only commit token.
+        let policy =
+            serde_yaml::from_str("rate_limits:\n  - scope: actor\n    commits: 1").unwrap();
+        let state = AppState::new(policy).with_db(state().db.clone().unwrap());
+        let mut payload = request("INSERT INTO orders (id, tenant_id) VALUES (2, 'acme')");
+        let preview = state.preview_sql(&payload).await.unwrap();
+        payload.preview_id = Some(preview.preview_id);
+        payload.idempotency_key = Some("order-2".to_string());
+
+        let first = state.commit_sql(&payload).await.unwrap();
+        let retry = state.commit_sql(&payload).await.unwrap();
+        assert_eq!(first, retry);
+
+        payload.sql = "INSERT INTO orders (id, tenant_id) VALUES (3, 'acme')".to_string();
+        let error = state.commit_sql(&payload).await.unwrap_err();
+        assert_eq!(error.code, ErrorCode::IdempotencyMismatch);
+
+        assert_eq!(state.sweep_expired().await, 0);
+        let state = state.with_idempotency_ttl(Duration::ZERO);
+        assert_eq!(state.sweep_expired().await, 1);
+        assert!(state.store.read().await.commits.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sweeps_settled_records_past_their_ttl() {
+        let state = state();
+        let mut payload = request("DELETE FROM orders WHERE tenant_id = 'acme' AND id = 1");
+        let preview = state.preview_sql(&payload).await.unwrap();
+        payload.preview_id = Some(preview.preview_id);
+        state.commit_sql(&payload).await.unwrap();
+        let mut failing = request("INSERT INTO orders (id, tenant_id) VALUES (2, 'acme')");
+        let preview = state.preview_sql(&failing).await.unwrap();
+        failing.preview_id = Some(preview.preview_id);
+        state
+            .db
+            .as_ref()
+            .unwrap()
+            .execute("INSERT INTO orders (id, tenant_id) VALUES (2, 'acme')")
+            .unwrap();
+        state.commit_sql(&failing).await.unwrap_err();
+        let pending = state
+            .preview_sql(&request("SELECT * FROM orders WHERE tenant_id = 'acme'"))
+            .await
+            .unwrap();
+
+        assert_eq!(state.sweep_expired().await, 0);
+        let state = state.with_record_ttl(Duration::ZERO);
+        assert_eq!(state.sweep_expired().await, 2);
+        let store = state.store.read().await;
+        assert_eq!(store.entries.len(), 1);
+        assert!(store.entries.contains_key(&pending.preview_id));
+    }
+
+    #[tokio::test]
+    async fn reverts_committed_changes() {
+        let state = state();
+        let mut payload = request("DELETE FROM orders WHERE tenant_id = 'acme' AND id = 1");
+        let preview = state.preview_sql(&payload).await.unwrap();
+        payload.preview_id = Some(preview.preview_id);
+        let committed = state.commit_sql(&payload).await.unwrap();
+
+        let mut revert = RevertRequest {
+            context: payload.context.clone(),
+            preview_id: None,
+            idempotency_key: None,
+        };
+        revert.context.actor = "agent:other".to_string();
+        let error = state
+            .preview_revert(&committed.preview_id, &revert)
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::Forbidden);
+        revert.context.actor = payload.context.actor.clone();
+
+        let preview = state
+            .preview_revert(&committed.preview_id, &revert)
+            .await
+            .unwrap();
+        assert_eq!(preview.statements[0].operation, "insert");
+
+        revert.preview_id = Some(preview.preview_id.clone());
+        state
+            .commit_revert(&committed.preview_id, &revert)
+            .await
+            .unwrap();
+        let restored = state
+            .preview_sql(&request("SELECT * FROM orders WHERE tenant_id = 'acme'"))
+            .await
+            .unwrap();
+        assert_eq!(restored.rows_affected, 1);
+
+        let error = state
+            .preview_revert(&committed.preview_id, &revert)
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::Conflict);
+        assert!(error.message.contains("already reverted"));
+    }
+
+    #[tokio::test]
+    async fn refuses_to_revert_tables_with_blob_columns() {
+        let state = state();
+        let db = state.db.clone().unwrap();
+        db.execute("CREATE TABLE files (id INTEGER PRIMARY KEY, data BLOB, tenant_id TEXT)")
+            .unwrap();
+        db.execute("INSERT INTO files VALUES (1, x'00ff', 'acme')")
+            .unwrap();
+        let mut payload = request("DELETE FROM files WHERE tenant_id = 'acme' AND id = 1");
+        let preview = state.preview_sql(&payload).await.unwrap();
+        payload.preview_id = Some(preview.preview_id);
+        let committed = state.commit_sql(&payload).await.unwrap();
+
+        let revert = RevertRequest {
+            context: payload.context.clone(),
+            preview_id: None,
+            idempotency_key: None,
+        };
+        let error = state
+            .preview_revert(&committed.preview_id, &revert)
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::Conflict);
+        assert!(error.message.contains("BLOB column 'data'"));
+    }
+
+    #[tokio::test]
+    async fn counts_audit_log_failures() {
+        let dir = std::env::temp_dir().join(format!("audit-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let audit = AuditLog::open(dir.join("audit.jsonl")).unwrap();
+        let state = state().with_audit_log(audit);
+        std::fs::remove_dir(&dir).unwrap();
+
+        let mut payload = request("DELETE FROM orders WHERE tenant_id = 'acme' AND id = 1");
+        let preview = state.preview_sql(&payload).await.unwrap();
+        payload.preview_id = Some(preview.preview_id);
+        state.commit_sql(&payload).await.unwrap();
+
+        let metrics = state.render_metrics().await;
+        assert!(metrics.contains("agentproxy_audit_failures_total{event=\"preview\"} 1"));
+        assert!(metrics.contains("agentproxy_audit_failures_total{event=\"commit\"} 1"));
+    }
+
+    #[tokio::test]
+    async fn audits_rate_limited_requests_and_replays() {
+        let dir = std::env::temp_dir().join(format!("audit-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("audit.jsonl");
+        let policy =
+            serde_yaml::from_str("rate_limits:\n  - scope: actor\n    previews: 1\n    commits: 1")
+                .unwrap();
+        let state = AppState::new(policy)
+            .with_db(state().db.clone().unwrap())
+            .with_audit_log(AuditLog::open(&path).unwrap());
+
+        let mut payload = request("INSERT INTO orders (id, tenant_id) VALUES (2, 'acme')");
+        let preview = state.preview_sql(&payload).await.unwrap();
+        payload.preview_id = Some(preview.preview_id);
+        payload.idempotency_key = Some("order-2".to_string());
+        state.commit_sql(&payload).await.unwrap();
+        state.commit_sql(&payload).await.unwrap();
+        state.preview_sql(&payload).await.unwrap_err();
+        payload.idempotency_key = None;
+        state.commit_sql(&payload).await.unwrap_err();
+
+        let events: Vec<String> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<serde_json::Value>(line).unwrap()["event"].to_string()
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "\"preview\"",
+                "\"commit\"",
+                "\"replay\"",
+                "\"rejection\"",
+                "\"failure\""
+            ]
+        );
+        assert!(crate::audit::verify(&path).unwrap().is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn gets_stored_queries_only_for_their_tenant() {
+        let state = state();
+        let preview = state
+            .preview_sql(&request("SELECT * FROM orders WHERE tenant_id = 'acme'"))
+            .await
+            .unwrap();
+        let mut get = QueryGetRequest::default();
+        let error = state
+            .get_query(&preview.preview_id, &get)
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidRequest);
+
+        get.tenant_id = Some("other".to_string());
+        let error = state
+            .get_query(&preview.preview_id, &get)
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::NotFound);
+
+        get.tenant_id = Some("acme".to_string());
+        let record = state.get_query(&preview.preview_id, &get).await.unwrap();
+        assert_eq!(record.tenant_id, "acme");
+    }
+
+    #[tokio::test]
+    async fn explains_stored_queries_only_to_their_tenant() {
+        let state = state();
+        let preview = state
+            .preview_sql(&request("SELECT * FROM orders WHERE tenant_id = 'acme'"))
+            .await
+            .unwrap();
+        let mut explain = ExplainRequest {
+            id: Some(preview.preview_id),
+            tenant_id: None,
+            request: None,
+        };
+        let error = state.explain_decision(&explain).await.unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidRequest);
+
+        explain.tenant_id = Some("other".to_string());
+        let error = state.explain_decision(&explain).await.unwrap_err();
+        assert_eq!(error.code, ErrorCode::Forbidden);
+
+        explain.tenant_id = Some("acme".to_string());
+        let explained = state.explain_decision(&explain).await.unwrap();
+        assert!(explained.allowed);
+        assert_eq!(explained.traces.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn lists_queries_with_filters_and_cursor() {
+        let state = state();
+        for id in 2..=4 {
+            let sql = format!("INSERT INTO orders (id, tenant_id) VALUES ({id}, 'acme')");
+            state.preview_sql(&request(&sql)).await.unwrap();
+        }
+        state
+            .preview_sql(&request("SELECT * FROM orders WHERE tenant_id = 'acme'"))
+            .await
+            .unwrap();
+
+        let mut filters = QueryListRequest {
+            operation: Some("insert".to_string()),
+            limit: Some(2),
+            ..Default::default()
+        };
+        let error = state.list_queries(&filters).await.unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidRequest);
+
+        filters.tenant_id = Some("other".to_string());
+        assert!(
+            state
+                .list_queries(&filters)
+                .await
+                .unwrap()
+                .queries
+                .is_empty()
+        );
+
+        filters.tenant_id = Some("acme".to_string());
+        let first = state.list_queries(&filters).await.unwrap();
+        assert_eq!(first.queries.len(), 2);
+
+        filters.cursor = first.next_cursor;
+        let second = state.list_queries(&filters).await.unwrap();
+        assert_eq!(second.queries.len(), 1);
+        assert!(second.next_cursor.is_none());
+        assert!(first.queries[1].created_at >= second.queries[0].created_at);
+    }
+
+    #[test]
+    fn client_ip_comes_only_from_the_peer_address() {
+        let mut context = request("SELECT 1").context;
+        context.client_ip = Some("10.0.0.1".to_string());
+        apply_peer_address(&mut context, None);
+        assert_eq!(context.client_ip, None);
+
+        let peer = ConnectInfo("192.0.2.7:4000".parse::<SocketAddr>().unwrap());
+        apply_peer_address(&mut context, Some(peer));
+        assert_eq!(context.client_ip.as_deref(), Some("192.0.2.7"));
+    }
+
+    #[tokio::test]
+    async fn readiness_reports_each_check() {
+        let state = state();
+        let ready = state.readiness().await;
+        assert!(ready.ok);
+        let names: Vec<_> = ready.checks.iter().map(|check| check.name).collect();
+        assert_eq!(names, vec!["database", "query_store"]);
+
+        let _held = state.store.read().await;
+        let blocked = state.readiness().await;
+        assert!(!blocked.ok);
+        assert!(!blocked.checks[1].ok);
+        assert!(blocked.checks[0].ok);
+    }
+
+    /// Blocks every transaction until the test drops the sending half of
+    /// `release`, like a long commit holding the connection.
+    struct HeldConnection {
+        release: std::sync::Mutex<std::sync::mpsc::Receiver<()>>,
+    }
+
+    impl SQLDB for HeldConnection {
+        fn execute(&self, _sql: &str) -> Result<u64, String> {
+            Ok(0)
+        }
+
+        fn transaction(
+            &self,
+            _mode: TransactionMode,
+            _work: &mut dyn FnMut(&dyn crate::db::SqlTransaction) -> Result<(), String>,
+        ) -> Result<(), String> {
+            if let Ok(release) = self.release.lock() {
+                let _ = release.recv();
+            }
+            Err("connection released".to_string())
+        }
+
+        fn describe_schema(&self) -> Result<crate::db::SchemaSnapshot, String> {
+            Err("unused".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn readiness_times_out_on_a_busy_database() {
+        let (held, release) = std::sync::mpsc::channel::<()>();
+        let state =
+            AppState::new(serde_yaml::from_str("{}").unwrap()).with_db(Arc::new(HeldConnection {
+                release: std::sync::Mutex::new(release),
+            }));
+
+        let ready = state.readiness().await;
+        assert!(!ready.ok);
+        assert!(ready.checks[0].detail.contains("did not finish"));
+        assert!(ready.checks[1].ok);
+        drop(held);
+    }
 }
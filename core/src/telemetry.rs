@@ -0,0 +1,66 @@
+use regex::{Captures, Regex};
+use std::sync::{
+    LazyLock,
+    atomic::{AtomicBool, Ordering},
+};
+
+/// Whether SQL in spans and log events keeps its literals. Off by default, so
+/// tenant ids, emails and other values in queries stay out of the logs.
+static LOG_SQL_LITERALS: AtomicBool = AtomicBool::new(false);
+
+/// Quoted identifiers, string literals and numbers, in that order, so quotes
+/// inside identifiers and digits inside strings are not matched on their own.
+static LITERALS: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#""(?:[^"]|"")*"|'(?:[^']|'')*'|\b\d+(?:\.\d+)?(?:[eE][+-]?\d+)?\b"#)
+        .expect("literal pattern is valid")
+});
+
+pub fn set_log_sql_literals(enabled: bool) {
+    LOG_SQL_LITERALS.store(enabled, Ordering::Relaxed);
+}
+
+/// SQL as it should appear in logs: redacted unless literals were enabled
+/// with [`set_log_sql_literals`].
+pub fn loggable_sql(sql: &str) -> String {
+    if LOG_SQL_LITERALS.load(Ordering::Relaxed) {
+        sql.to_string()
+    } else {
+        redact_sql(sql)
+    }
+}
+
+/// Replaces string and numeric literals with `?`, keeping identifiers,
+/// keywords and placeholders such as `$1` intact. Works on unparsable SQL too.
+pub fn redact_sql(sql: &str) -> String {
+    LITERALS
+        .replace_all(sql, |captures: &Captures| {
+            let matched = captures.get(0).expect("match has a group 0");
+            let text = matched.as_str();
+            let placeholder = sql[..matched.start()].ends_with(['$', '?', ':', '@']);
+            if text.starts_with('"') || placeholder {
+                text.to_string()
+            } else {
+                "?".to_string()
+            }
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_literals_but_not_identifiers_or_placeholders() {
+        assert_eq!(
+            redact_sql(
+                "SELECT \"col 1\", t2.id FROM t2 WHERE email = 'a''b@x.io' AND n > 4.5e3 AND id = $1"
+            ),
+            "SELECT \"col 1\", t2.id FROM t2 WHERE email = ? AND n > ? AND id = $1"
+        );
+        assert_eq!(
+            redact_sql("UPDATE users SET name = 'it''s' WHERE id IN (1, 2"),
+            "UPDATE users SET name = ? WHERE id IN (?, ?"
+        );
+    }
+}
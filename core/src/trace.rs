@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::{ProxyError, ProxyResult};
+use crate::policy::RuleScope;
+
+/// Every rule checked for one statement, in evaluation order. A statement is
+/// allowed when none of its steps failed.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, schemars::JsonSchema)]
+pub struct DecisionTrace {
+    pub allowed: bool,
+    pub role: String,
+    pub steps: Vec<TraceStep>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, schemars::JsonSchema)]
+pub struct TraceStep {
+    /// Rule name, such as `allow_ops` or `where_required`.
+    pub rule: String,
+    /// Path of the rule in the policy file, such as
+    /// `roles.customer.carts.required_filters[0]`, or `builtin`.
+    pub source: String,
+    pub scope: RuleScope,
+    #[serde(default)]
+    pub table: Option<String>,
+    pub outcome: TraceOutcome,
+    #[serde(default)]
+    pub detail: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceOutcome {
+    Passed,
+    Failed,
+    /// The table has no policy section, so only built-in rules apply to it.
+    NoPolicy,
+}
+
+impl DecisionTrace {
+    pub fn new(role: &str) -> Self {
+        Self {
+            allowed: true,
+            role: role.to_string(),
+            steps: Vec::new(),
+        }
+    }
+
+    /// Records the result of a rule; an error fails the step with it as detail.
+    pub fn record(
+        &mut self,
+        rule: &str,
+        source: &str,
+        scope: RuleScope,
+        table: Option<&str>,
+        result: Result<(), String>,
+    ) {
+        let (outcome, detail) = match result {
+            Ok(()) => (TraceOutcome::Passed, None),
+            Err(error) => {
+                self.allowed = false;
+                (TraceOutcome::Failed, Some(error))
+            }
+        };
+        self.steps.push(TraceStep {
+            rule: rule.to_string(),
+            source: source.to_string(),
+            scope,
+            table: table.map(str::to_string),
+            outcome,
+            detail,
+        });
+    }
+
+    pub fn push(&mut self, step: TraceStep) {
+        self.allowed &= step.outcome != TraceOutcome::Failed;
+        self.steps.push(step);
+    }
+
+    /// The denial for the first failed step, as the engine reports it.
+    pub fn result(&self) -> ProxyResult<()> {
+        match self
+            .steps
+            .iter()
+            .find(|step| step.outcome == TraceOutcome::Failed)
+        {
+            Some(step) => Err(ProxyError::denied(step)),
+            None => Ok(()),
+        }
+    }
+}
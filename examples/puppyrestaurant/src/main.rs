@@ -172,7 +172,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
     tokio::spawn(run_wgui(wgui));
 
     let listener = TcpListener::bind(addr).await?;
-    axum::serve(listener, router.into_make_service()).await?;
+    axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }